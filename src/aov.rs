@@ -0,0 +1,235 @@
+use nalgebra_glm as glm;
+use std::collections::HashMap;
+
+use crate::denoise::Guides;
+use crate::integrator::Source;
+use crate::materials;
+use crate::objects::HitRecord;
+use crate::output::Layer;
+use crate::ray::Ray;
+
+pub const NAMES: [&str; 9] = ["albedo", "normal", "depth", "position", "object", "material", "direct", "indirect", "lights"];
+
+// Buffers that need the integrator to tell where the light it finds comes from.
+const LIGHTING: [&str; 3] = ["direct", "indirect", "lights"];
+
+// Lights past this many share a layer, as scenes can have hundreds of them.
+const LIGHT_LAYERS: usize = 8;
+
+// Auxiliary buffers rendered alongside the image for compositing: what the surface first seen in
+// each pixel is like, and the light of the image split up by where it came from. Each is the
+// plain average of the samples taken in the pixel, whatever the film's filter, so normals and
+// depths don't bleed over edges. Surface buffers only average the samples that hit something,
+// and the object and material IDs are those of the first sample, counting up from one in the
+// order objects were added and materials first seen in the image, with zero for nothing.
+pub struct Aovs {
+    names: Vec<String>,
+    width: u32,
+    pixels: Vec<AovPixel>,
+    // Light from each of the first lights, then all the others if there are more, then the
+    // background, for every pixel.
+    lights: Vec<glm::TVec3<f64>>,
+    scene_lights: usize,
+    // Whether the surface buffers are needed to guide the denoiser, whichever were asked for.
+    guides: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct AovPixel {
+    samples: u32,
+    hits: u32,
+    albedo: glm::TVec3<f64>,
+    normal: glm::TVec3<f64>,
+    position: glm::TVec3<f64>,
+    depth: f64,
+    object: usize,
+    material: usize,
+    direct: glm::TVec3<f64>,
+    indirect: glm::TVec3<f64>,
+}
+
+// The buffers of a row of pixels, taken out to be rendered into.
+pub struct AovRow {
+    pixels: Vec<AovPixel>,
+    lights: Vec<glm::TVec3<f64>>,
+    slots: usize,
+    splits_light: bool,
+}
+
+impl Aovs {
+    // Buffers for `names`, leaving out the ones splitting light when the integrator can't.
+    pub fn new(names: &[String], width: u32, height: u32, scene_lights: usize, splits_light: bool, guides: bool) -> Self {
+        let names: Vec<String> = names
+            .iter()
+            .filter(|name| splits_light || !LIGHTING.contains(&name.as_str()))
+            .cloned()
+            .collect();
+        let pixels = (width * height) as usize;
+        let lights = if names.iter().any(|name| name == "lights") { pixels * slots(scene_lights) } else { 0 };
+        Aovs {
+            names,
+            width,
+            pixels: vec![AovPixel::default(); pixels],
+            lights: vec![glm::vec3(0.0, 0.0, 0.0); lights],
+            scene_lights,
+            guides,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && !self.guides
+    }
+
+    // The surface buffers the denoiser is guided by, with the normals of pixels whose samples
+    // saw several surfaces averaged into one direction.
+    pub fn guides(&self) -> Guides {
+        Guides {
+            albedo: self.pixels.iter().map(|pixel| pixel.albedo / pixel.samples.max(1) as f64).collect(),
+            normal: self
+                .pixels
+                .iter()
+                .map(|pixel| if pixel.normal.magnitude_squared() > 0.0 { glm::normalize(&pixel.normal) } else { pixel.normal })
+                .collect(),
+            depth: self.pixels.iter().map(depth).collect(),
+        }
+    }
+
+    pub fn row(&self, y: u32) -> AovRow {
+        let start = (y * self.width) as usize;
+        let slots = if self.lights.is_empty() { 0 } else { slots(self.scene_lights) };
+        AovRow {
+            pixels: self.pixels[start..start + self.width as usize].to_vec(),
+            lights: self.lights[start * slots..(start + self.width as usize) * slots].to_vec(),
+            slots,
+            splits_light: self.names.iter().any(|name| LIGHTING.contains(&name.as_str())),
+        }
+    }
+
+    pub fn set_row(&mut self, y: u32, row: &AovRow) {
+        let start = (y * self.width) as usize;
+        self.pixels[start..start + row.pixels.len()].copy_from_slice(&row.pixels);
+        self.lights[start * row.slots..start * row.slots + row.lights.len()].copy_from_slice(&row.lights);
+    }
+
+    // The averaged buffers as layers to save, in the order they were asked for.
+    pub fn into_layers(self) -> Vec<Layer> {
+        let pixels = &self.pixels;
+        let average = |f: &dyn Fn(&AovPixel) -> glm::TVec3<f64>, count: &dyn Fn(&AovPixel) -> u32| -> Vec<glm::TVec3<f64>> {
+            pixels.iter().map(|pixel| f(pixel) / count(pixel).max(1) as f64).collect()
+        };
+        let samples = |pixel: &AovPixel| pixel.samples;
+        let hits = |pixel: &AovPixel| pixel.hits;
+        let mut layers = Vec::new();
+        for name in &self.names {
+            match name.as_str() {
+                "albedo" => layers.push(Layer::rgb("albedo", &average(&|pixel| pixel.albedo, &samples))),
+                "normal" => layers.push(Layer::xyz("normal", &average(&|pixel| pixel.normal, &hits))),
+                "position" => layers.push(Layer::xyz("position", &average(&|pixel| pixel.position, &hits))),
+                "depth" => {
+                    let depths: Vec<f64> = pixels.iter().map(depth).collect();
+                    layers.push(Layer { channels: vec!["Z"], ..Layer::gray("depth", &depths) });
+                }
+                "object" => {
+                    let ids: Vec<f64> = pixels.iter().map(|pixel| pixel.object as f64).collect();
+                    layers.push(Layer { channels: vec!["id"], ..Layer::gray("object", &ids) });
+                }
+                "material" => {
+                    // Materials are told apart by where they are in memory, which changes from run
+                    // to run, so they are numbered in the order the image shows them instead.
+                    let mut numbers = HashMap::new();
+                    let ids: Vec<f64> = pixels
+                        .iter()
+                        .map(|pixel| match pixel.material {
+                            0 => 0.0,
+                            material => {
+                                let next = numbers.len() + 1;
+                                *numbers.entry(material).or_insert(next) as f64
+                            }
+                        })
+                        .collect();
+                    layers.push(Layer { channels: vec!["id"], ..Layer::gray("material", &ids) });
+                }
+                "direct" => layers.push(Layer::rgb("direct", &average(&|pixel| pixel.direct, &samples))),
+                "indirect" => layers.push(Layer::rgb("indirect", &average(&|pixel| pixel.indirect, &samples))),
+                "lights" => {
+                    let slots = slots(self.scene_lights);
+                    for slot in 0..slots {
+                        let name = if slot == slots - 1 {
+                            "background".to_string()
+                        } else if slot == LIGHT_LAYERS {
+                            "lights_rest".to_string()
+                        } else {
+                            format!("light{}", slot)
+                        };
+                        let light: Vec<glm::TVec3<f64>> = pixels
+                            .iter()
+                            .enumerate()
+                            .map(|(index, pixel)| self.lights[index * slots + slot] / pixel.samples.max(1) as f64)
+                            .collect();
+                        layers.push(Layer::rgb(&name, &light));
+                    }
+                }
+                _ => {}
+            }
+        }
+        layers
+    }
+}
+
+impl AovRow {
+    // Whether light has to be passed to `add_light`.
+    pub fn splits_light(&self) -> bool {
+        self.splits_light
+    }
+
+    // Adds what sample `index` of pixel `x` first hit along the camera ray `r`, if anything.
+    pub fn add_hit(&mut self, x: usize, r: &Ray, hit: Option<(usize, HitRecord)>, index: u64) {
+        let pixel = &mut self.pixels[x];
+        pixel.samples += 1;
+        let (object, hit) = match hit {
+            Some(hit) => hit,
+            None => return,
+        };
+        pixel.hits += 1;
+        pixel.albedo += hit.material.albedo(r, &hit);
+        pixel.normal += hit.material.shading_normal(r, &hit);
+        pixel.position += hit.p;
+        pixel.depth += hit.t * r.direction.magnitude();
+        if index == 0 {
+            pixel.object = object + 1;
+            pixel.material = materials::id(&hit.material);
+        }
+    }
+
+    // Adds light reaching pixel `x` from `source`.
+    pub fn add_light(&mut self, x: usize, source: Source, direct: bool, light: &glm::TVec3<f64>) {
+        let pixel = &mut self.pixels[x];
+        if direct {
+            pixel.direct += light;
+        } else {
+            pixel.indirect += light;
+        }
+        if self.slots > 0 {
+            let slot = match source {
+                Source::Light(index) => index.min(LIGHT_LAYERS),
+                Source::Background => self.slots - 1,
+            };
+            self.lights[x * self.slots + slot] += light;
+        }
+    }
+}
+
+// Pixels where nothing was hit are infinitely far away.
+fn depth(pixel: &AovPixel) -> f64 {
+    if pixel.hits == 0 {
+        f64::INFINITY
+    } else {
+        pixel.depth / pixel.hits as f64
+    }
+}
+
+// Layers of light for a scene with `lights` lights, one for each of the first, one for the rest
+// and one for the background.
+fn slots(lights: usize) -> usize {
+    lights.min(LIGHT_LAYERS) + usize::from(lights > LIGHT_LAYERS) + 1
+}
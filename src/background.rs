@@ -0,0 +1,70 @@
+use nalgebra_glm as glm;
+use std::f64::consts::PI;
+
+use crate::colorspace::{self, ColorSpace};
+use crate::util;
+
+pub trait Background: Send + Sync {
+    fn color(&self, direction: &glm::TVec3<f64>) -> glm::TVec3<f64>;
+
+    // Picks a direction towards the background for direct lighting, returning the direction,
+    // the radiance arriving from it and its solid angle pdf.
+    fn sample(&self, _u: glm::TVec2<f64>) -> Option<(glm::TVec3<f64>, glm::TVec3<f64>, f64)> {
+        None
+    }
+
+    fn pdf(&self, _direction: &glm::TVec3<f64>) -> f64 {
+        0.0
+    }
+
+    // Like `sample`, but for the directions photons arrive from. Backgrounds too even to be
+    // worth sampling for direct lighting can still cast caustics.
+    fn sample_photon(&self, u: glm::TVec2<f64>) -> Option<(glm::TVec3<f64>, glm::TVec3<f64>, f64)> {
+        self.sample(u)
+    }
+
+    fn photon_pdf(&self, direction: &glm::TVec3<f64>) -> f64 {
+        self.pdf(direction)
+    }
+}
+
+// From white at the bottom to light blue at the top.
+pub struct Gradient {
+    bottom: glm::TVec3<f64>,
+    top: glm::TVec3<f64>,
+}
+
+impl Gradient {
+    // The gradient with its colors taken to be in `space`.
+    pub fn new(space: ColorSpace) -> Self {
+        Gradient {
+            bottom: space.convert(&glm::vec3(1.0, 1.0, 1.0), colorspace::working()),
+            top: space.convert(&glm::vec3(0.5, 0.7, 1.0), colorspace::working()),
+        }
+    }
+}
+
+impl Background for Gradient {
+    fn color(&self, direction: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        let unit_direction = glm::normalize(direction);
+        let t = 0.5 * (unit_direction.y + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+
+    fn sample_photon(&self, u: glm::TVec2<f64>) -> Option<(glm::TVec3<f64>, glm::TVec3<f64>, f64)> {
+        let direction = util::uniform_sphere(u);
+        Some((direction, self.color(&direction), 1.0 / (4.0 * PI)))
+    }
+
+    fn photon_pdf(&self, _direction: &glm::TVec3<f64>) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+}
+
+pub struct Solid(pub glm::TVec3<f64>);
+
+impl Background for Solid {
+    fn color(&self, _direction: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        self.0
+    }
+}
@@ -0,0 +1,491 @@
+use nalgebra_glm as glm;
+use std::sync::Mutex;
+
+use crate::camera::Camera;
+use crate::integrator::Integrator;
+use crate::materials;
+use crate::objects::Hittable;
+use crate::objects;
+use crate::path;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::util;
+
+// Bidirectional path tracing after Veach and PBRT: a path from the camera and a path from a light
+// are connected at every pair of vertices, and each connection is weighted by how likely the other
+// strategies were to produce the same path. Light from the background is handled the way the path
+// tracer does it, as it can't start light paths.
+pub struct BdptIntegrator {
+    pub max_depth: u32,
+    splats: Mutex<Vec<(glm::TVec2<f64>, glm::TVec3<f64>)>>,
+}
+
+impl BdptIntegrator {
+    pub fn new(max_depth: u32) -> Self {
+        BdptIntegrator {
+            max_depth,
+            splats: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn name(&self) -> &'static str {
+        "bdpt"
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, camera: &Camera, sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        let max_depth = self.max_depth as usize;
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        let camera_path = camera_subpath(scene, camera, r, max_depth + 2, &mut color, sampler);
+        let light_path = light_subpath(scene, max_depth + 1, sampler);
+
+        let mut splats = Vec::new();
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = s as i64 + t as i64 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > max_depth as i64 {
+                    continue;
+                }
+                if let Some((l, screen_coords)) = connect(scene, camera, &light_path, &camera_path, s, t, sampler) {
+                    match screen_coords {
+                        Some(screen_coords) => splats.push((screen_coords, l)),
+                        None => color += l,
+                    }
+                }
+            }
+        }
+        if !splats.is_empty() {
+            self.splats.lock().unwrap().extend(splats);
+        }
+        color
+    }
+
+    fn take_splats(&self) -> Vec<(glm::TVec2<f64>, glm::TVec3<f64>)> {
+        std::mem::take(&mut *self.splats.lock().unwrap())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    p: glm::TVec3<f64>,
+    // Zero for vertices that aren't on a surface, like a pinhole or a point light.
+    normal: glm::TVec3<f64>,
+    beta: glm::TVec3<f64>,
+    // Area densities of sampling this vertex from its neighbours towards the camera and the light.
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // Whether the path scattered off this vertex in a single possible direction.
+    delta: bool,
+    hit: Option<objects::HitRecord>,
+    incoming: glm::TVec3<f64>,
+    light: Option<usize>,
+}
+
+impl Vertex {
+    fn camera(p: glm::TVec3<f64>, beta: glm::TVec3<f64>) -> Self {
+        Vertex {
+            kind: VertexKind::Camera,
+            p,
+            normal: glm::vec3(0.0, 0.0, 0.0),
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            hit: None,
+            incoming: glm::vec3(0.0, 0.0, 0.0),
+            light: None,
+        }
+    }
+
+    fn light(light: usize, p: glm::TVec3<f64>, normal: glm::TVec3<f64>, beta: glm::TVec3<f64>, pdf_fwd: f64) -> Self {
+        Vertex {
+            kind: VertexKind::Light,
+            normal,
+            pdf_fwd,
+            light: Some(light),
+            ..Vertex::camera(p, beta)
+        }
+    }
+
+    fn surface(scene: &Scene, hit: objects::HitRecord, beta: glm::TVec3<f64>, incoming: glm::TVec3<f64>) -> Self {
+        Vertex {
+            kind: VertexKind::Surface,
+            p: hit.p,
+            normal: hit.normal,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            light: scene.lights.index_of(materials::id(&hit.material)),
+            hit: Some(hit),
+            incoming,
+        }
+    }
+
+    fn is_on_surface(&self) -> bool {
+        self.normal != glm::vec3(0.0, 0.0, 0.0)
+    }
+
+    fn is_light(&self) -> bool {
+        self.kind == VertexKind::Light || self.light.is_some()
+    }
+
+    fn incoming_ray(&self) -> Ray {
+        Ray::new(self.p - self.incoming, self.incoming)
+    }
+
+    fn is_connectible(&self) -> bool {
+        match &self.hit {
+            Some(hit) => {
+                let towards_normal = Ray::new(self.p, self.normal);
+                hit.material.eval(&self.incoming_ray(), hit, &towards_normal) != glm::vec3(0.0, 0.0, 0.0)
+            }
+            None => true,
+        }
+    }
+
+    // The BSDF times the cosine for scattering towards `next`.
+    fn f(&self, next: &Vertex) -> glm::TVec3<f64> {
+        match &self.hit {
+            Some(hit) => hit.material.eval(&self.incoming_ray(), hit, &Ray::new(self.p, next.p - self.p)),
+            None => glm::vec3(0.0, 0.0, 0.0),
+        }
+    }
+
+    fn le(&self) -> glm::TVec3<f64> {
+        match &self.hit {
+            Some(hit) => hit.material.emitted(&self.incoming_ray(), hit),
+            None => glm::vec3(0.0, 0.0, 0.0),
+        }
+    }
+
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let distance_squared = w.magnitude_squared();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf / distance_squared;
+        if next.is_on_surface() {
+            pdf *= glm::dot(&next.normal, &w).abs() / distance_squared.sqrt();
+        }
+        pdf
+    }
+
+    // Area density of sampling `next` from this vertex, having arrived from `prev`.
+    fn pdf(&self, scene: &Scene, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        match self.kind {
+            VertexKind::Light => self.pdf_light(scene, next),
+            VertexKind::Camera => {
+                let (_, pdf_direction) = camera.pdf_we(&Ray::new(self.p, next.p - self.p));
+                self.convert_density(pdf_direction, next)
+            }
+            VertexKind::Surface => {
+                let (hit, prev) = match (&self.hit, prev) {
+                    (Some(hit), Some(prev)) => (hit, prev),
+                    _ => return 0.0,
+                };
+                let r_in = Ray::new(prev.p, self.p - prev.p);
+                let pdf = hit.material.scattering_pdf(&r_in, hit, &Ray::new(self.p, next.p - self.p));
+                self.convert_density(pdf, next)
+            }
+        }
+    }
+
+    // Area density of the light at this vertex emitting towards `next`.
+    fn pdf_light(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let light = match self.light {
+            Some(light) => &scene.lights.lights()[light],
+            None => return 0.0,
+        };
+        let (_, pdf_direction) = light.pdf_le(&Ray::new(self.p, next.p - self.p), &self.normal);
+        self.convert_density(pdf_direction, next)
+    }
+
+    // Density of picking the light at this vertex and this point on it to start a light path.
+    fn pdf_light_origin(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let index = match self.light {
+            Some(light) => light,
+            None => return 0.0,
+        };
+        let light = &scene.lights.lights()[index];
+        let (pdf_position, _) = light.pdf_le(&Ray::new(self.p, next.p - self.p), &self.normal);
+        pdf_position * scene.lights.power_pmf(index)
+    }
+}
+
+fn camera_subpath(
+    scene: &Scene,
+    camera: &Camera,
+    r: &Ray,
+    max_vertices: usize,
+    background: &mut glm::TVec3<f64>,
+    sampler: &mut dyn Sampler,
+) -> Vec<Vertex> {
+    let mut path = vec![Vertex::camera(r.origin, glm::vec3(1.0, 1.0, 1.0))];
+    let (_, pdf_direction) = camera.pdf_we(r);
+    let ray = Ray::new(r.origin, glm::normalize(&r.direction));
+    random_walk(scene, ray, glm::vec3(1.0, 1.0, 1.0), pdf_direction, max_vertices - 1, &mut path, Some(background), sampler);
+    path
+}
+
+fn light_subpath(scene: &Scene, max_vertices: usize, sampler: &mut dyn Sampler) -> Vec<Vertex> {
+    let (index, pmf) = match scene.lights.sample_by_power(sampler.get_1d()) {
+        Some(sample) => sample,
+        None => return Vec::new(),
+    };
+    let light = &scene.lights.lights()[index];
+    let u1 = sampler.get_2d();
+    let u2 = sampler.get_2d();
+    let emission = match light.sample_le(u1, u2) {
+        Some(emission) if emission.pdf_position > 0.0 && emission.pdf_direction > 0.0 && pmf > 0.0 => emission,
+        _ => return Vec::new(),
+    };
+    let mut path = vec![Vertex::light(
+        index,
+        emission.ray.origin,
+        emission.normal,
+        emission.radiance,
+        emission.pdf_position * pmf,
+    )];
+    let cosine = if path[0].is_on_surface() {
+        glm::dot(&emission.normal, &emission.ray.direction).abs()
+    } else {
+        1.0
+    };
+    let beta = emission.radiance * cosine / (pmf * emission.pdf_position * emission.pdf_direction);
+    random_walk(scene, emission.ray, beta, emission.pdf_direction, max_vertices - 1, &mut path, None, sampler);
+    path
+}
+
+// Extends `path` by following scattered rays. Camera paths pass `background` to pick up the light
+// arriving from the background along the way.
+#[allow(clippy::too_many_arguments)]
+fn random_walk(
+    scene: &Scene,
+    ray: Ray,
+    beta: glm::TVec3<f64>,
+    pdf: f64,
+    max_bounces: usize,
+    path: &mut Vec<Vertex>,
+    mut background: Option<&mut glm::TVec3<f64>>,
+    sampler: &mut dyn Sampler,
+) {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+    let mut scattering_pdf: Option<f64> = None;
+    let mut bounces = 0;
+    while bounces < max_bounces {
+        let hit = match scene.world.hit(&ray, 0.001, f64::MAX) {
+            Some(hit) => hit,
+            None => {
+                if let Some(background) = background.as_mut() {
+                    let mut emitted = scene.background.color(&ray.direction);
+                    if let Some(pdf) = scattering_pdf {
+                        emitted *= util::power_heuristic(pdf, scene.background.pdf(&ray.direction));
+                    }
+                    **background += beta.component_mul(&emitted);
+                }
+                break;
+            }
+        };
+        let mut vertex = Vertex::surface(scene, hit, beta, ray.direction);
+        vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+        bounces += 1;
+        if bounces >= max_bounces {
+            break;
+        }
+
+        let current = path.len() - 1;
+        let hit = path[current].hit.clone().unwrap();
+        let (scattered, attenuation) = match hit.material.scatter(&ray, &hit, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+        let pdf = hit.material.scattering_pdf(&ray, &hit, &scattered);
+        let pdf_rev = if pdf > 0.0 {
+            if let Some(background) = background.as_mut() {
+                **background += beta.component_mul(&path::sample_background(&ray, &hit, scene, sampler));
+            }
+            scattering_pdf = Some(pdf);
+            let reversed = Ray::new(hit.p + scattered.direction, -scattered.direction);
+            hit.material.scattering_pdf(&reversed, &hit, &Ray::new(hit.p, -ray.direction))
+        } else {
+            path[current].delta = true;
+            scattering_pdf = None;
+            0.0
+        };
+        pdf_fwd = pdf;
+        beta = beta.component_mul(&attenuation);
+        let converted = path[current].convert_density(pdf_rev, &path[current - 1]);
+        path[current - 1].pdf_rev = converted;
+        ray = Ray::new(scattered.origin, glm::normalize(&scattered.direction));
+    }
+}
+
+fn visible(scene: &Scene, a: &glm::TVec3<f64>, b: &glm::TVec3<f64>) -> bool {
+    let d = b - a;
+    let distance = d.magnitude();
+    distance > 0.0 && scene.world.hit(&Ray::new(*a, d / distance), 0.001, distance - 0.001).is_none()
+}
+
+// Contribution of the path made of the first `s` light vertices and `t` camera vertices, with its
+// position on the screen when it has to be splatted onto another pixel.
+fn connect(
+    scene: &Scene,
+    camera: &Camera,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    s: usize,
+    t: usize,
+    sampler: &mut dyn Sampler,
+) -> Option<(glm::TVec3<f64>, Option<glm::TVec2<f64>>)> {
+    let mut sampled: Option<Vertex> = None;
+    let mut screen_coords = None;
+    let l = if s == 0 {
+        let pt = &camera_path[t - 1];
+        if !pt.is_light() {
+            return None;
+        }
+        pt.beta.component_mul(&pt.le())
+    } else if t == 1 {
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible() {
+            return None;
+        }
+        let sample = camera.sample_wi(&qs.p, sampler.get_2d())?;
+        if sample.pdf <= 0.0 || sample.importance <= 0.0 {
+            return None;
+        }
+        let vertex = Vertex::camera(qs.p + sample.direction * sample.distance, glm::vec3(1.0, 1.0, 1.0) * (sample.importance / sample.pdf));
+        let l = qs.beta.component_mul(&qs.f(&vertex)).component_mul(&vertex.beta);
+        if l == glm::vec3(0.0, 0.0, 0.0) || !visible(scene, &qs.p, &vertex.p) {
+            return None;
+        }
+        screen_coords = Some(sample.screen_coords);
+        sampled = Some(vertex);
+        l
+    } else if s == 1 {
+        let pt = &camera_path[t - 1];
+        if !pt.is_connectible() {
+            return None;
+        }
+        let (index, pmf) = scene.lights.sample_by_power(sampler.get_1d())?;
+        let light = &scene.lights.lights()[index];
+        let sample = light.sample(&pt.p, sampler.get_2d())?;
+        if sample.pdf <= 0.0 || pmf <= 0.0 {
+            return None;
+        }
+        let p = pt.p + sample.direction * sample.distance;
+        let mut vertex = Vertex::light(index, p, sample.normal, sample.radiance / (sample.pdf * pmf), 0.0);
+        vertex.pdf_fwd = vertex.pdf_light_origin(scene, pt);
+        let l = pt.beta.component_mul(&pt.f(&vertex)).component_mul(&vertex.beta);
+        if l == glm::vec3(0.0, 0.0, 0.0) || !visible(scene, &pt.p, &p) {
+            return None;
+        }
+        sampled = Some(vertex);
+        l
+    } else {
+        let qs = &light_path[s - 1];
+        let pt = &camera_path[t - 1];
+        if !qs.is_connectible() || !pt.is_connectible() {
+            return None;
+        }
+        let distance_squared = (qs.p - pt.p).magnitude_squared();
+        let l = qs.beta.component_mul(&qs.f(pt)).component_mul(&pt.f(qs)).component_mul(&pt.beta) / distance_squared;
+        if l == glm::vec3(0.0, 0.0, 0.0) || !visible(scene, &qs.p, &pt.p) {
+            return None;
+        }
+        l
+    };
+    let weight = mis_weight(scene, camera, light_path, camera_path, sampled.as_ref(), s, t);
+    Some((l * weight, screen_coords))
+}
+
+fn mis_weight(
+    scene: &Scene,
+    camera: &Camera,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    sampled: Option<&Vertex>,
+    s: usize,
+    t: usize,
+) -> f64 {
+    if s + t == 2 {
+        return 1.0;
+    }
+    // (pdf_fwd, pdf_rev, delta) of every vertex, adjusted below to describe this strategy.
+    let densities = |v: &Vertex| (v.pdf_fwd, v.pdf_rev, v.delta);
+    let mut light_pdfs: Vec<(f64, f64, bool)> = light_path[..s].iter().map(densities).collect();
+    let mut camera_pdfs: Vec<(f64, f64, bool)> = camera_path[..t].iter().map(densities).collect();
+
+    let qs = match s {
+        0 => None,
+        1 => sampled,
+        _ => Some(&light_path[s - 1]),
+    };
+    let pt = if t == 1 { sampled.unwrap() } else { &camera_path[t - 1] };
+    let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+    let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+    if s == 1 {
+        light_pdfs[0] = densities(qs.unwrap());
+    }
+    if t == 1 {
+        camera_pdfs[0] = densities(pt);
+    }
+
+    camera_pdfs[t - 1].2 = false;
+    camera_pdfs[t - 1].1 = match qs {
+        Some(qs) => qs.pdf(scene, camera, qs_minus, pt),
+        None => pt_minus.map_or(0.0, |pt_minus| pt.pdf_light_origin(scene, pt_minus)),
+    };
+    if let Some(pt_minus) = pt_minus {
+        camera_pdfs[t - 2].1 = match qs {
+            Some(qs) => pt.pdf(scene, camera, Some(qs), pt_minus),
+            None => pt.pdf_light(scene, pt_minus),
+        };
+    }
+    if let Some(qs) = qs {
+        light_pdfs[s - 1].2 = false;
+        light_pdfs[s - 1].1 = pt.pdf(scene, camera, pt_minus, qs);
+        if let Some(qs_minus) = qs_minus {
+            light_pdfs[s - 2].1 = qs.pdf(scene, camera, Some(pt), qs_minus);
+        }
+    }
+
+    let remap = |f: f64| if f != 0.0 { f } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ri = 1.0;
+    for i in (1..t).rev() {
+        ri *= remap(camera_pdfs[i].1) / remap(camera_pdfs[i].0);
+        if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+            sum += ri;
+        }
+    }
+    ri = 1.0;
+    for i in (0..s).rev() {
+        ri *= remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+        let delta_light = if i > 0 {
+            light_pdfs[i - 1].2
+        } else {
+            let origin = qs.filter(|_| s == 1).unwrap_or(&light_path[0]);
+            origin.light.is_some_and(|light| scene.lights.lights()[light].is_delta())
+        };
+        if !light_pdfs[i].2 && !delta_light {
+            sum += ri;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
@@ -3,7 +3,7 @@ use crate::ray;
 use crate::util;
 use nalgebra_glm as glm;
 
-#[allow(dead_code)] #[derive(Debug)]
+#[allow(dead_code)] #[derive(Debug, Clone)]
 pub struct Camera {
     pub aspect_ratio: f64,
     pub vfov: f64,
@@ -51,8 +51,9 @@ impl Camera {
 
 
 impl Camera {
-    pub fn get_ray(&self, screen_coords: glm::TVec2<f64>) -> ray::Ray {
-        let rd = self.lens_radius * util::random_point_in_unit_disk();
+    // `u` picks the point on the lens the ray leaves from.
+    pub fn get_ray(&self, screen_coords: glm::TVec2<f64>, u: glm::TVec2<f64>) -> ray::Ray {
+        let rd = self.lens_radius * util::unit_disk(u);
         let offset = self.u * rd.x + self.v * rd.y;
         ray::Ray::new(self.origin + offset, self.lower_left_corner + self.horizontal * screen_coords.x + self.vertical * screen_coords.y - self.origin - offset)
     }
@@ -80,4 +81,92 @@ impl Camera {
         self.vertical = self.focus_dist * viewport_height * self.v;
         self.lower_left_corner = self.origin - self.horizontal/2.0 - self.vertical/2.0 - self.focus_dist * self.w;
     }
-}
\ No newline at end of file
+
+}
+
+// Importance emitted by the camera, so paths traced from the lights can be connected to it.
+// Screen coordinates are in the same [0, 1] range that `get_ray` takes.
+pub struct CameraSample {
+    pub direction: glm::TVec3<f64>,
+    pub importance: f64,
+    pub pdf: f64,
+    pub distance: f64,
+    pub screen_coords: glm::TVec2<f64>,
+}
+
+impl Camera {
+    // Area of the image at unit distance from the lens.
+    fn film_area(&self) -> f64 {
+        self.horizontal.magnitude() * self.vertical.magnitude() / (self.focus_dist * self.focus_dist)
+    }
+
+    fn lens_area(&self) -> f64 {
+        if self.lens_radius > 0.0 {
+            std::f64::consts::PI * self.lens_radius * self.lens_radius
+        } else {
+            1.0
+        }
+    }
+
+    fn screen_coords(&self, r: &ray::Ray) -> Option<(glm::TVec2<f64>, f64)> {
+        let direction = glm::normalize(&r.direction);
+        let cos_theta = glm::dot(&direction, &-self.w);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let focus_point = r.origin + direction * (self.focus_dist / cos_theta);
+        let offset = focus_point - self.lower_left_corner;
+        let s = glm::dot(&offset, &self.horizontal) / self.horizontal.magnitude_squared();
+        let t = glm::dot(&offset, &self.vertical) / self.vertical.magnitude_squared();
+        if !(0.0..=1.0).contains(&s) || !(0.0..=1.0).contains(&t) {
+            return None;
+        }
+        Some((glm::vec2(s, t), cos_theta))
+    }
+
+    pub fn we(&self, r: &ray::Ray) -> Option<(f64, glm::TVec2<f64>)> {
+        let (screen_coords, cos_theta) = self.screen_coords(r)?;
+        let cos2_theta = cos_theta * cos_theta;
+        Some((1.0 / (self.film_area() * self.lens_area() * cos2_theta * cos2_theta), screen_coords))
+    }
+
+    // Area density of `get_ray` picking the origin of `r` on the lens, and solid angle density of
+    // it picking its direction.
+    pub fn pdf_we(&self, r: &ray::Ray) -> (f64, f64) {
+        match self.screen_coords(r) {
+            Some((_, cos_theta)) => (
+                1.0 / self.lens_area(),
+                1.0 / (self.film_area() * cos_theta * cos_theta * cos_theta),
+            ),
+            None => (0.0, 0.0),
+        }
+    }
+
+    pub fn sample_wi(&self, p: &glm::TVec3<f64>, u: glm::TVec2<f64>) -> Option<CameraSample> {
+        let disk = util::unit_disk(u) * self.lens_radius;
+        let lens_point = self.origin + self.u * disk.x + self.v * disk.y;
+        let to_lens = lens_point - p;
+        let distance = to_lens.magnitude();
+        if distance == 0.0 {
+            return None;
+        }
+        let direction = to_lens / distance;
+        let (importance, screen_coords) = self.we(&ray::Ray::new(lens_point, -direction))?;
+        let cos_lens = glm::dot(&self.w, &direction).abs();
+        Some(CameraSample {
+            direction,
+            importance,
+            pdf: distance * distance / (cos_lens * self.lens_area()),
+            distance,
+            screen_coords,
+        })
+    }
+
+    pub fn position(&self) -> glm::TVec3<f64> {
+        self.origin
+    }
+
+    pub fn forward(&self) -> glm::TVec3<f64> {
+        -self.w
+    }
+}
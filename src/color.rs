@@ -1,36 +1,32 @@
 use nalgebra_glm as glm;
-use image;
 use rand::Rng;
 
-pub fn write_pixel(frag: glm::TVec3<f64>, _prev_color: image::Rgba<u8>, samples_per_pixel: u32, _frames: i32, render_reset_flag: bool) -> image::Rgba<u8> {
-    let mut frag = frag;
-    let scale = 1.0 / samples_per_pixel as f64;
-    frag.x = (frag.x * scale).sqrt();
-    frag.y = (frag.y * scale).sqrt();
-    frag.z = (frag.z * scale).sqrt();
-    let new_color = image::Rgba([(frag.x as f64 * 256.) as u8, (frag.y as f64 * 256.) as u8, (frag.z as f64 * 256.) as u8, 255]);
-    if render_reset_flag {
-        return mix(new_color, _prev_color, _frames as f64 / (_frames as f64 + 1.0));
-    }
-    new_color
+use crate::colorspace;
+
+pub fn random(rng: &mut impl Rng, min: f64, max: f64) -> glm::TVec3<f64> {
+    let r = rng.gen_range(min..max);
+    let g = rng.gen_range(min..max);
+    let b = rng.gen_range(min..max);
+    glm::vec3(r, g, b)
 }
 
-#[allow(dead_code)]
-fn mix(v: image::Rgba<u8>, w: image::Rgba<u8>, factor: f64) -> image::Rgba<u8> {
-    let v = v.0;
-    let w = w.0;
-    let factor = factor.min(1.0).max(0.0);
-    let factor2 = 1.0 - factor;
-    let r = (v[0] as f64 * factor2 + w[0] as f64 * factor) as u8;
-    let g = (v[1] as f64 * factor2 + w[1] as f64 * factor) as u8;
-    let b = (v[2] as f64 * factor2 + w[2] as f64 * factor) as u8;
-    let a = (v[3] as f64 * factor2 + w[3] as f64 * factor) as u8;
-    image::Rgba([r,g,b,a])
+// Black through blue, red and yellow to white as `t` goes from 0 to 1, for showing quantities.
+pub fn heat(t: f64) -> image::Rgba<u8> {
+    const STOPS: [[f64; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [0.1, 0.1, 0.8],
+        [0.9, 0.1, 0.2],
+        [1.0, 0.9, 0.1],
+        [1.0, 1.0, 1.0],
+    ];
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let i = (t as usize).min(STOPS.len() - 2);
+    let f = t - i as f64;
+    let channel = |c: usize| ((STOPS[i][c] * (1.0 - f) + STOPS[i + 1][c] * f) * 255.0).round() as u8;
+    image::Rgba([channel(0), channel(1), channel(2), 255])
 }
 
-pub fn random(min: f64, max: f64) -> glm::TVec3<f64> {
-    let r = rand::thread_rng().gen_range(min..max);
-    let g = rand::thread_rng().gen_range(min..max);
-    let b = rand::thread_rng().gen_range(min..max);
-    glm::vec3(r, g, b)
-}
\ No newline at end of file
+// Luminance of a color in the working space.
+pub fn luminance(color: &glm::TVec3<f64>) -> f64 {
+    colorspace::working().luminance(color)
+}
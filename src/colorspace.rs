@@ -0,0 +1,229 @@
+use nalgebra_glm as glm;
+use std::sync::OnceLock;
+
+use crate::tonemap;
+
+// RGB color spaces, by their primaries and white point. Colors in them are linear; `encode` and
+// `decode` convert to and from the transfer curve 8 bit images in the space are stored with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    LinearSrgb,
+    // The wide gamut space of the Academy Color Encoding System meant for rendering and
+    // compositing, with a D60 white. It has no transfer curve, so it is only ever linear.
+    AcesCg,
+    Rec2020,
+    DisplayP3,
+}
+
+pub const NAMES: [&str; 4] = ["srgb", "acescg", "rec2020", "p3"];
+
+const COLOR_SPACES: [ColorSpace; 4] = [ColorSpace::LinearSrgb, ColorSpace::AcesCg, ColorSpace::Rec2020, ColorSpace::DisplayP3];
+
+// Matrices from linear RGB to CIE XYZ, from the standards defining each space, by rows.
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [[0.4124, 0.3576, 0.1805], [0.2126, 0.7152, 0.0722], [0.0193, 0.1192, 0.9505]];
+const ACESCG_TO_XYZ: [[f64; 3]; 3] = [
+    [0.6624542, 0.1340042, 0.1561877],
+    [0.2722287, 0.6740818, 0.0536895],
+    [-0.0055746, 0.0040607, 1.0103391],
+];
+const REC2020_TO_XYZ: [[f64; 3]; 3] = [
+    [0.6369580, 0.1446169, 0.1688810],
+    [0.2627002, 0.6779981, 0.0593017],
+    [0.0000000, 0.0280727, 1.0609851],
+];
+const P3_TO_XYZ: [[f64; 3]; 3] = [
+    [0.4865709, 0.2656677, 0.1982173],
+    [0.2289746, 0.6917385, 0.0792869],
+    [0.0000000, 0.0451134, 1.0439444],
+];
+
+// Cone responses of the Bradford chromatic adaptation transform, which whites are matched in.
+const BRADFORD: [[f64; 3]; 3] = [[0.8951, 0.2664, -0.1614], [-0.7502, 1.7135, 0.0367], [0.0389, -0.0685, 1.0296]];
+
+impl ColorSpace {
+    pub fn by_name(name: &str) -> Option<Self> {
+        NAMES.iter().position(|&n| n == name).map(|index| COLOR_SPACES[index])
+    }
+
+    fn rgb_to_xyz(&self) -> &'static [[f64; 3]; 3] {
+        match self {
+            ColorSpace::LinearSrgb => &SRGB_TO_XYZ,
+            ColorSpace::AcesCg => &ACESCG_TO_XYZ,
+            ColorSpace::Rec2020 => &REC2020_TO_XYZ,
+            ColorSpace::DisplayP3 => &P3_TO_XYZ,
+        }
+    }
+
+    fn to_xyz(self) -> glm::TMat3<f64> {
+        matrix(self.rgb_to_xyz())
+    }
+
+    // The Y of the color, how bright it looks.
+    pub fn luminance(&self, color: &glm::TVec3<f64>) -> f64 {
+        let [r, g, b] = self.rgb_to_xyz()[1];
+        r * color.x + g * color.y + b * color.z
+    }
+
+    // Chromaticities of the red, green and blue primaries and of white.
+    pub fn chromaticities(&self) -> [glm::TVec2<f64>; 4] {
+        let to_xyz = self.to_xyz();
+        let xy = |xyz: glm::TVec3<f64>| glm::vec2(xyz.x, xyz.y) / (xyz.x + xyz.y + xyz.z);
+        [
+            xy(to_xyz.column(0).into()),
+            xy(to_xyz.column(1).into()),
+            xy(to_xyz.column(2).into()),
+            xy(to_xyz * glm::vec3(1.0, 1.0, 1.0)),
+        ]
+    }
+
+    // Matrix taking linear colors in this space to `to`, with this space's white adapted to
+    // that of `to`, so that white stays white.
+    pub fn conversion(&self, to: ColorSpace) -> glm::TMat3<f64> {
+        let bradford = matrix(&BRADFORD);
+        let from_white = bradford * self.to_xyz() * glm::vec3(1.0, 1.0, 1.0);
+        let to_white = bradford * to.to_xyz() * glm::vec3(1.0, 1.0, 1.0);
+        let adapt = bradford.try_inverse().unwrap() * glm::TMat3::from_diagonal(&to_white.component_div(&from_white)) * bradford;
+        to.to_xyz().try_inverse().unwrap() * adapt * self.to_xyz()
+    }
+
+    pub fn convert(&self, color: &glm::TVec3<f64>, to: ColorSpace) -> glm::TVec3<f64> {
+        if *self == to {
+            *color
+        } else {
+            self.conversion(to) * color
+        }
+    }
+
+    // Linear value to what 8 bit images in this space store. Display P3 shares the sRGB curve,
+    // and Rec. 2020 is encoded for the pure 2.4 gamma of BT.1886.
+    pub fn encode(&self, linear: f64) -> f64 {
+        match self {
+            ColorSpace::LinearSrgb | ColorSpace::DisplayP3 => tonemap::srgb_encode(linear),
+            ColorSpace::Rec2020 => linear.max(0.0).powf(1.0 / 2.4),
+            ColorSpace::AcesCg => linear,
+        }
+    }
+
+    pub fn decode(&self, encoded: f64) -> f64 {
+        match self {
+            ColorSpace::LinearSrgb | ColorSpace::DisplayP3 => tonemap::srgb_decode(encoded),
+            ColorSpace::Rec2020 => encoded.max(0.0).powf(2.4),
+            ColorSpace::AcesCg => encoded,
+        }
+    }
+}
+
+fn matrix(rows: &[[f64; 3]; 3]) -> glm::TMat3<f64> {
+    glm::mat3(
+        rows[0][0], rows[0][1], rows[0][2],
+        rows[1][0], rows[1][1], rows[1][2],
+        rows[2][0], rows[2][1], rows[2][2],
+    )
+}
+
+// The space the renderer works in: every color in the scene is converted into it when the scene
+// is built, and light is added up in it. It is the same for the whole run, so it is set once
+// before anything is loaded, rather than handed down to every material and integrator. The
+// conversions out of it are worked out then too, as every pixel shown goes through one.
+struct Working {
+    space: ColorSpace,
+    // From the working space to each space, in the order of `COLOR_SPACES`.
+    to: [glm::TMat3<f64>; 4],
+    from_srgb: glm::TMat3<f64>,
+}
+
+static WORKING: OnceLock<Working> = OnceLock::new();
+
+impl Working {
+    fn new(space: ColorSpace) -> Self {
+        Working {
+            space,
+            to: COLOR_SPACES.map(|to| space.conversion(to)),
+            from_srgb: ColorSpace::LinearSrgb.conversion(space),
+        }
+    }
+}
+
+pub fn set_working(space: ColorSpace) {
+    if WORKING.set(Working::new(space)).is_err() {
+        panic!("The working color space can only be set before it is used");
+    }
+}
+
+fn working_state() -> &'static Working {
+    WORKING.get_or_init(|| Working::new(ColorSpace::LinearSrgb))
+}
+
+pub fn working() -> ColorSpace {
+    working_state().space
+}
+
+// Converts `color` from the working space to `space`.
+pub fn from_working(color: &glm::TVec3<f64>, space: ColorSpace) -> glm::TVec3<f64> {
+    let working = working_state();
+    if working.space == space {
+        *color
+    } else {
+        working.to[space as usize] * color
+    }
+}
+
+// Converts between the working space and linear sRGB, which spectra and the sky are computed in.
+pub fn to_srgb(color: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+    from_working(color, ColorSpace::LinearSrgb)
+}
+
+pub fn from_srgb(color: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+    let working = working_state();
+    if working.space == ColorSpace::LinearSrgb {
+        *color
+    } else {
+        working.from_srgb * color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &glm::TMat3<f64>, b: &glm::TMat3<f64>, tolerance: f64) {
+        assert!((a - b).abs().max() <= tolerance, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn converting_to_the_same_space_changes_nothing() {
+        for space in COLOR_SPACES {
+            assert_close(&space.conversion(space), &glm::TMat3::identity(), 1e-12);
+        }
+    }
+
+    #[test]
+    fn white_stays_white() {
+        let white = glm::vec3(1.0, 1.0, 1.0);
+        for from in COLOR_SPACES {
+            for to in COLOR_SPACES {
+                let converted = from.conversion(to) * white;
+                assert!((converted - white).abs().max() < 1e-3, "{:?} to {:?}: {}", from, to, converted);
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_round_trips_through_xyz() {
+        let to_xyz = ColorSpace::LinearSrgb.to_xyz();
+        let round_trip = to_xyz.try_inverse().unwrap() * to_xyz;
+        assert_close(&round_trip, &glm::TMat3::identity(), 1e-12);
+        let color = glm::vec3(0.2, 0.5, 0.8);
+        let converted = ColorSpace::LinearSrgb.conversion(ColorSpace::DisplayP3) * color;
+        let back = ColorSpace::DisplayP3.conversion(ColorSpace::LinearSrgb) * converted;
+        assert!((back - color).abs().max() < 1e-9);
+    }
+
+    #[test]
+    fn working_conversions_are_in_the_order_of_the_spaces() {
+        let working = Working::new(ColorSpace::AcesCg);
+        for to in COLOR_SPACES {
+            assert_close(&working.to[to as usize], &ColorSpace::AcesCg.conversion(to), 0.0);
+        }
+    }
+}
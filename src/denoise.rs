@@ -0,0 +1,179 @@
+use nalgebra_glm as glm;
+use std::thread;
+
+use crate::camera::Camera;
+use crate::color;
+use crate::scene::Scene;
+
+// What the denoiser tells edges apart by: the surface first seen in each pixel.
+#[derive(Clone)]
+pub struct Guides {
+    pub albedo: Vec<glm::TVec3<f64>>,
+    pub normal: Vec<glm::TVec3<f64>>,
+    // Distance along the camera ray, infinite where nothing was hit.
+    pub depth: Vec<f64>,
+}
+
+impl Guides {
+    pub fn new(pixels: usize) -> Self {
+        Guides {
+            albedo: vec![glm::vec3(0.0, 0.0, 0.0); pixels],
+            normal: vec![glm::vec3(0.0, 0.0, 0.0); pixels],
+            depth: vec![f64::INFINITY; pixels],
+        }
+    }
+
+    // Sets the guides of `pixel` to what the ray through its centre hits.
+    pub fn trace(&mut self, scene: &Scene, camera: &Camera, pixel: (u32, u32), size: (u32, u32)) {
+        let (x, y) = pixel;
+        let (width, height) = size;
+        let index = (x + y * width) as usize;
+        let screen_coords = glm::vec2((x as f64 + 0.5) / width as f64, 1.0 - (y as f64 + 0.5) / height as f64);
+        let ray = camera.get_ray(screen_coords, glm::vec2(0.0, 0.0));
+        match scene.world.hit_object(&ray, 0.001, f64::MAX) {
+            Some((_, hit)) => {
+                self.albedo[index] = hit.material.albedo(&ray, &hit);
+                self.normal[index] = hit.material.shading_normal(&ray, &hit);
+                self.depth[index] = hit.t * ray.direction.magnitude();
+            }
+            None => {
+                self.albedo[index] = glm::vec3(0.0, 0.0, 0.0);
+                self.normal[index] = glm::vec3(0.0, 0.0, 0.0);
+                self.depth[index] = f64::INFINITY;
+            }
+        }
+    }
+}
+
+// Passes of the filter, each reaching twice as far as the last.
+const PASSES: u32 = 5;
+
+// Weights of the B3 spline the filter is made of, by distance from the centre in steps.
+const KERNEL: [f64; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// How sharply the edge stopping weights fall off with differences in brightness, relative to
+// the brightness's standard deviation, in normals, and in depth, relative to how fast the depth
+// changes across the pixel.
+const SIGMA_LUMINANCE: f64 = 4.0;
+const NORMAL_POWER: i32 = 128;
+const SIGMA_DEPTH: f64 = 1.0;
+
+// Albedos darker than this are left alone rather than divided out.
+const MIN_ALBEDO: f64 = 0.01;
+
+// Denoises `image` with the edge avoiding à-trous wavelet filter of Dammertz et al., "Edge-Avoiding
+// À-Trous Wavelet Transform for fast Global Illumination Filtering", with the variance guided
+// weights of Schied et al.'s SVGF. Each pass blurs with a 5x5 kernel whose taps are spread
+// further apart, but only across pixels that show the same surface at a similar depth and facing
+// a similar way, with a similar brightness given how noisy the pixel is. The albedo is divided
+// out first, so that textures stay sharp, and multiplied back in at the end.
+pub fn denoise(image: &[glm::TVec3<f64>], guides: &Guides, width: u32, height: u32, threads: usize) -> Vec<glm::TVec3<f64>> {
+    let (w, h) = (width as usize, height as usize);
+    let albedo: Vec<glm::TVec3<f64>> = guides.albedo.iter().map(|a| a.map(|c| if c > MIN_ALBEDO { c } else { 1.0 })).collect();
+    let mut color: Vec<glm::TVec3<f64>> = image.iter().zip(&albedo).map(|(c, a)| c.component_div(a)).collect();
+    let luminance: Vec<f64> = color.iter().map(color::luminance).collect();
+
+    // Neither the noise nor how fast the depth changes is known, so both are estimated from the
+    // pixel's neighbours.
+    let mut variance = vec![0.0; w * h];
+    let mut gradient = vec![0.0; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let (mut sum, mut squares, mut count) = (0.0, 0.0, 0.0);
+            for (nx, ny) in neighbours(x, y, w, h, 1) {
+                let l = luminance[nx + ny * w];
+                sum += l;
+                squares += l * l;
+                count += 1.0;
+            }
+            let mean = sum / count;
+            variance[x + y * w] = (squares / count - mean * mean).max(0.0);
+            let depth = |nx: usize, ny: usize| guides.depth[nx + ny * w];
+            let across = |a: f64, b: f64| if a.is_finite() && b.is_finite() { (a - b).abs() / 2.0 } else { 0.0 };
+            gradient[x + y * w] = across(depth(x.saturating_sub(1), y), depth((x + 1).min(w - 1), y))
+                .max(across(depth(x, y.saturating_sub(1)), depth(x, (y + 1).min(h - 1))));
+        }
+    }
+
+    for pass in 0..PASSES {
+        let step = 1 << pass;
+        let mut next_color = vec![glm::vec3(0.0, 0.0, 0.0); w * h];
+        let mut next_variance = vec![0.0; w * h];
+        let rows = h.div_ceil(threads.max(1));
+        thread::scope(|scope| {
+            for (chunk, (colors, variances)) in next_color.chunks_mut(rows * w).zip(next_variance.chunks_mut(rows * w)).enumerate() {
+                let (color, variance, gradient) = (&color, &variance, &gradient);
+                scope.spawn(move || {
+                    for (offset, (out_color, out_variance)) in colors.iter_mut().zip(variances.iter_mut()).enumerate() {
+                        let p = chunk * rows * w + offset;
+                        let (x, y) = (p % w, p / w);
+                        let l_p = color::luminance(&color[p]);
+                        let sigma_l = SIGMA_LUMINANCE * blurred(variance, x, y, w, h).sqrt() + 1e-6;
+                        let (mut weights, mut sum, mut variance_sum) = (0.0, glm::vec3(0.0, 0.0, 0.0), 0.0);
+                        for dy in -2i64..=2 {
+                            for dx in -2i64..=2 {
+                                let qx = x as i64 + dx * step;
+                                let qy = y as i64 + dy * step;
+                                if qx < 0 || qy < 0 || qx >= w as i64 || qy >= h as i64 {
+                                    continue;
+                                }
+                                let q = qx as usize + qy as usize * w;
+                                let kernel = KERNEL[dx.unsigned_abs() as usize] * KERNEL[dy.unsigned_abs() as usize];
+                                let distance = ((dx * dx + dy * dy) as f64).sqrt() * step as f64;
+                                let weight = kernel
+                                    * surface_weight(guides, p, q, gradient[p] * distance)
+                                    * (-(l_p - color::luminance(&color[q])).abs() / sigma_l).exp();
+                                weights += weight;
+                                sum += color[q] * weight;
+                                variance_sum += weight * weight * variance[q];
+                            }
+                        }
+                        if weights > 0.0 {
+                            *out_color = sum / weights;
+                            *out_variance = variance_sum / (weights * weights);
+                        } else {
+                            *out_color = color[p];
+                            *out_variance = variance[p];
+                        }
+                    }
+                });
+            }
+        });
+        color = next_color;
+        variance = next_variance;
+    }
+    color.iter().zip(&albedo).map(|(c, a)| c.component_mul(a)).collect()
+}
+
+// How much pixel `q` looks like the same surface as pixel `p`, by their normals and depths, with
+// the depth allowed to change by `slope` between them.
+fn surface_weight(guides: &Guides, p: usize, q: usize, slope: f64) -> f64 {
+    let (depth_p, depth_q) = (guides.depth[p], guides.depth[q]);
+    match (depth_p.is_finite(), depth_q.is_finite()) {
+        // Both see the background.
+        (false, false) => 1.0,
+        (true, true) => {
+            let normal = glm::dot(&guides.normal[p], &guides.normal[q]).max(0.0).powi(NORMAL_POWER);
+            normal * (-(depth_p - depth_q).abs() / (SIGMA_DEPTH * slope + 1e-6)).exp()
+        }
+        _ => 0.0,
+    }
+}
+
+// The variance at (x, y) blurred with its neighbours, as estimates from few samples are noisy
+// themselves.
+fn blurred(variance: &[f64], x: usize, y: usize, width: usize, height: usize) -> f64 {
+    let (mut sum, mut weights) = (0.0, 0.0);
+    for (nx, ny) in neighbours(x, y, width, height, 1) {
+        let weight = [0.5, 0.25][nx.abs_diff(x)] * [0.5, 0.25][ny.abs_diff(y)];
+        sum += variance[nx + ny * width] * weight;
+        weights += weight;
+    }
+    sum / weights
+}
+
+// The pixels up to `radius` from (x, y) that are in the image, (x, y) included.
+fn neighbours(x: usize, y: usize, width: usize, height: usize, radius: usize) -> impl Iterator<Item = (usize, usize)> {
+    let xs = x.saturating_sub(radius)..(x + radius + 1).min(width);
+    (y.saturating_sub(radius)..(y + radius + 1).min(height)).flat_map(move |ny| xs.clone().map(move |nx| (nx, ny)))
+}
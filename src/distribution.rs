@@ -0,0 +1,104 @@
+use nalgebra_glm as glm;
+
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].abs() / n as f64;
+        }
+        let integral = cdf[n];
+        if integral == 0.0 {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Distribution1D { func, cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Returns the sampled position in [0, 1), its pdf and the index of the bucket it fell into.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let offset = self
+            .cdf
+            .partition_point(|&c| c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = if self.integral > 0.0 {
+            self.func[offset] / self.integral
+        } else {
+            0.0
+        };
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+        let (_, _, offset) = self.sample_continuous(u);
+        (offset, self.discrete_pdf(offset))
+    }
+
+    pub fn discrete_pdf(&self, index: usize) -> f64 {
+        if self.integral > 0.0 {
+            self.func[index].abs() / (self.integral * self.count() as f64)
+        } else {
+            1.0 / self.count() as f64
+        }
+    }
+}
+
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // `func` is laid out row by row, with `nu` values per row and `nv` rows.
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Self {
+        let conditional: Vec<Distribution1D> = func
+            .chunks(nu)
+            .take(nv)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Distribution2D { conditional, marginal }
+    }
+
+    pub fn sample(&self, u: glm::TVec2<f64>) -> (glm::TVec2<f64>, f64) {
+        let (v, marginal_pdf, row) = self.marginal.sample_continuous(u.y);
+        let (u, conditional_pdf, _) = self.conditional[row].sample_continuous(u.x);
+        (glm::vec2(u, v), marginal_pdf * conditional_pdf)
+    }
+
+    pub fn pdf(&self, uv: glm::TVec2<f64>) -> f64 {
+        if self.marginal.integral() == 0.0 {
+            return 0.0;
+        }
+        let nv = self.marginal.count();
+        let row = ((uv.y * nv as f64) as usize).min(nv - 1);
+        let nu = self.conditional[row].count();
+        let column = ((uv.x * nu as f64) as usize).min(nu - 1);
+        self.conditional[row].func[column] / self.marginal.integral()
+    }
+}
@@ -0,0 +1,141 @@
+use crate::background::Background;
+use crate::color;
+use crate::colorspace::{self, ColorSpace};
+use crate::distribution::Distribution2D;
+use image::codecs::hdr::HdrDecoder;
+use nalgebra_glm as glm;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<glm::TVec3<f64>>,
+    rotation: f64,
+    intensity: f64,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    // Loads a map whose colors are in `space`, linear in HDR and OpenEXR files and encoded with
+    // the space's transfer curve in others.
+    pub fn load(path: &str, rotation: f64, intensity: f64, space: ColorSpace) -> Result<Self, String> {
+        let (width, height, mut pixels) = load_pixels(path, space)?;
+        if width == 0 || height == 0 {
+            return Err(format!("Environment map {} is empty", path));
+        }
+        if space != colorspace::working() {
+            let conversion = space.conversion(colorspace::working());
+            for pixel in &mut pixels {
+                *pixel = conversion * *pixel;
+            }
+        }
+        Ok(EnvironmentMap::new(width, height, pixels, rotation, intensity))
+    }
+
+    pub fn new(width: usize, height: usize, pixels: Vec<glm::TVec3<f64>>, rotation: f64, intensity: f64) -> Self {
+        // Weight each texel by its brightness and by the solid angle it covers on the sphere.
+        let mut func = vec![0.0; width * height];
+        for y in 0..height {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                func[y * width + x] = color::luminance(&pixels[y * width + x]) * sin_theta;
+            }
+        }
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            rotation,
+            intensity,
+            distribution: Distribution2D::new(&func, width, height),
+        }
+    }
+
+    fn direction_to_uv(&self, direction: &glm::TVec3<f64>) -> glm::TVec2<f64> {
+        let d = glm::normalize(direction);
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        let phi = d.z.atan2(d.x) + PI + self.rotation;
+        glm::vec2(phi.rem_euclid(2.0 * PI) / (2.0 * PI), theta / PI)
+    }
+
+    fn uv_to_direction(&self, uv: glm::TVec2<f64>) -> glm::TVec3<f64> {
+        let theta = uv.y * PI;
+        let phi = uv.x * 2.0 * PI - PI - self.rotation;
+        glm::vec3(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+
+    fn lookup(&self, uv: glm::TVec2<f64>) -> glm::TVec3<f64> {
+        let x = ((uv.x * self.width as f64) as usize).min(self.width - 1);
+        let y = ((uv.y * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+}
+
+impl Background for EnvironmentMap {
+    fn color(&self, direction: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        self.lookup(self.direction_to_uv(direction))
+    }
+
+    fn sample(&self, u: glm::TVec2<f64>) -> Option<(glm::TVec3<f64>, glm::TVec3<f64>, f64)> {
+        let (uv, map_pdf) = self.distribution.sample(u);
+        let sin_theta = (uv.y * PI).sin();
+        if map_pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+        let pdf = map_pdf / (2.0 * PI * PI * sin_theta);
+        Some((self.uv_to_direction(uv), self.lookup(uv), pdf))
+    }
+
+    fn pdf(&self, direction: &glm::TVec3<f64>) -> f64 {
+        let uv = self.direction_to_uv(direction);
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+fn load_pixels(path: &str, space: ColorSpace) -> Result<(usize, usize, Vec<glm::TVec3<f64>>), String> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    match extension.as_deref() {
+        Some("hdr") => {
+            let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+            let decoder = HdrDecoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+            let metadata = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|p| glm::vec3(p[0] as f64, p[1] as f64, p[2] as f64))
+                .collect();
+            Ok((metadata.width as usize, metadata.height as usize, pixels))
+        }
+        Some("exr") => {
+            let image = exr::prelude::read_first_rgba_layer_from_file(
+                path,
+                |resolution, _| (resolution.width(), vec![glm::vec3(0.0, 0.0, 0.0); resolution.area()]),
+                |(width, pixels): &mut (usize, Vec<glm::TVec3<f64>>), position, (r, g, b, _a): (f32, f32, f32, f32)| {
+                    pixels[position.y() * *width + position.x()] = glm::vec3(r as f64, g as f64, b as f64);
+                },
+            )
+            .map_err(|e| e.to_string())?;
+            let (width, pixels) = image.layer_data.channel_data.pixels;
+            Ok((width, pixels.len() / width.max(1), pixels))
+        }
+        _ => {
+            let image = image::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?.to_rgb16();
+            let pixels = image
+                .pixels()
+                .map(|p| glm::vec3(p[0] as f64, p[1] as f64, p[2] as f64).map(|c| space.decode(c / 65535.0)))
+                .collect();
+            Ok((image.width() as usize, image.height() as usize, pixels))
+        }
+    }
+}
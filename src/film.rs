@@ -0,0 +1,146 @@
+use nalgebra_glm as glm;
+
+use crate::filter::Filter;
+
+// Sums are kept in fixed point with this many steps to the unit. Threads add their samples in no
+// particular order, and unlike sums of floats, sums of integers don't depend on it.
+const SCALE: f64 = (1u64 << 32) as f64;
+
+// Where the samples of an image are gathered. Each sample counts towards every pixel within the
+// filter's radius of where it landed, weighted by the filter, and a pixel is the weighted average
+// of its samples. Where the negative lobes of a filter leave a pixel with no weight, it falls back
+// to the plain average of the samples that landed inside it, the nearest it has. Splats from
+// integrators that trace paths from the lights are kept apart, as they are averaged over the
+// samples of the whole image instead.
+pub struct Film {
+    width: u32,
+    height: u32,
+    filter: Box<dyn Filter>,
+    pixels: Vec<FilmPixel>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct FilmPixel {
+    color: [i64; 3],
+    weight: i64,
+    splat: [i64; 3],
+    // Sum and count of the samples that landed inside the pixel.
+    inside: [i64; 3],
+    inside_count: i64,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, filter: Box<dyn Filter>) -> Self {
+        Film { width, height, filter, pixels: vec![FilmPixel::default(); (width * height) as usize] }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.fill(FilmPixel::default());
+    }
+
+    // Adds a sample that landed at `position`, in pixels from the top left corner.
+    pub fn add_sample(&mut self, position: &glm::TVec2<f64>, color: &glm::TVec3<f64>) {
+        for (index, weight) in self.footprint(position) {
+            let pixel = &mut self.pixels[index];
+            add(&mut pixel.color, &(color * weight));
+            pixel.weight = pixel.weight.saturating_add(to_fixed(weight));
+        }
+        let (x, y) = (position.x.floor(), position.y.floor());
+        if x >= 0.0 && y >= 0.0 && x < self.width as f64 && y < self.height as f64 {
+            let pixel = &mut self.pixels[x as usize + y as usize * self.width as usize];
+            add(&mut pixel.inside, color);
+            pixel.inside_count += 1;
+        }
+    }
+
+    // Adds light splatted at `screen_coords`, spread over the pixels around it in proportion to
+    // the filter so that all of it lands somewhere.
+    pub fn add_splat(&mut self, screen_coords: &glm::TVec2<f64>, color: &glm::TVec3<f64>) {
+        let position = glm::vec2(screen_coords.x * self.width as f64, (1.0 - screen_coords.y) * self.height as f64);
+        let footprint = self.footprint(&position);
+        let total: f64 = footprint.iter().map(|(_, weight)| weight).sum();
+        if total <= 0.0 {
+            return;
+        }
+        for (index, weight) in footprint {
+            add(&mut self.pixels[index].splat, &(color * (weight / total)));
+        }
+    }
+
+    // Color of the pixel at `index`, with its splats scaled by `splat_scale`.
+    pub fn pixel(&self, index: usize, splat_scale: f64) -> glm::TVec3<f64> {
+        let pixel = &self.pixels[index];
+        let splat = from_fixed(&pixel.splat) * splat_scale;
+        if pixel.weight <= 0 {
+            if pixel.inside_count > 0 {
+                return from_fixed(&pixel.inside) / pixel.inside_count as f64 + splat;
+            }
+            return splat;
+        }
+        let color = from_fixed(&pixel.color) / (pixel.weight as f64 / SCALE);
+        // Filters with negative lobes can take a pixel below black next to a bright one.
+        color.map(|c| c.max(0.0)) + splat
+    }
+
+    pub fn has_samples(&self, index: usize) -> bool {
+        self.pixels[index].weight > 0 || self.pixels[index].inside_count > 0
+    }
+
+    // The pixels within the filter radius of `position`, with their weights.
+    fn footprint(&self, position: &glm::TVec2<f64>) -> Vec<(usize, f64)> {
+        let radius = self.filter.radius();
+        // Pixels whose centre is further than the radius before the sample, or no further after.
+        let range = |p: f64, size: u32| {
+            let first = ((p - 0.5 - radius).floor() as i64 + 1).max(0);
+            let last = ((p - 0.5 + radius).floor() as i64).min(size as i64 - 1);
+            first as u32..(last + 1).max(first) as u32
+        };
+        let mut footprint = Vec::new();
+        for y in range(position.y, self.height) {
+            let weight_y = self.filter.evaluate(y as f64 + 0.5 - position.y);
+            if weight_y == 0.0 {
+                continue;
+            }
+            for x in range(position.x, self.width) {
+                let weight = self.filter.evaluate(x as f64 + 0.5 - position.x) * weight_y;
+                if weight != 0.0 {
+                    footprint.push(((x + y * self.width) as usize, weight));
+                }
+            }
+        }
+        footprint
+    }
+}
+
+fn to_fixed(value: f64) -> i64 {
+    (value * SCALE) as i64
+}
+
+fn from_fixed(sum: &[i64; 3]) -> glm::TVec3<f64> {
+    glm::vec3(sum[0] as f64, sum[1] as f64, sum[2] as f64) / SCALE
+}
+
+fn add(sum: &mut [i64; 3], value: &glm::TVec3<f64>) {
+    for (sum, value) in sum.iter_mut().zip(value.iter()) {
+        *sum = sum.saturating_add(to_fixed(*value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter;
+
+    #[test]
+    fn falls_back_to_samples_inside_where_negative_lobes_win() {
+        let mut film = Film::new(3, 1, filter::by_name("mitchell", 2.0).unwrap());
+        film.add_sample(&glm::vec2(0.5, 0.5), &glm::vec3(1.0, 1.0, 1.0));
+        // A pixel and a half away, where the filter is negative.
+        for _ in 0..100 {
+            film.add_sample(&glm::vec2(2.0, 0.5), &glm::vec3(5.0, 5.0, 5.0));
+        }
+        assert!(film.has_samples(0));
+        let color = film.pixel(0, 0.0);
+        assert!((color - glm::vec3(1.0, 1.0, 1.0)).magnitude() < 1e-6, "{:?}", color);
+    }
+}
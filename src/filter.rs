@@ -0,0 +1,146 @@
+use std::f64::consts::PI;
+
+// How much a sample counts towards a pixel, by how far it landed from the pixel's centre along
+// each axis, in pixels. Filters are the product of the same curve along both axes.
+pub trait Filter: Send + Sync {
+    // Distance from the centre past which samples don't count.
+    fn radius(&self) -> f64;
+
+    fn evaluate(&self, x: f64) -> f64;
+}
+
+pub const NAMES: [&str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+// Radius each filter is used with unless told otherwise, wide enough for its whole shape.
+pub fn default_radius(name: &str) -> f64 {
+    match name {
+        "tent" => 1.0,
+        "gaussian" => 1.5,
+        "mitchell" => 2.0,
+        "lanczos" => 3.0,
+        _ => 0.5,
+    }
+}
+
+pub fn by_name(name: &str, radius: f64) -> Option<Box<dyn Filter>> {
+    let filter: Box<dyn Filter> = match name {
+        "box" => Box::new(BoxFilter { radius }),
+        "tent" => Box::new(TentFilter { radius }),
+        "gaussian" => Box::new(GaussianFilter { radius, sigma: radius / 3.0 }),
+        "mitchell" => Box::new(MitchellFilter { radius, b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+        "lanczos" => Box::new(LanczosFilter { radius }),
+        _ => return None,
+    };
+    Some(filter)
+}
+
+// Every sample within the radius counts the same. At a radius of half a pixel, each sample only
+// counts towards the pixel it was taken in.
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64) -> f64 {
+        if x.abs() <= self.radius {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// Falls off linearly to the radius.
+pub struct TentFilter {
+    pub radius: f64,
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0)
+    }
+}
+
+// A Gaussian, shifted down to reach zero at the radius.
+pub struct GaussianFilter {
+    pub radius: f64,
+    pub sigma: f64,
+}
+
+impl GaussianFilter {
+    fn gaussian(&self, x: f64) -> f64 {
+        (-x * x / (2.0 * self.sigma * self.sigma)).exp()
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64) -> f64 {
+        (self.gaussian(x) - self.gaussian(self.radius)).max(0.0)
+    }
+}
+
+// The cubic from Mitchell and Netravali, "Reconstruction Filters in Computer Graphics", stretched
+// over the radius. Its negative lobes sharpen edges that a Gaussian would blur, and `b` and `c`
+// trade that off against ringing; a third each is their recommendation.
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64) -> f64 {
+        let (b, c) = (self.b, self.c);
+        let x = (2.0 * x / self.radius).abs();
+        let value = if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            (-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+        } else {
+            (12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)
+        };
+        value / 6.0
+    }
+}
+
+// A sinc windowed by a wider sinc that reaches zero at the radius, which keeps detail up to the
+// pixel spacing sharper than the other filters at the cost of some ringing.
+pub struct LanczosFilter {
+    pub radius: f64,
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64) -> f64 {
+        if x.abs() >= self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.radius)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
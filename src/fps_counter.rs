@@ -27,7 +27,7 @@ impl FpsCounter {
             .expect("Time went backwards");
         let now = since_the_epoch.as_secs() * 1000 +
         since_the_epoch.subsec_nanos() as u64 / 1_000_000;
-        if now - self.last_time as u64 >= 1000 {
+        if now - self.last_time >= 1000 {
             self.frames = 0;
         }
         let temp_last_time = self.last_time;
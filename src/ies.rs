@@ -0,0 +1,289 @@
+use nalgebra_glm as glm;
+use std::fmt;
+use std::fs;
+
+#[derive(Debug)]
+pub enum IesError {
+    Io(std::io::Error),
+    MissingTilt,
+    UnexpectedEnd,
+    InvalidNumber(String),
+    UnsupportedPhotometricType(i32),
+    InvalidAngles(&'static str),
+}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IesError::Io(e) => write!(f, "could not read IES file: {}", e),
+            IesError::MissingTilt => write!(f, "missing TILT= line"),
+            IesError::UnexpectedEnd => write!(f, "file ended before all photometric data was read"),
+            IesError::InvalidNumber(token) => write!(f, "expected a number, found `{}`", token),
+            IesError::UnsupportedPhotometricType(t) => write!(f, "unsupported photometric type {}, only type C is supported", t),
+            IesError::InvalidAngles(reason) => write!(f, "invalid angles: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for IesError {}
+
+impl From<std::io::Error> for IesError {
+    fn from(e: std::io::Error) -> Self {
+        IesError::Io(e)
+    }
+}
+
+// A type C photometric profile from an IES LM-63 file. Candela values are normalized so the
+// brightest direction has an intensity of one.
+#[derive(Debug)]
+pub struct IesProfile {
+    vertical_angles: Vec<f64>,
+    horizontal_angles: Vec<f64>,
+    // One row of vertical samples per horizontal angle.
+    candela: Vec<Vec<f64>>,
+}
+
+impl IesProfile {
+    pub fn load(path: &str) -> Result<Self, IesError> {
+        IesProfile::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, IesError> {
+        let mut lines = source.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim()[5..].to_string(),
+                Some(_) => continue,
+                None => return Err(IesError::MissingTilt),
+            }
+        };
+        let rest: Vec<&str> = lines.flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',')).filter(|t| !t.is_empty()).collect();
+        let mut numbers = Numbers { tokens: rest.into_iter() };
+
+        // The tilt data describes how the lamp output changes with its orientation, which only
+        // matters for fixtures mounted at an angle. It is read past but otherwise ignored.
+        if tilt == "INCLUDE" {
+            numbers.next()?;
+            let pairs = numbers.next()? as usize;
+            for _ in 0..pairs * 2 {
+                numbers.next()?;
+            }
+        }
+
+        let _lamps = numbers.next()?;
+        let _lumens_per_lamp = numbers.next()?;
+        let multiplier = numbers.next()?;
+        let vertical_count = numbers.next()? as usize;
+        let horizontal_count = numbers.next()? as usize;
+        let photometric_type = numbers.next()? as i32;
+        let _units = numbers.next()?;
+        let _width = numbers.next()?;
+        let _length = numbers.next()?;
+        let _height = numbers.next()?;
+        let _ballast_factor = numbers.next()?;
+        let _future_use = numbers.next()?;
+        let _input_watts = numbers.next()?;
+
+        if photometric_type != 1 {
+            return Err(IesError::UnsupportedPhotometricType(photometric_type));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(IesError::InvalidAngles("no angles given"));
+        }
+
+        let vertical_angles = numbers.take(vertical_count)?;
+        let horizontal_angles = numbers.take(horizontal_count)?;
+        if !is_sorted(&vertical_angles) || !is_sorted(&horizontal_angles) {
+            return Err(IesError::InvalidAngles("angles must be increasing"));
+        }
+        if vertical_angles[0] < 0.0 || vertical_angles[vertical_count - 1] > 180.0 {
+            return Err(IesError::InvalidAngles("vertical angles must lie between 0 and 180 degrees"));
+        }
+        // Symmetric fixtures give a quadrant, a half or a single plane of the distribution, and
+        // bilaterally symmetric ones may give the half from 90 to 270 degrees.
+        let last = horizontal_angles[horizontal_count - 1];
+        if !((horizontal_angles[0] == 0.0 && [0.0, 90.0, 180.0, 360.0].contains(&last))
+            || (horizontal_angles[0] == 90.0 && last == 270.0))
+        {
+            return Err(IesError::InvalidAngles(
+                "horizontal angles must run from 0 to 0, 90, 180 or 360 degrees, or from 90 to 270",
+            ));
+        }
+
+        let mut candela = Vec::with_capacity(horizontal_count);
+        for _ in 0..horizontal_count {
+            candela.push(numbers.take(vertical_count)?.into_iter().map(|c| c * multiplier).collect::<Vec<f64>>());
+        }
+        let max = candela.iter().flatten().fold(0.0_f64, |a, &b| a.max(b));
+        if max > 0.0 {
+            for value in candela.iter_mut().flatten() {
+                *value /= max;
+            }
+        }
+
+        Ok(IesProfile { vertical_angles, horizontal_angles, candela })
+    }
+
+    // Relative intensity towards `direction`, given in the frame of the fixture where `axis`
+    // points at the nadir (vertical angle 0) and `reference` at horizontal angle 0.
+    pub fn evaluate(&self, direction: &glm::TVec3<f64>, axis: &glm::TVec3<f64>, reference: &glm::TVec3<f64>) -> f64 {
+        let d = glm::normalize(direction);
+        let vertical = glm::dot(&d, axis).clamp(-1.0, 1.0).acos().to_degrees();
+        let side = glm::cross(axis, reference);
+        let horizontal = glm::dot(&d, &side).atan2(glm::dot(&d, reference)).to_degrees();
+        let horizontal = self.fold_horizontal(horizontal.rem_euclid(360.0));
+
+        let (h0, h1, ht) = match interval(&self.horizontal_angles, horizontal) {
+            Some(found) => found,
+            None => return 0.0,
+        };
+        let (v0, v1, vt) = match interval(&self.vertical_angles, vertical) {
+            Some(found) => found,
+            None => return 0.0,
+        };
+        let at = |h: usize| self.candela[h][v0] * (1.0 - vt) + self.candela[h][v1] * vt;
+        at(h0) * (1.0 - ht) + at(h1) * ht
+    }
+
+    // Files only store the part of the distribution that is not implied by its symmetry.
+    fn fold_horizontal(&self, angle: f64) -> f64 {
+        let last = *self.horizontal_angles.last().unwrap();
+        if self.horizontal_angles[0] == 90.0 {
+            // Mirrored across the plane through 90 and 270 degrees.
+            if (90.0..=270.0).contains(&angle) { angle } else { (180.0 - angle).rem_euclid(360.0) }
+        } else if last == 0.0 {
+            0.0
+        } else if last == 90.0 {
+            let angle = if angle > 180.0 { 360.0 - angle } else { angle };
+            if angle > 90.0 { 180.0 - angle } else { angle }
+        } else if last == 180.0 {
+            if angle > 180.0 { 360.0 - angle } else { angle }
+        } else {
+            angle
+        }
+    }
+}
+
+struct Numbers<'a> {
+    tokens: std::vec::IntoIter<&'a str>,
+}
+
+impl Numbers<'_> {
+    fn next(&mut self) -> Result<f64, IesError> {
+        let token = self.tokens.next().ok_or(IesError::UnexpectedEnd)?;
+        token.parse().map_err(|_| IesError::InvalidNumber(token.to_string()))
+    }
+
+    fn take(&mut self, count: usize) -> Result<Vec<f64>, IesError> {
+        (0..count).map(|_| self.next()).collect()
+    }
+}
+
+fn is_sorted(values: &[f64]) -> bool {
+    values.windows(2).all(|w| w[0] < w[1])
+}
+
+// Finds the pair of samples around `x` and how far between them it lies.
+fn interval(values: &[f64], x: f64) -> Option<(usize, usize, f64)> {
+    if values.len() == 1 {
+        return Some((0, 0, 0.0));
+    }
+    if x < values[0] || x > values[values.len() - 1] {
+        return None;
+    }
+    let i = values.partition_point(|&v| v <= x).clamp(1, values.len() - 1) - 1;
+    let t = (x - values[i]) / (values[i + 1] - values[i]);
+    Some((i, i + 1, t.clamp(0.0, 1.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A lamp giving 100 cd straight down, half that at 45 degrees and nothing sideways.
+    const MINIMAL: &str = "IESNA:LM-63-2002
+[TEST] minimal
+TILT=NONE
+1 1000 1 3 1 1 2 0 0 0
+1 1 100
+0 45 90
+0
+100 50 0
+";
+
+    const DOWN: glm::TVec3<f64> = glm::TVec3::new(0.0, -1.0, 0.0);
+    const REFERENCE: glm::TVec3<f64> = glm::TVec3::new(1.0, 0.0, 0.0);
+
+    // Direction at `vertical` degrees from the nadir and `horizontal` degrees around it.
+    fn direction(vertical: f64, horizontal: f64) -> glm::TVec3<f64> {
+        let (v, h) = (vertical.to_radians(), horizontal.to_radians());
+        let side = glm::cross(&DOWN, &REFERENCE);
+        DOWN * v.cos() + (REFERENCE * h.cos() + side * h.sin()) * v.sin()
+    }
+
+    #[test]
+    fn parses_minimal_type_c() {
+        let profile = IesProfile::parse(MINIMAL).unwrap();
+        assert!((profile.evaluate(&direction(0.0, 0.0), &DOWN, &REFERENCE) - 1.0).abs() < 1e-9);
+        assert!((profile.evaluate(&direction(45.0, 123.0), &DOWN, &REFERENCE) - 0.5).abs() < 1e-9);
+        assert!(profile.evaluate(&direction(90.0, 0.0), &DOWN, &REFERENCE).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let truncated = MINIMAL.trim_end().trim_end_matches('0').trim_end();
+        assert!(matches!(IesProfile::parse(truncated), Err(IesError::UnexpectedEnd)));
+        assert!(matches!(IesProfile::parse("IESNA:LM-63-2002\n"), Err(IesError::MissingTilt)));
+    }
+
+    #[test]
+    fn rejects_unordered_angles() {
+        let unordered = MINIMAL.replace("0 45 90", "0 90 45");
+        assert!(matches!(IesProfile::parse(&unordered), Err(IesError::InvalidAngles(_))));
+        let late_start = MINIMAL.replace("1 1000 1 3 1", "1 1000 1 3 2").replace("\n0\n", "\n45 90\n").replace("100 50 0", "100 50 0 100 50 0");
+        assert!(matches!(IesProfile::parse(&late_start), Err(IesError::InvalidAngles(_))));
+    }
+
+    #[test]
+    fn rejects_horizontal_angles_ending_between_symmetries() {
+        let profile = |last: &str| {
+            let source = MINIMAL.replace("1 1000 1 3 1", "1 1000 1 3 2").replace("\n0\n", &format!("\n0 {}\n", last));
+            IesProfile::parse(&source.replace("100 50 0", "100 50 0 100 50 0"))
+        };
+        for last in ["45", "135", "270", "300"] {
+            assert!(matches!(profile(last), Err(IesError::InvalidAngles(_))));
+        }
+        for last in ["90", "180", "360"] {
+            assert!(profile(last).is_ok());
+        }
+    }
+
+    #[test]
+    fn skips_included_tilt() {
+        let tilted = MINIMAL.replace("TILT=NONE", "TILT=INCLUDE\n1\n2\n0 90\n1 0.5");
+        let profile = IesProfile::parse(&tilted).unwrap();
+        assert!((profile.evaluate(&direction(45.0, 0.0), &DOWN, &REFERENCE) - 0.5).abs() < 1e-9);
+        let cut_short = MINIMAL.replace("TILT=NONE", "TILT=INCLUDE\n1\n2\n0 90");
+        assert!(IesProfile::parse(&cut_short).is_err());
+    }
+
+    #[test]
+    fn mirrors_bilateral_profiles_from_90_degrees() {
+        // Sideways, 100 cd at 90 degrees, 50 at 180 and 0 at 270.
+        let bilateral = "TILT=NONE
+1 1000 1 2 3 1 2 0 0 0
+1 1 100
+0 90
+90 180 270
+100 100
+100 50
+100 0
+";
+        let profile = IesProfile::parse(bilateral).unwrap();
+        let sideways = |horizontal: f64| profile.evaluate(&direction(90.0, horizontal), &DOWN, &REFERENCE);
+        assert!((sideways(90.0) - 1.0).abs() < 1e-9);
+        assert!((sideways(0.0) - sideways(180.0)).abs() < 1e-9);
+        assert!((sideways(45.0) - sideways(135.0)).abs() < 1e-9);
+        assert!((sideways(315.0) - sideways(225.0)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,281 @@
+use nalgebra_glm as glm;
+
+use crate::bdpt;
+use crate::camera::Camera;
+use crate::colorspace;
+use crate::materials;
+use crate::mlt;
+use crate::objects::Hittable;
+use crate::path;
+use crate::photon;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::tonemap;
+use crate::util;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+pub trait Integrator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Called before each frame, for integrators that trace light into the scene ahead of the
+    // camera paths. `pass` counts the frames already accumulated into the same image.
+    fn preprocess(&mut self, _scene: &Scene, _camera: &Camera, _pass: u64) {}
+
+    // Radiance arriving along `r`, or whatever quantity the integrator visualizes instead, with
+    // the sampler set to the sample the ray was made for. The `camera` is the one the frame is
+    // rendered from, for integrators that connect paths to it.
+    fn li(&self, r: &Ray, scene: &Scene, camera: &Camera, sampler: &mut dyn Sampler) -> glm::TVec3<f64>;
+
+    // Like `li`, but also handing every bit of light the path picks up to `record`, with where
+    // it came from and whether it is direct, reaching the camera off at most one diffuse
+    // surface. Only integrators that can tell, as `splits_light` says, call it.
+    fn li_by_source(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        camera: &Camera,
+        sampler: &mut dyn Sampler,
+        _record: &mut dyn FnMut(Source, bool, &glm::TVec3<f64>),
+    ) -> glm::TVec3<f64> {
+        self.li(r, scene, camera, sampler)
+    }
+
+    fn splits_light(&self) -> bool {
+        false
+    }
+
+    // Contributions to other pixels than the one being traced, by their screen coordinates, from
+    // integrators that connect paths to the camera. The caller divides them by the number of
+    // samples taken over the whole image.
+    fn take_splats(&self) -> Vec<(glm::TVec2<f64>, glm::TVec3<f64>)> {
+        Vec::new()
+    }
+
+    // Whether the image depends on the order `li` is called in, so rendering it on several
+    // threads would make it come out differently every time.
+    fn is_sequential(&self) -> bool {
+        false
+    }
+}
+
+// Where light reaching the camera was emitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    // The light at this index among the scene's lights.
+    Light(usize),
+    // The background, or a glowing surface that isn't one of the lights.
+    Background,
+}
+
+// What the integrators are configured with.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub depths: path::PathDepths,
+    pub photons: usize,
+    pub photon_radius: f64,
+    pub mutations: u32,
+    pub bootstrap_samples: u32,
+    pub large_step_probability: f64,
+    pub mutation_sigma: f64,
+    pub spectral: bool,
+    // Seeds the random numbers that integrators draw outside of the camera samples.
+    pub seed: u64,
+}
+
+pub const NAMES: [&str; 11] = ["path", "bdpt", "photon", "mlt", "ao", "direct", "normals", "depth", "uv", "material", "hit"];
+
+pub fn by_name(name: &str, settings: &Settings) -> Option<Box<dyn Integrator>> {
+    let depths = settings.depths;
+    let integrator: Box<dyn Integrator> = match name {
+        "path" => Box::new(path::PathIntegrator { depths, spectral: settings.spectral }),
+        "bdpt" => Box::new(bdpt::BdptIntegrator::new(depths.diffuse)),
+        "photon" => Box::new(photon::PhotonIntegrator::new(depths, settings.photons, settings.photon_radius, settings.seed)),
+        "mlt" => Box::new(mlt::MltIntegrator::new(
+            depths,
+            settings.mutations,
+            settings.bootstrap_samples,
+            settings.large_step_probability,
+            settings.mutation_sigma,
+            settings.spectral,
+            settings.seed,
+        )),
+        "ao" => Box::new(AmbientOcclusion { distance: 1.0 }),
+        "direct" => Box::new(DirectLighting { max_depth: depths.specular.max(depths.transmission) }),
+        "normals" => Box::new(DebugIntegrator::new(DebugView::Normals)),
+        "depth" => Box::new(DebugIntegrator::new(DebugView::Depth)),
+        "uv" => Box::new(DebugIntegrator::new(DebugView::Uv)),
+        "material" => Box::new(DebugIntegrator::new(DebugView::MaterialId)),
+        "hit" => Box::new(DebugIntegrator::new(DebugView::HitMiss)),
+        _ => return None,
+    };
+    Some(integrator)
+}
+
+pub fn next(current: &dyn Integrator, settings: &Settings) -> Box<dyn Integrator> {
+    let index = NAMES.iter().position(|&name| name == current.name()).unwrap_or(0);
+    by_name(NAMES[(index + 1) % NAMES.len()], settings).unwrap()
+}
+
+// White where the surface is open to the sky within `distance`, black where it is occluded.
+pub struct AmbientOcclusion {
+    pub distance: f64,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn name(&self) -> &'static str {
+        "ao"
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, _camera: &Camera, sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        if let Some(hit) = scene.world.hit(r, 0.001, f64::MAX) {
+            let mut direction = hit.normal + util::uniform_sphere(sampler.get_2d());
+            if util::near_zero(&direction) {
+                direction = hit.normal;
+            }
+            let occlusion_ray = Ray::new(hit.p, direction);
+            if scene.world.hit(&occlusion_ray, 0.001, self.distance / direction.magnitude()).is_some() {
+                return glm::vec3(0.0, 0.0, 0.0);
+            }
+        }
+        glm::vec3(1.0, 1.0, 1.0)
+    }
+}
+
+// Light reaching the first diffuse surface straight from the lights and background, following
+// mirrors and glass on the way there.
+pub struct DirectLighting {
+    pub max_depth: u32,
+}
+
+impl Integrator for DirectLighting {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, _camera: &Camera, sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+        let mut ray = Ray::new(r.origin, r.direction);
+        for _ in 0..=self.max_depth {
+            let hit = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(hit) => hit,
+                None => {
+                    color += throughput.component_mul(&scene.background.color(&ray.direction));
+                    break;
+                }
+            };
+            color += throughput.component_mul(&hit.material.emitted(&ray, &hit));
+            let (scattered, attenuation) = match hit.material.scatter(&ray, &hit, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let pdf = hit.material.scattering_pdf(&ray, &hit, &scattered);
+            if pdf > 0.0 {
+                let background = path::sample_background(&ray, &hit, scene, sampler);
+                let direct = background + path::sample_light(&ray, &hit, scene, sampler).map_or(glm::vec3(0.0, 0.0, 0.0), |(_, light)| light);
+                color += throughput.component_mul(&direct);
+                // The other half of multiple importance sampling: whatever the scattered ray
+                // hits, weighted against the chance of sampling it as a light.
+                let throughput = throughput.component_mul(&attenuation);
+                let emitted = match scene.world.hit(&scattered, 0.001, f64::MAX) {
+                    Some(next) => {
+                        let mut emitted = next.material.emitted(&scattered, &next);
+                        if emitted != glm::vec3(0.0, 0.0, 0.0) {
+                            if let Some((light, pmf)) = scene.lights.pmf(&hit.p, &hit.normal, materials::id(&next.material)) {
+                                emitted *= util::power_heuristic(pdf, pmf * light.pdf(&hit.p, &scattered.direction));
+                            }
+                        }
+                        emitted
+                    }
+                    None => scene.background.color(&scattered.direction) * util::power_heuristic(pdf, scene.background.pdf(&scattered.direction)),
+                };
+                color += throughput.component_mul(&emitted);
+                break;
+            }
+            throughput = throughput.component_mul(&attenuation);
+            ray = scattered;
+        }
+        color
+    }
+}
+
+pub enum DebugView {
+    Normals,
+    Depth,
+    Uv,
+    MaterialId,
+    HitMiss,
+}
+
+pub struct DebugIntegrator {
+    view: DebugView,
+    // Materials numbered in the order the scene was built, so they get the same colors every run.
+    material_numbers: OnceLock<HashMap<usize, usize>>,
+}
+
+impl DebugIntegrator {
+    pub fn new(view: DebugView) -> Self {
+        DebugIntegrator { view, material_numbers: OnceLock::new() }
+    }
+
+    fn material_number(&self, scene: &Scene, material: &Arc<dyn materials::Material>) -> usize {
+        let numbers = self.material_numbers.get_or_init(|| {
+            let mut numbers = HashMap::new();
+            for material in scene.world.materials() {
+                let next = numbers.len();
+                numbers.entry(materials::id(&material)).or_insert(next);
+            }
+            numbers
+        });
+        numbers.get(&materials::id(material)).copied().unwrap_or(numbers.len())
+    }
+}
+
+// Distance at which the depth view fades to white.
+const DEPTH_RANGE: f64 = 30.0;
+
+impl Integrator for DebugIntegrator {
+    fn name(&self) -> &'static str {
+        match self.view {
+            DebugView::Normals => "normals",
+            DebugView::Depth => "depth",
+            DebugView::Uv => "uv",
+            DebugView::MaterialId => "material",
+            DebugView::HitMiss => "hit",
+        }
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, _camera: &Camera, _sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        let hit = match scene.world.hit(r, 0.001, f64::MAX) {
+            Some(hit) => hit,
+            None => return glm::vec3(0.0, 0.0, 0.0),
+        };
+        // Colors go through the same sRGB encoding as rendered images, so decode them to show them
+        // as is with the clamp tone mapper, in whatever space is rendered in.
+        let color = match self.view {
+            DebugView::Normals => (hit.normal + glm::vec3(1.0, 1.0, 1.0)) * 0.5,
+            DebugView::Depth => {
+                let depth = (hit.t * r.direction.magnitude() / DEPTH_RANGE).min(1.0);
+                glm::vec3(depth, depth, depth)
+            }
+            DebugView::Uv => glm::vec3(hit.u, hit.v, 0.0),
+            DebugView::MaterialId => id_color(self.material_number(scene, &hit.material)),
+            DebugView::HitMiss => glm::vec3(1.0, 1.0, 1.0),
+        };
+        colorspace::from_srgb(&color.map(tonemap::srgb_decode))
+    }
+}
+
+fn id_color(id: usize) -> glm::TVec3<f64> {
+    let mut hash = id as u64;
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    glm::vec3(
+        (hash & 0xff) as f64 / 255.0,
+        ((hash >> 8) & 0xff) as f64 / 255.0,
+        ((hash >> 16) & 0xff) as f64 / 255.0,
+    )
+}
@@ -0,0 +1,214 @@
+use crate::distribution::Distribution1D;
+use crate::lights::{Light, LightBounds};
+use nalgebra_glm as glm;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+enum Node {
+    Interior { bounds: LightBounds, children: [usize; 2] },
+    Leaf { bounds: LightBounds, light: usize },
+}
+
+impl Node {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            Node::Interior { bounds, .. } | Node::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+// Picks lights with a probability proportional to an estimate of how much they contribute at
+// a shading point, by walking down a hierarchy of light bounds.
+#[derive(Default)]
+pub struct LightBvh {
+    lights: Vec<Arc<dyn Light>>,
+    nodes: Vec<Node>,
+    // For every light the branches taken from the root to reach it, one bit per level.
+    trails: Vec<u64>,
+    by_material: HashMap<usize, usize>,
+    // Picks lights by their power alone, for algorithms that start paths at the lights.
+    power: Option<Distribution1D>,
+}
+
+impl LightBvh {
+    pub fn new(lights: Vec<Arc<dyn Light>>) -> Self {
+        let mut bvh = LightBvh {
+            trails: vec![0; lights.len()],
+            by_material: lights
+                .iter()
+                .enumerate()
+                .filter_map(|(i, light)| light.material_id().map(|id| (id, i)))
+                .collect(),
+            ..Default::default()
+        };
+        let mut entries: Vec<(usize, LightBounds)> = lights
+            .iter()
+            .enumerate()
+            .map(|(i, light)| (i, light.bounds()))
+            .filter(|(_, bounds)| bounds.phi > 0.0)
+            .collect();
+        if !lights.is_empty() {
+            bvh.power = Some(Distribution1D::new(lights.iter().map(|light| light.bounds().phi).collect()));
+        }
+        bvh.lights = lights;
+        if !entries.is_empty() {
+            bvh.build(&mut entries, 0, 0);
+        }
+        bvh
+    }
+
+    fn build(&mut self, entries: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        if entries.len() == 1 || depth == 63 {
+            let (light, bounds) = entries[0];
+            self.trails[light] = trail;
+            self.nodes.push(Node::Leaf { bounds, light });
+            return self.nodes.len() - 1;
+        }
+
+        // Split at the median centroid along the axis where the lights are spread the most.
+        let (min, max) = entries.iter().fold(
+            (glm::vec3(f64::MAX, f64::MAX, f64::MAX), glm::vec3(f64::MIN, f64::MIN, f64::MIN)),
+            |(min, max), (_, bounds)| (glm::min2(&min, &bounds.centroid()), glm::max2(&max, &bounds.centroid())),
+        );
+        let extent = max - min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        entries.sort_by(|a, b| a.1.centroid()[axis].total_cmp(&b.1.centroid()[axis]));
+        let middle = entries.len() / 2;
+
+        let index = self.nodes.len();
+        self.nodes.push(Node::Interior {
+            bounds: entries[0].1,
+            children: [0, 0],
+        });
+        let (left, right) = entries.split_at_mut(middle);
+        let first = self.build(left, trail, depth + 1);
+        let second = self.build(right, trail | (1 << depth), depth + 1);
+        let bounds = self.nodes[first].bounds().union(self.nodes[second].bounds());
+        self.nodes[index] = Node::Interior { bounds, children: [first, second] };
+        index
+    }
+
+    // Picks a light for shading point `p` with normal `n`, returning its index with its probability.
+    pub fn sample(&self, p: &glm::TVec3<f64>, n: &glm::TVec3<f64>, u: f64) -> Option<(usize, f64)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut u = u;
+        let mut pmf = 1.0;
+        let mut index = 0;
+        loop {
+            match &self.nodes[index] {
+                Node::Interior { children, .. } => {
+                    let first = self.nodes[children[0]].bounds().importance(p, n);
+                    let second = self.nodes[children[1]].bounds().importance(p, n);
+                    if first == 0.0 && second == 0.0 {
+                        return None;
+                    }
+                    let p_first = first / (first + second);
+                    if u < p_first {
+                        u = (u / p_first).min(1.0 - f64::EPSILON);
+                        pmf *= p_first;
+                        index = children[0];
+                    } else {
+                        u = ((u - p_first) / (1.0 - p_first)).min(1.0 - f64::EPSILON);
+                        pmf *= 1.0 - p_first;
+                        index = children[1];
+                    }
+                }
+                Node::Leaf { bounds, light } => {
+                    if bounds.importance(p, n) > 0.0 {
+                        return Some((*light, pmf));
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    // Probability of `sample` picking the light whose geometry uses `material_id`.
+    pub fn pmf(&self, p: &glm::TVec3<f64>, n: &glm::TVec3<f64>, material_id: usize) -> Option<(&Arc<dyn Light>, f64)> {
+        let light = *self.by_material.get(&material_id)?;
+        let mut trail = self.trails[light];
+        let mut pmf = 1.0;
+        let mut index = 0;
+        while let Some(Node::Interior { children, .. }) = self.nodes.get(index) {
+            let first = self.nodes[children[0]].bounds().importance(p, n);
+            let second = self.nodes[children[1]].bounds().importance(p, n);
+            if first + second == 0.0 {
+                return Some((&self.lights[light], 0.0));
+            }
+            let child = (trail & 1) as usize;
+            pmf *= [first, second][child] / (first + second);
+            index = children[child];
+            trail >>= 1;
+        }
+        if self.nodes[index].bounds().importance(p, n) == 0.0 {
+            pmf = 0.0;
+        }
+        Some((&self.lights[light], pmf))
+    }
+
+    pub fn lights(&self) -> &[Arc<dyn Light>] {
+        &self.lights
+    }
+
+    pub fn index_of(&self, material_id: usize) -> Option<usize> {
+        self.by_material.get(&material_id).copied()
+    }
+
+    pub fn sample_by_power(&self, u: f64) -> Option<(usize, f64)> {
+        self.power.as_ref().map(|power| power.sample_discrete(u))
+    }
+
+    pub fn power_pmf(&self, index: usize) -> f64 {
+        self.power.as_ref().map_or(0.0, |power| power.discrete_pdf(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lights::SphereLight;
+
+    #[test]
+    fn pmf_matches_how_often_sample_picks_each_light() {
+        let lights: Vec<Arc<dyn Light>> = vec![
+            Arc::new(SphereLight::new(glm::vec3(0.0, 1.0, 0.0), 0.5, glm::vec3(4.0, 4.0, 4.0))),
+            Arc::new(SphereLight::new(glm::vec3(3.0, 0.5, -2.0), 0.2, glm::vec3(10.0, 2.0, 1.0))),
+            Arc::new(SphereLight::new(glm::vec3(-4.0, 2.0, 1.0), 1.0, glm::vec3(1.0, 1.0, 1.0))),
+            Arc::new(SphereLight::new(glm::vec3(1.0, 0.2, 5.0), 0.1, glm::vec3(20.0, 20.0, 20.0))),
+            Arc::new(SphereLight::new(glm::vec3(-2.0, 3.0, -3.0), 0.3, glm::vec3(2.0, 5.0, 2.0))),
+        ];
+        let ids: Vec<usize> = lights.iter().map(|light| light.material_id().unwrap()).collect();
+        let bvh = LightBvh::new(lights);
+
+        let points = [
+            (glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 0.0)),
+            (glm::vec3(2.0, 0.0, -1.0), glm::vec3(0.0, 1.0, 0.0)),
+            (glm::vec3(-3.0, 1.0, 2.0), glm::vec3(1.0, 0.0, 0.0)),
+            (glm::vec3(0.5, 4.0, 0.5), glm::vec3(0.0, -1.0, 0.0)),
+        ];
+        const STEPS: usize = 100000;
+        for (p, n) in points.iter() {
+            let pmfs: Vec<f64> = ids.iter().map(|&id| bvh.pmf(p, n, id).unwrap().1).collect();
+            assert!((pmfs.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+
+            let mut counts = vec![0; ids.len()];
+            for step in 0..STEPS {
+                let (index, pmf) = bvh.sample(p, n, (step as f64 + 0.5) / STEPS as f64).unwrap();
+                let i = ids.iter().position(|&id| Some(id) == bvh.lights()[index].material_id()).unwrap();
+                assert!((pmf - pmfs[i]).abs() < 1e-9);
+                counts[i] += 1;
+            }
+            for (count, pmf) in counts.iter().zip(pmfs.iter()) {
+                assert!((*count as f64 / STEPS as f64 - pmf).abs() < 1e-3);
+            }
+        }
+    }
+}
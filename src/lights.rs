@@ -0,0 +1,431 @@
+use crate::color;
+use crate::ies::IesProfile;
+use crate::materials;
+use crate::ray;
+use crate::sphere;
+use crate::util;
+use nalgebra_glm as glm;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub struct LightSample {
+    pub direction: glm::TVec3<f64>,
+    pub radiance: glm::TVec3<f64>,
+    pub pdf: f64,
+    pub distance: f64,
+    // Surface normal at the sampled point, zero for lights without a surface.
+    pub normal: glm::TVec3<f64>,
+}
+
+// A ray leaving the light, for algorithms that trace paths starting at the lights.
+pub struct EmissionSample {
+    pub ray: ray::Ray,
+    pub normal: glm::TVec3<f64>,
+    pub radiance: glm::TVec3<f64>,
+    pub pdf_position: f64,
+    pub pdf_direction: f64,
+}
+
+pub trait Light: Send + Sync {
+    fn bounds(&self) -> LightBounds;
+
+    fn sample(&self, p: &glm::TVec3<f64>, u: glm::TVec2<f64>) -> Option<LightSample>;
+
+    // Solid angle pdf of `sample` picking `direction` from `p`.
+    fn pdf(&self, p: &glm::TVec3<f64>, direction: &glm::TVec3<f64>) -> f64;
+
+    // Identifies the material of the geometry representing this light, so rays that hit it
+    // can be traced back to the light. Lights without geometry can't be hit.
+    fn material_id(&self) -> Option<usize> {
+        None
+    }
+
+    // Whether the light is a single point, which can't be hit or found by scattering.
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn sample_le(&self, u1: glm::TVec2<f64>, u2: glm::TVec2<f64>) -> Option<EmissionSample>;
+
+    // Area density of `sample_le` picking the origin of `r`, on a surface with normal `normal`,
+    // and solid angle density of it picking its direction.
+    fn pdf_le(&self, r: &ray::Ray, normal: &glm::TVec3<f64>) -> (f64, f64);
+}
+
+// Conservative bounds on the position, power and emission directions of one or more lights,
+// following the light BVH from PBRT v4.
+#[derive(Clone, Copy)]
+pub struct LightBounds {
+    pub min: glm::TVec3<f64>,
+    pub max: glm::TVec3<f64>,
+    pub phi: f64,
+    pub w: glm::TVec3<f64>,
+    pub cos_theta_o: f64,
+    pub cos_theta_e: f64,
+}
+
+impl LightBounds {
+    pub fn centroid(&self) -> glm::TVec3<f64> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.phi == 0.0 {
+            return *other;
+        }
+        if other.phi == 0.0 {
+            return *self;
+        }
+        let (w, cos_theta_o) = cone_union(self.w, self.cos_theta_o, other.w, other.cos_theta_o);
+        LightBounds {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+            phi: self.phi + other.phi,
+            w,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+        }
+    }
+
+    pub fn importance(&self, p: &glm::TVec3<f64>, n: &glm::TVec3<f64>) -> f64 {
+        let pc = self.centroid();
+        let diagonal = (self.max - self.min).magnitude();
+        let d2 = (p - pc).magnitude_squared().max(diagonal * 0.5);
+        if d2 == 0.0 {
+            return self.phi;
+        }
+        let wi = glm::normalize(&(p - pc));
+        let cos_theta_w = glm::dot(&self.w, &wi);
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // Angle subtended by the bounds as seen from p.
+        let radius = diagonal * 0.5;
+        let cos_theta_b = if (p - pc).magnitude_squared() < radius * radius {
+            -1.0
+        } else {
+            safe_sqrt(1.0 - radius * radius / (p - pc).magnitude_squared())
+        };
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+        let mut importance = self.phi * cos_theta_p / d2;
+
+        if glm::dot(n, n) > 0.0 {
+            let cos_theta_i = glm::dot(&wi, n).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        importance.max(0.0)
+    }
+}
+
+pub struct SphereLight {
+    pub centre: glm::TVec3<f64>,
+    pub radius: f64,
+    pub material: Arc<materials::DiffuseLight>,
+}
+
+impl SphereLight {
+    pub fn new(centre: glm::TVec3<f64>, radius: f64, emit: glm::TVec3<f64>) -> Self {
+        SphereLight {
+            centre,
+            radius,
+            material: materials::DiffuseLight::new(emit),
+        }
+    }
+
+    pub fn sphere(&self) -> sphere::Sphere {
+        sphere::Sphere::new(self.centre, self.radius, self.material.clone())
+    }
+
+    fn cos_theta_max(&self, p: &glm::TVec3<f64>) -> Option<f64> {
+        let distance_squared = (self.centre - p).magnitude_squared();
+        if distance_squared <= self.radius * self.radius {
+            return None;
+        }
+        Some((1.0 - self.radius * self.radius / distance_squared).sqrt())
+    }
+}
+
+impl Light for SphereLight {
+    fn bounds(&self) -> LightBounds {
+        let extent = glm::vec3(self.radius, self.radius, self.radius);
+        let area = 4.0 * PI * self.radius * self.radius;
+        LightBounds {
+            min: self.centre - extent,
+            max: self.centre + extent,
+            phi: color::luminance(&self.material.emit) * area * PI,
+            w: glm::vec3(0.0, 0.0, 1.0),
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+        }
+    }
+
+    fn sample(&self, p: &glm::TVec3<f64>, u: glm::TVec2<f64>) -> Option<LightSample> {
+        let cos_theta_max = self.cos_theta_max(p)?;
+        let axis = glm::normalize(&(self.centre - p));
+        let direction = util::sample_cone(&axis, cos_theta_max, u);
+
+        let oc = p - self.centre;
+        let b = glm::dot(&oc, &direction);
+        let c = glm::dot(&oc, &oc) - self.radius * self.radius;
+        let distance = -b - (b * b - c).max(0.0).sqrt();
+        Some(LightSample {
+            direction,
+            radiance: self.material.emit,
+            pdf: 1.0 / (2.0 * PI * (1.0 - cos_theta_max)),
+            distance,
+            normal: (p + direction * distance - self.centre) / self.radius,
+        })
+    }
+
+    fn pdf(&self, p: &glm::TVec3<f64>, direction: &glm::TVec3<f64>) -> f64 {
+        match self.cos_theta_max(p) {
+            Some(cos_theta_max) => {
+                let axis = glm::normalize(&(self.centre - p));
+                if glm::dot(&axis, &glm::normalize(direction)) >= cos_theta_max {
+                    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        }
+    }
+
+    fn material_id(&self) -> Option<usize> {
+        Some(Arc::as_ptr(&self.material) as *const u8 as usize)
+    }
+
+    fn sample_le(&self, u1: glm::TVec2<f64>, u2: glm::TVec2<f64>) -> Option<EmissionSample> {
+        let normal = util::uniform_sphere(u1);
+        let direction = util::cosine_hemisphere(&normal, u2);
+        Some(EmissionSample {
+            ray: ray::Ray::new(self.centre + normal * self.radius, direction),
+            normal,
+            radiance: self.material.emit,
+            pdf_position: 1.0 / (4.0 * PI * self.radius * self.radius),
+            pdf_direction: glm::dot(&normal, &direction).max(0.0) / PI,
+        })
+    }
+
+    fn pdf_le(&self, r: &ray::Ray, normal: &glm::TVec3<f64>) -> (f64, f64) {
+        let cosine = glm::dot(normal, &glm::normalize(&r.direction));
+        (1.0 / (4.0 * PI * self.radius * self.radius), cosine.max(0.0) / PI)
+    }
+}
+
+// Light emitted from a single point. With a photometric profile the intensity in each direction
+// is scaled by the profile, with the nadir of the fixture pointing along `axis`.
+pub struct PointLight {
+    pub position: glm::TVec3<f64>,
+    pub intensity: glm::TVec3<f64>,
+    pub axis: glm::TVec3<f64>,
+    pub profile: Option<Arc<IesProfile>>,
+}
+
+impl Light for PointLight {
+    fn bounds(&self) -> LightBounds {
+        LightBounds {
+            min: self.position,
+            max: self.position,
+            phi: 4.0 * PI * color::luminance(&self.intensity),
+            w: self.axis,
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+        }
+    }
+
+    fn sample(&self, p: &glm::TVec3<f64>, _u: glm::TVec2<f64>) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance = to_light.magnitude();
+        // Points at the light itself get no direction to it, and would get infinite light.
+        if distance < 1e-9 {
+            return None;
+        }
+        let direction = to_light / distance;
+        let scale = profile_scale(&self.profile, &-direction, &self.axis);
+        Some(LightSample {
+            direction,
+            radiance: self.intensity * scale / (distance * distance),
+            pdf: 1.0,
+            distance,
+            normal: glm::vec3(0.0, 0.0, 0.0),
+        })
+    }
+
+    fn pdf(&self, _p: &glm::TVec3<f64>, _direction: &glm::TVec3<f64>) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn sample_le(&self, u1: glm::TVec2<f64>, _u2: glm::TVec2<f64>) -> Option<EmissionSample> {
+        let direction = util::uniform_sphere(u1);
+        Some(EmissionSample {
+            ray: ray::Ray::new(self.position, direction),
+            normal: glm::vec3(0.0, 0.0, 0.0),
+            radiance: self.intensity * profile_scale(&self.profile, &direction, &self.axis),
+            pdf_position: 1.0,
+            pdf_direction: 1.0 / (4.0 * PI),
+        })
+    }
+
+    fn pdf_le(&self, _r: &ray::Ray, _normal: &glm::TVec3<f64>) -> (f64, f64) {
+        (0.0, 1.0 / (4.0 * PI))
+    }
+}
+
+// A point light restricted to a cone around `direction`, fading out between the falloff start
+// and the total width. A photometric profile further shapes the light within the cone.
+pub struct SpotLight {
+    pub position: glm::TVec3<f64>,
+    pub direction: glm::TVec3<f64>,
+    pub intensity: glm::TVec3<f64>,
+    pub cos_total_width: f64,
+    pub cos_falloff_start: f64,
+    pub profile: Option<Arc<IesProfile>>,
+}
+
+impl SpotLight {
+    fn falloff(&self, w: &glm::TVec3<f64>) -> f64 {
+        let cos_theta = glm::dot(w, &self.direction);
+        if cos_theta < self.cos_total_width {
+            return 0.0;
+        }
+        if cos_theta >= self.cos_falloff_start {
+            return 1.0;
+        }
+        let t = (cos_theta - self.cos_total_width) / (self.cos_falloff_start - self.cos_total_width);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn bounds(&self) -> LightBounds {
+        let phi = color::luminance(&self.intensity) * 4.0 * PI;
+        let cos_theta_e = (self.cos_total_width.acos() - self.cos_falloff_start.acos()).cos();
+        LightBounds {
+            min: self.position,
+            max: self.position,
+            phi,
+            w: self.direction,
+            cos_theta_o: self.cos_falloff_start,
+            cos_theta_e,
+        }
+    }
+
+    fn sample(&self, p: &glm::TVec3<f64>, _u: glm::TVec2<f64>) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance = to_light.magnitude();
+        if distance < 1e-9 {
+            return None;
+        }
+        let direction = to_light / distance;
+        let scale = self.falloff(&-direction) * profile_scale(&self.profile, &-direction, &self.direction);
+        if scale == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            radiance: self.intensity * scale / (distance * distance),
+            pdf: 1.0,
+            distance,
+            normal: glm::vec3(0.0, 0.0, 0.0),
+        })
+    }
+
+    fn pdf(&self, _p: &glm::TVec3<f64>, _direction: &glm::TVec3<f64>) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn sample_le(&self, u1: glm::TVec2<f64>, _u2: glm::TVec2<f64>) -> Option<EmissionSample> {
+        let direction = util::sample_cone(&self.direction, self.cos_total_width, u1);
+        let scale = self.falloff(&direction) * profile_scale(&self.profile, &direction, &self.direction);
+        Some(EmissionSample {
+            ray: ray::Ray::new(self.position, direction),
+            normal: glm::vec3(0.0, 0.0, 0.0),
+            radiance: self.intensity * scale,
+            pdf_position: 1.0,
+            pdf_direction: 1.0 / (2.0 * PI * (1.0 - self.cos_total_width)),
+        })
+    }
+
+    fn pdf_le(&self, r: &ray::Ray, _normal: &glm::TVec3<f64>) -> (f64, f64) {
+        if glm::dot(&glm::normalize(&r.direction), &self.direction) >= self.cos_total_width {
+            (0.0, 1.0 / (2.0 * PI * (1.0 - self.cos_total_width)))
+        } else {
+            (0.0, 0.0)
+        }
+    }
+}
+
+fn profile_scale(profile: &Option<Arc<IesProfile>>, w: &glm::TVec3<f64>, axis: &glm::TVec3<f64>) -> f64 {
+    match profile {
+        Some(profile) => {
+            let (reference, _) = util::orthonormal_basis(axis);
+            profile.evaluate(w, axis, &reference)
+        }
+        None => 1.0,
+    }
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+// cos(max(0, a - b)) given the sines and cosines of a and b.
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    cos_a * cos_b + sin_a * sin_b
+}
+
+// sin(max(0, a - b)) given the sines and cosines of a and b.
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    sin_a * cos_b - cos_a * sin_b
+}
+
+fn cone_union(
+    w_a: glm::TVec3<f64>,
+    cos_a: f64,
+    w_b: glm::TVec3<f64>,
+    cos_b: f64,
+) -> (glm::TVec3<f64>, f64) {
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = glm::dot(&w_a, &w_b).clamp(-1.0, 1.0).acos();
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (w_a, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (w_b, cos_b);
+    }
+    let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+    if theta_o >= PI {
+        return (w_a, -1.0);
+    }
+    let theta_r = theta_o - theta_a;
+    let axis = glm::cross(&w_a, &w_b);
+    if axis.magnitude_squared() == 0.0 {
+        return (w_a, -1.0);
+    }
+    let w = glm::rotate_vec3(&w_a, theta_r, &glm::normalize(&axis));
+    (w, theta_o.cos())
+}
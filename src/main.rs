@@ -1,5 +1,3 @@
-
-use find_folder;
 use nalgebra_glm as glm;
 use piston_window::{Event::*, AdvancedWindow};
 use piston_window::Input::Button;
@@ -7,67 +5,188 @@ use piston_window::{self, Transformed};
 use std::cell::Cell;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::sync::mpsc::channel;
 
-use rand::{self, Rng};
-use std::{f64, thread};
+use rand::rngs::StdRng;
+use rand::{self, Rng, SeedableRng};
+use std::thread;
 
+mod aov;
+mod background;
+mod bdpt;
 mod camera;
 mod color;
+mod colorspace;
+mod denoise;
+mod distribution;
+mod environment;
+mod film;
+mod filter;
 mod fps_counter;
+mod ies;
+mod integrator;
+mod light_bvh;
+mod lights;
 mod materials;
+mod mesh;
+mod mlt;
 mod objects;
+mod options;
+mod output;
+mod path;
+mod photon;
+mod photon_map;
+mod post;
 mod ray;
+mod render;
+mod sampler;
+mod scene;
+mod sky;
+mod spectrum;
 mod sphere;
+mod textures;
+mod tonemap;
 mod util;
 
 const WIDTH: u32 = 1280;
 const HEIGHT: u32 = 720;
 const SAMPLES_PER_PIXEL: u32 = 1;
-const RAY_DEPTH: u8 = 20;
+const PATH_DEPTHS: path::PathDepths = path::PathDepths {
+    diffuse: 8,
+    specular: 16,
+    transmission: 20,
+    volume: 256,
+    roulette_depth: 3,
+};
 const FPS: u128 = 10;
 const FRAME_TIME: u128 = 1000 / FPS;
+// Metropolis bootstraps on the thread that draws the window, so the viewer takes at most this
+// many bootstrap samples to keep the frames coming.
+const VIEWER_BOOTSTRAP_SAMPLES: u32 = 10000;
+// Stops the exposure changes by for each press of - or =.
+const EXPOSURE_STEP: f64 = 0.5;
+// Relative error adaptive sampling aims for when only given a time budget.
+const DEFAULT_TARGET_ERROR: f64 = 0.01;
+
+
+// Index of refraction of subsurface spheres, between that of skin and wax.
+const SUBSURFACE_IOR: f64 = 1.4;
+
+// How the materials of the generated scenes can be varied from the command line.
+struct SceneMaterials {
+    // Color space the colors of the materials are picked in.
+    colors: colorspace::ColorSpace,
+    glass: Option<materials::Dispersion>,
+    film: Option<materials::ThinFilm>,
+    subsurface: Option<glm::TVec3<f64>>,
+    // Index of refraction of a clear coat over the diffuse spheres.
+    coat: Option<f64>,
+    checker_ground: bool,
+    // Normal or bump map over the diffuse spheres.
+    shading: Option<materials::ShadingMap>,
+    // Opacity of the diffuse spheres, cutting holes where it's transparent.
+    cutout: Option<Arc<dyn textures::Texture>>,
+    // Heights the diffuse spheres are displaced by, and how far a height of one moves them.
+    displacement: Option<(Arc<dyn textures::Texture>, f64)>,
+}
+
+impl SceneMaterials {
+    // `color` in the working space.
+    fn color(&self, color: glm::TVec3<f64>) -> glm::TVec3<f64> {
+        self.colors.convert(&color, colorspace::working())
+    }
+
+    fn ground(&self) -> Arc<dyn materials::Material> {
+        if !self.checker_ground {
+            return materials::Lambertian::new(self.color(glm::vec3(0.5,0.5,0.5)));
+        }
+        let light = materials::Lambertian::new(self.color(glm::vec3(0.9, 0.9, 0.9)));
+        let dark = materials::Lambertian::new(self.color(glm::vec3(0.2, 0.3, 0.1)));
+        let weight = textures::Checker::new(
+            textures::SolidColor::new(glm::vec3(1.0, 1.0, 1.0)),
+            textures::SolidColor::new(glm::vec3(0.0, 0.0, 0.0)),
+            2.0,
+        );
+        materials::Mix::textured(light, dark, weight)
+    }
 
+    fn diffuse(&self, albedo: glm::TVec3<f64>) -> Arc<dyn materials::Material> {
+        let albedo = self.color(albedo);
+        let base: Arc<dyn materials::Material> = match self.subsurface {
+            Some(mean_free_path) => materials::Subsurface::new(albedo, mean_free_path, SUBSURFACE_IOR),
+            None => materials::Lambertian::new(albedo),
+        };
+        let base: Arc<dyn materials::Material> = match &self.shading {
+            Some(map) => materials::Shaded::new(base, map.clone()),
+            None => base,
+        };
+        match self.coat {
+            Some(ir) => materials::Coated::new(base, ir),
+            None => base,
+        }
+    }
+
+    fn push_diffuse_sphere(&self, world: &mut objects::HittableList, centre: glm::TVec3<f64>, albedo: glm::TVec3<f64>) {
+        if let Some((height, scale)) = &self.displacement {
+            world.push(mesh::Mesh::sphere(centre, 0.2, self.diffuse(albedo)).displaced(height.as_ref(), *scale));
+            return;
+        }
+        match &self.cutout {
+            Some(opacity) => world.push(sphere::Sphere::cutout(centre, 0.2, self.diffuse(albedo), opacity.clone())),
+            None => world.push(sphere::Sphere::new(centre, 0.2, self.diffuse(albedo))),
+        }
+    }
+
+    fn metal(&self, albedo: glm::TVec3<f64>, fuzz: f64) -> Arc<dyn materials::Material> {
+        let albedo = self.color(albedo);
+        match self.film {
+            Some(film) => materials::Metal::coated(albedo, fuzz, film),
+            None => materials::Metal::new(albedo, fuzz),
+        }
+    }
 
-fn random_scene() -> objects::HittableList {
+    fn glass(&self) -> Arc<dyn materials::Material> {
+        match (self.glass, self.film) {
+            (_, Some(film)) => materials::Dielectric::coated(1.5, self.glass, film),
+            (Some(dispersion), None) => materials::Dielectric::dispersive(dispersion),
+            (None, None) => materials::Dielectric::new(1.5),
+        }
+    }
+}
+
+fn random_scene(looks: &SceneMaterials, rng: &mut StdRng) -> objects::HittableList {
     let mut world = objects::HittableList::default();
-    let ground_material = materials::Lambertian::new(glm::vec3(0.5,0.5,0.5));
     world.push(sphere::Sphere::new(
         glm::vec3(0.0, -1000.0, 0.0),
         1000.0,
-        ground_material
+        looks.ground()
     ));
 
     for a in -2..=2 {
         for b in -2..=2 {
-            let choose_mat = rand::thread_rng().gen::<f64>();
+            let choose_mat = rng.gen::<f64>();
             let centre: glm::TVec3<f64> = glm::vec3(
-                a as f64 + 0.9 * rand::thread_rng().gen::<f64>(),
+                a as f64 + 0.9 * rng.gen::<f64>(),
                 0.2,
-                b as f64 + 0.9 * rand::thread_rng().gen::<f64>(),
+                b as f64 + 0.9 * rng.gen::<f64>(),
             );
 
             if (centre - glm::vec3(4.0, 0.2, 0.0)).magnitude() > 0.9 {
                 if choose_mat < 0.8 {
                     // Diffuse
-                    let albedo: glm::TVec3<f64> = color::random(0.0,1.0);
-                    let sphere_mat = materials::Lambertian::new(albedo);
-                    let sphere = sphere::Sphere::new(centre, 0.2, sphere_mat);
-    
-                    world.push(sphere);
+                    let albedo: glm::TVec3<f64> = color::random(rng, 0.0,1.0);
+                    looks.push_diffuse_sphere(&mut world, centre, albedo);
                 } else if choose_mat < 0.95 {
                     // Metal
-                    let albedo = color::random(0.4,1.0);
-                    let fuzz = rand::thread_rng().gen_range(0.0..0.5);
-                    let sphere_mat = materials::Metal::new(albedo, fuzz);
-                    let sphere = sphere::Sphere::new(centre, 0.2, sphere_mat);
+                    let albedo = color::random(rng, 0.4,1.0);
+                    let fuzz = rng.gen_range(0.0..0.5);
+                    let sphere = sphere::Sphere::new(centre, 0.2, looks.metal(albedo, fuzz));
     
                     world.push(sphere);
                 } else {
                     // Glass
-                    let sphere_mat = materials::Dielectric::new(1.5);
-                    let sphere = sphere::Sphere::new(centre, 0.2, sphere_mat);
+                    let sphere = sphere::Sphere::new(centre, 0.2, looks.glass());
     
                     world.push(sphere);
                 }
@@ -78,33 +197,152 @@ fn random_scene() -> objects::HittableList {
     world
 }
 
-fn main() {
-    let mut frame_buffer: image::RgbaImage =
-        image::RgbaImage::from_pixel(WIDTH, HEIGHT, image::Rgba([0, 0, 0, 255]));
+fn glowing_scene(looks: &SceneMaterials, rng: &mut StdRng) -> (objects::HittableList, Vec<Arc<dyn lights::Light>>) {
+    let mut world = objects::HittableList::default();
+    let mut scene_lights: Vec<Arc<dyn lights::Light>> = Vec::new();
+    world.push(sphere::Sphere::new(
+        glm::vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        looks.ground()
+    ));
 
-    let mut window: piston_window::PistonWindow =
-        piston_window::WindowSettings::new("Raytracer", [WIDTH, HEIGHT])
-            .exit_on_esc(true)
-            .build()
-            .unwrap_or_else(|_e| panic!("Could not create window!"));
+    for a in -11..=11 {
+        for b in -11..=11 {
+            let choose_mat = rng.gen::<f64>();
+            let centre: glm::TVec3<f64> = glm::vec3(
+                a as f64 + 0.9 * rng.gen::<f64>(),
+                0.2,
+                b as f64 + 0.9 * rng.gen::<f64>(),
+            );
 
-    window.set_capture_cursor(true);
-    let mut tex_context = piston_window::TextureContext {
-        factory: window.factory.clone(),
-        encoder: window.factory.create_command_buffer().into(),
-    };
-    let mut tex = piston_window::Texture::from_image(
-        &mut tex_context,
-        &frame_buffer,
-        &piston_window::TextureSettings::new(),
-    )
-    .unwrap();
+            if (centre - glm::vec3(4.0, 0.2, 0.0)).magnitude() > 0.9 {
+                if choose_mat < 0.5 {
+                    // Glowing
+                    let emit = looks.color(color::random(rng, 0.2, 1.0) * 4.0);
+                    let light = lights::SphereLight::new(centre, 0.2, emit);
+                    world.push(light.sphere());
+                    scene_lights.push(Arc::new(light));
+                } else if choose_mat < 0.9 {
+                    // Diffuse
+                    let albedo: glm::TVec3<f64> = color::random(rng, 0.0,1.0);
+                    looks.push_diffuse_sphere(&mut world, centre, albedo);
+                } else {
+                    // Metal
+                    let albedo = color::random(rng, 0.4,1.0);
+                    let fuzz = rng.gen_range(0.0..0.5);
+                    world.push(sphere::Sphere::new(centre, 0.2, looks.metal(albedo, fuzz)));
+                }
+            }
+        }
+    }
 
-    let mut fps_counter = fps_counter::FpsCounter::new();
+    (world, scene_lights)
+}
+
+// Reports a problem with what the renderer was asked to do and exits, rather than panicking.
+fn exit_with(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
 
+fn main() {
+    let options = options::Options::from_args().unwrap_or_else(|e| exit_with(e));
+    let seed = options.seed.unwrap_or_else(|| {
+        let seed = rand::random();
+        eprintln!("Seed: {}", seed);
+        seed
+    });
+    let mut rng = StdRng::seed_from_u64(seed);
+    // Everything is built in the working space, so it has to be known first.
+    colorspace::set_working(colorspace::ColorSpace::by_name(&options.working_space).unwrap());
+    let input_space = colorspace::ColorSpace::by_name(&options.input_space).unwrap();
+    let output_space = colorspace::ColorSpace::by_name(&options.output_space).unwrap();
+    let mut display = tonemap::Display {
+        exposure: options.exposure,
+        tone_mapper: tonemap::ToneMapper::by_name(&options.tone_map).unwrap(),
+        dither: options.dither,
+        effects: options.post.clone(),
+        // The window shows sRGB, while saved images are written in the output space.
+        space: colorspace::ColorSpace::LinearSrgb,
+    };
 
     //* WORLD
-    let world = random_scene();
+    let glass = match options.glass.as_str() {
+        "bk7" => Some(materials::BK7),
+        "flint" => Some(materials::DENSE_FLINT),
+        "diamond" => Some(materials::DIAMOND),
+        _ => None,
+    };
+    let looks = SceneMaterials {
+        colors: input_space,
+        glass,
+        film: options.film_thickness.map(|thickness| materials::ThinFilm { thickness, ior: options.film_ior }),
+        subsurface: options.subsurface.map(|mean_free_path| glm::make_vec3(&mean_free_path)),
+        coat: options.coat,
+        checker_ground: options.checker_ground,
+        shading: match (&options.normal_map, &options.bump_map) {
+            (Some(path), _) => Some(materials::ShadingMap::Normal(
+                textures::Image::load(path).unwrap_or_else(|e| exit_with(format!("Could not load normal map: {}", e))),
+            )),
+            (None, Some(path)) => Some(materials::ShadingMap::Bump {
+                height: textures::Image::load(path).unwrap_or_else(|e| exit_with(format!("Could not load bump map: {}", e))),
+                strength: options.bump_strength,
+            }),
+            (None, None) => None,
+        },
+        cutout: options.cutout.as_ref().map(|path| -> Arc<dyn textures::Texture> {
+            textures::Image::load_opacity(path).unwrap_or_else(|e| exit_with(format!("Could not load cutout: {}", e)))
+        }),
+        displacement: options.displacement.as_ref().map(|path| -> (Arc<dyn textures::Texture>, f64) {
+            let height = textures::Image::load(path).unwrap_or_else(|e| exit_with(format!("Could not load displacement map: {}", e)));
+            (height, options.displacement_scale)
+        }),
+    };
+    let (world, mut scene_lights) = match options.scene.as_str() {
+        "glowing" => glowing_scene(&looks, &mut rng),
+        _ => (random_scene(&looks, &mut rng), Vec::new()),
+    };
+    let background: Box<dyn background::Background> = match &options.environment_map {
+        Some(path) => Box::new(
+            environment::EnvironmentMap::load(
+                path,
+                util::degrees_to_radians(options.environment_rotation),
+                options.environment_intensity,
+                input_space,
+            )
+            .unwrap_or_else(|e| exit_with(format!("Could not load environment map: {}", e))),
+        ),
+        None if options.sky => Box::new(sky::Sky::new(
+            util::degrees_to_radians(options.sun_elevation),
+            util::degrees_to_radians(options.sun_azimuth),
+            options.turbidity,
+            options.ground_albedo,
+            options.sky_intensity,
+        )),
+        None if options.scene == "glowing" => Box::new(background::Solid(glm::vec3(0.0, 0.0, 0.0))),
+        None => Box::new(background::Gradient::new(input_space)),
+    };
+    if let Some(kind) = &options.light {
+        let profile = options.light_profile.as_ref().map(|path| {
+            Arc::new(ies::IesProfile::load(path).unwrap_or_else(|e| exit_with(format!("Could not load {}: {}", path, e))))
+        });
+        let position = glm::make_vec3(&options.light_position);
+        let direction = glm::normalize(&glm::make_vec3(&options.light_direction));
+        let intensity = glm::vec3(1.0, 1.0, 1.0) * options.light_intensity;
+        let light: Arc<dyn lights::Light> = if kind == "spot" {
+            Arc::new(lights::SpotLight {
+                position,
+                direction,
+                intensity,
+                cos_total_width: util::degrees_to_radians(options.spot_angle).cos(),
+                cos_falloff_start: util::degrees_to_radians(options.spot_falloff.min(options.spot_angle)).cos(),
+                profile,
+            })
+        } else {
+            Arc::new(lights::PointLight { position, intensity, axis: direction, profile })
+        };
+        scene_lights.push(light);
+    }
     // let mut world = objects::HittableList::default();
 
     // let material_ground = materials::Lambertian::new(glm::vec3(0.8, 0.8, 0.0));
@@ -155,6 +393,105 @@ fn main() {
         dist_to_focus,
     )));
 
+    let integrator_settings = integrator::Settings {
+        depths: PATH_DEPTHS,
+        photons: options.photons,
+        photon_radius: options.photon_radius,
+        mutations: options.mutations,
+        bootstrap_samples: match options.output {
+            Some(_) => options.bootstrap_samples,
+            None => options.bootstrap_samples.min(VIEWER_BOOTSTRAP_SAMPLES),
+        },
+        large_step_probability: options.large_step_probability,
+        mutation_sigma: options.mutation_sigma,
+        spectral: options.spectral,
+        seed,
+    };
+    let mut integrator = integrator::by_name(&options.integrator, &integrator_settings).unwrap();
+    let mut sampler = sampler::by_name(&options.sampler, options.strata, seed).unwrap();
+    let scene = scene::Scene {
+        world,
+        lights: light_bvh::LightBvh::new(scene_lights),
+        background,
+    };
+
+    if let Some(path) = &options.output {
+        let settings = render::Settings {
+            sampler: &options.sampler,
+            strata: options.strata,
+            seed,
+            samples: options.samples,
+            threads: options.threads,
+            width: WIDTH,
+            height: HEIGHT,
+            filter: &options.filter,
+            filter_radius: options.filter_radius,
+            adaptive: (options.target_error.is_some() || options.time_budget.is_some()).then(|| render::Adaptive {
+                target_error: options.target_error.unwrap_or(DEFAULT_TARGET_ERROR),
+                max_samples: options.max_samples,
+                time_budget: options.time_budget.map(Duration::from_secs_f64),
+            }),
+            aovs: &options.aovs,
+            guides: options.denoise,
+        };
+        if !integrator.splits_light() && options.aovs.iter().any(|name| ["direct", "indirect", "lights"].contains(&name.as_str())) {
+            eprintln!("The {} integrator can't tell where light comes from, so it renders no direct, indirect or light buffers", integrator.name());
+        }
+        let render = render::render(&scene, &camera.lock().unwrap(), integrator.as_mut(), &settings);
+        let mut layers = match &render.guides {
+            Some(guides) => {
+                let denoised = denoise::denoise(&render.image, guides, WIDTH, HEIGHT, options.threads);
+                let mut layers = vec![output::Layer::rgb("beauty", &denoised)];
+                if output::keeps_layers(path) {
+                    layers.push(output::Layer::rgb("noisy", &render.image));
+                }
+                layers
+            }
+            None => vec![output::Layer::rgb("beauty", &render.image)],
+        };
+        if output::keeps_layers(path) {
+            layers.push(output::Layer::gray("samples", &render.samples.iter().map(|&samples| samples as f64).collect::<Vec<_>>()));
+            layers.push(output::Layer::gray("variance", &render.variance));
+        }
+        layers.extend(render.aovs);
+        let display = tonemap::Display { space: output_space, ..display };
+        output::save(path, WIDTH, HEIGHT, &layers, &display).unwrap_or_else(|e| exit_with(e));
+        if let Some(path) = &options.heatmap {
+            // Samples go up in doublings, so they are shown on a log scale from the fewest to the most.
+            let range = (options.max_samples as f64 / options.samples as f64).ln().max(f64::EPSILON);
+            let mut heatmap = image::RgbaImage::new(WIDTH, HEIGHT);
+            for (pixel, &samples) in heatmap.pixels_mut().zip(render.samples.iter()) {
+                *pixel = color::heat((samples as f64 / options.samples as f64).ln() / range);
+            }
+            heatmap.save(path).unwrap_or_else(|e| exit_with(format!("Could not save {}: {}", path, e)));
+        }
+        return;
+    }
+
+    let mut frame_buffer: image::RgbaImage =
+        image::RgbaImage::from_pixel(WIDTH, HEIGHT, image::Rgba([0, 0, 0, 255]));
+
+    let mut window: piston_window::PistonWindow =
+        piston_window::WindowSettings::new("Raytracer", [WIDTH, HEIGHT])
+            .exit_on_esc(true)
+            .build()
+            .unwrap_or_else(|_e| panic!("Could not create window!"));
+
+    window.set_capture_cursor(true);
+    let mut tex_context = piston_window::TextureContext {
+        factory: window.factory.clone(),
+        encoder: window.factory.create_command_buffer().into(),
+    };
+    let mut tex = piston_window::Texture::from_image(
+        &mut tex_context,
+        &frame_buffer,
+        &piston_window::TextureSettings::new(),
+    )
+    .unwrap();
+
+    let mut fps_counter = fps_counter::FpsCounter::new();
+
+
     //* TEXT
     let assets = find_folder::Search::ParentsThenKids(3, 3)
         .for_folder("assets")
@@ -167,6 +504,21 @@ fn main() {
     let mut reset_frame_count: bool = false;
 
     let mut frame_counts: Vec<i32> = vec![0; (WIDTH * HEIGHT) as usize];
+    // The image accumulated while rendering progressively, and the light splatted onto it by
+    // integrators that trace paths from the lights, together with the number of samples that was
+    // gathered over since the view last changed.
+    let mut film = film::Film::new(WIDTH, HEIGHT, filter::by_name(&options.filter, options.filter_radius).unwrap());
+    let mut light_samples: u64 = 0;
+    // While denoising, what each pixel shows before it is denoised, and the surfaces seen through
+    // the pixels the last time they were sampled, which guide the denoiser.
+    let mut denoising = options.denoise;
+    let mut shown = vec![glm::vec3(0.0, 0.0, 0.0); (WIDTH * HEIGHT) as usize];
+    let mut guides = denoise::Guides::new((WIDTH * HEIGHT) as usize);
+    // Frames accumulated into the image since progressive rendering started.
+    let mut passes: u64 = 0;
+    // Frames drawn so far, each picking its pixels from a random stream of its own.
+    let mut frames: u64 = 0;
+    let mut last_pose = (glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 0.0));
     let cam: Arc<Mutex<camera::Camera>> = Arc::clone(&camera);
     let (sender, receiver) = channel();
     thread::spawn(move || {
@@ -174,14 +526,14 @@ fn main() {
         loop {
             let mouse_speed = receiver.try_recv().unwrap_or([0.0,0.0]);
             let mut camera = cam.lock().unwrap();
-            for i  in 0..speed.len() {
-                if speed[i].abs() < 0.05 {
-                    speed[i] = 0.0;
+            for s in speed.iter_mut() {
+                if s.abs() < 0.05 {
+                    *s = 0.0;
                 }
-                if speed[i] > 0.0 {
-                    speed[i] -= 0.1;
-                } else if speed[i] < 0.0 {
-                    speed[i] += 0.1;
+                if *s > 0.0 {
+                    *s -= 0.1;
+                } else if *s < 0.0 {
+                    *s += 0.1;
                 }
             }
             // check for wasd to add or remove speed; make sure to make origin arc mutex
@@ -213,127 +565,221 @@ fn main() {
 
     while let Some(e) = window.next() {
         match e {
-            Input(input, _) => {
-                if !render_reset_flag.get() {
-                    match input {
-                        Button(button_args) => {
-                            if let piston_window::Button::Keyboard(key) = button_args.button {
-                                let camera = Arc::clone(&camera);
-                                let mut camera = camera.lock().unwrap();
-                                match button_args.state {
-                                    piston_window::ButtonState::Press => {
-                                        match key {
-                                            piston_window::Key::W => {
-                                                camera.wasd[0] = true;
-                                            }
-                                            piston_window::Key::A => {
-                                                camera.wasd[1] = true;
-                                            }
-                                            piston_window::Key::S => {
-                                                camera.wasd[2] = true;
-                                            }
-                                            piston_window::Key::D => {
-                                                camera.wasd[3] = true;
-                                            }
-                                            piston_window::Key::Space => {
-                                                camera.wasd[4] = true;
-                                            }
-                                            piston_window::Key::LCtrl => {
-                                                camera.wasd[5] = true;
-                                            }
-                                            piston_window::Key::P => {
-                                                (*render_reset_flag).set(true);
-                                            }
-                                            _ => (),
-                                        }
-                                    }
-                                    piston_window::ButtonState::Release => {
-                                        match key {
-                                            piston_window::Key::W => {
-                                                camera.wasd[0] = false;
-                                            }
-                                            piston_window::Key::A => {
-                                                camera.wasd[1] = false;
-                                            }
-                                            piston_window::Key::S => {
-                                                camera.wasd[2] = false;
-                                            }
-                                            piston_window::Key::D => {
-                                                camera.wasd[3] = false;
-                                            }
-                                            piston_window::Key::Space => {
-                                                camera.wasd[4] = false;
-                                            }
-                                            piston_window::Key::LCtrl => {
-                                                camera.wasd[5] = false;
-                                            }
-                                            _ => (),
-                                        }
-                                    },
+            // How the image is shown can be changed while it is rendered progressively too.
+            Input(
+                Button(piston_window::ButtonArgs {
+                    button: piston_window::Button::Keyboard(
+                        key @ (piston_window::Key::T
+                        | piston_window::Key::Minus
+                        | piston_window::Key::Equals
+                        | piston_window::Key::N
+                        | piston_window::Key::O),
+                    ),
+                    state: piston_window::ButtonState::Press,
+                    ..
+                }),
+                _,
+            ) => match key {
+                piston_window::Key::T => display.tone_mapper = display.tone_mapper.next(),
+                piston_window::Key::Minus => display.exposure -= EXPOSURE_STEP,
+                piston_window::Key::Equals => display.exposure += EXPOSURE_STEP,
+                piston_window::Key::N => denoising = !denoising,
+                _ => {
+                    let splat_scale = if light_samples > 0 { (WIDTH * HEIGHT) as f64 / light_samples as f64 } else { 0.0 };
+                    let image: Vec<glm::TVec3<f64>> = (0..(WIDTH * HEIGHT) as usize).map(|index| film.pixel(index, splat_scale)).collect();
+                    let display = tonemap::Display { space: output_space, ..display.clone() };
+                    match output::save(&options.snapshot, WIDTH, HEIGHT, &[output::Layer::rgb("beauty", &image)], &display) {
+                        Ok(()) => println!("Saved {}", options.snapshot),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+            },
+            Input(input, _) if !render_reset_flag.get() => match input {
+                Button(piston_window::ButtonArgs { button: piston_window::Button::Keyboard(key), state, .. }) => {
+                    let camera = Arc::clone(&camera);
+                    let mut camera = camera.lock().unwrap();
+                    match state {
+                        piston_window::ButtonState::Press => {
+                            match key {
+                                piston_window::Key::W => {
+                                    camera.wasd[0] = true;
+                                }
+                                piston_window::Key::A => {
+                                    camera.wasd[1] = true;
+                                }
+                                piston_window::Key::S => {
+                                    camera.wasd[2] = true;
+                                }
+                                piston_window::Key::D => {
+                                    camera.wasd[3] = true;
+                                }
+                                piston_window::Key::Space => {
+                                    camera.wasd[4] = true;
                                 }
+                                piston_window::Key::LCtrl => {
+                                    camera.wasd[5] = true;
+                                }
+                                piston_window::Key::P => {
+                                    (*render_reset_flag).set(true);
+                                }
+                                piston_window::Key::I => {
+                                    integrator = integrator::next(integrator.as_ref(), &integrator_settings);
+                                    light_samples = 0;
+                                    passes = 0;
+                                    film.clear();
+                                }
+                                _ => (),
                             }
                         }
-                        piston_window::Input::Move(motion) => {
-                            if let piston_window::Motion::MouseRelative(pos) = motion {
-                                sender.send(pos).expect("Invalid Mouse Position Recorded!");
+                        piston_window::ButtonState::Release => {
+                            match key {
+                                piston_window::Key::W => {
+                                    camera.wasd[0] = false;
+                                }
+                                piston_window::Key::A => {
+                                    camera.wasd[1] = false;
+                                }
+                                piston_window::Key::S => {
+                                    camera.wasd[2] = false;
+                                }
+                                piston_window::Key::D => {
+                                    camera.wasd[3] = false;
+                                }
+                                piston_window::Key::Space => {
+                                    camera.wasd[4] = false;
+                                }
+                                piston_window::Key::LCtrl => {
+                                    camera.wasd[5] = false;
+                                }
+                                _ => (),
                             }
-                        }
-                        _ => ()
+                        },
                     }
                 }
-            }
-            Loop(l) => {
-                if let piston_window::Loop::Render(_ren) = l {
-                    let camera = Arc::clone(&camera);
-                    window.draw_2d(&e, |c, g, device| {
-                        piston_window::clear([1.0; 4], g);
-                        let now = Instant::now();
-                        while now.elapsed().as_millis() <= FRAME_TIME {
-                            let x = rand::thread_rng().gen_range(0..WIDTH);
-                            let y = rand::thread_rng().gen_range(0..HEIGHT);
-                            let pixel = frame_buffer.get_pixel_mut(x, y);
-                            let mut pixel_color = glm::vec3(0.0, 0.0, 0.0);
-
-                            for _i in 0..SAMPLES_PER_PIXEL {
-                                let screen_coords = glm::vec2(
-                                    (x as f64 + rand::random::<f64>()) / WIDTH as f64,
-                                    1. - ((y as f64 + rand::random::<f64>()) / HEIGHT as f64),
-                                );
-                                let ray: ray::Ray = camera.lock().unwrap().get_ray(screen_coords);
-                                pixel_color += ray::ray_color(&ray, &world, RAY_DEPTH);
+                piston_window::Input::Move(piston_window::Motion::MouseRelative(pos)) => {
+                    sender.send(pos).expect("Invalid Mouse Position Recorded!");
+                }
+                _ => ()
+            },
+            Loop(piston_window::Loop::Render(_ren)) => {
+                let camera = Arc::clone(&camera);
+                window.draw_2d(&e, |c, g, device| {
+                    piston_window::clear([1.0; 4], g);
+                    let pose = {
+                        let camera = camera.lock().unwrap();
+                        (camera.position(), camera.forward())
+                    };
+                    if pose != last_pose {
+                        light_samples = 0;
+                        film.clear();
+                        last_pose = pose;
+                        passes = 0;
+                    }
+                    let progressive = render_reset_flag.get();
+                    if progressive && !reset_frame_count {
+                        frame_counts.fill(0);
+                        film.clear();
+                        light_samples = 0;
+                        reset_frame_count = true;
+                    }
+                    // Denoising and post effects need the whole image, so it is redrawn every frame.
+                    let full_frame = denoising || !display.effects.is_empty();
+                    let frame_camera = camera.lock().unwrap().clone();
+                    integrator.preprocess(&scene, &frame_camera, passes);
+                    let splat_scale = if light_samples > 0 { (WIDTH * HEIGHT) as f64 / light_samples as f64 } else { 0.0 };
+                    let mut frame_samples: u64 = 0;
+                    let now = Instant::now();
+                    while now.elapsed().as_millis() <= FRAME_TIME {
+                        let bits = sampler::hash(&[seed, frames, frame_samples]);
+                        let x = (bits % WIDTH as u64) as u32;
+                        let y = ((bits >> 32) % HEIGHT as u64) as u32;
+                        let index = (x + y * WIDTH) as usize;
+                        let mut pixel_color = glm::vec3(0.0, 0.0, 0.0);
+
+                        let frame_count = frame_counts[index] as u64;
+                        for i in 0..SAMPLES_PER_PIXEL {
+                            let (position, color) = render::sample_pixel(
+                                &scene,
+                                integrator.as_ref(),
+                                &frame_camera,
+                                sampler.as_mut(),
+                                (x, y),
+                                frame_count * SAMPLES_PER_PIXEL as u64 + i as u64,
+                                (WIDTH, HEIGHT),
+                                None,
+                            );
+                            if progressive {
+                                film.add_sample(&position, &color);
                             }
-                            if render_reset_flag.get() && !reset_frame_count {
-                                for i in 0..frame_counts.len() {
-                                    frame_counts[i] = 0;
-                                }
-                                reset_frame_count = true;
+                            pixel_color += color;
+                        }
+                        frame_samples += SAMPLES_PER_PIXEL as u64;
+                        if denoising {
+                            guides.trace(&scene, &frame_camera, (x, y), (WIDTH, HEIGHT));
+                        }
+                        // While the view moves, pixels show just their latest samples.
+                        if !progressive {
+                            shown[index] = pixel_color / SAMPLES_PER_PIXEL as f64 + film.pixel(index, splat_scale);
+                            if !full_frame {
+                                *frame_buffer.get_pixel_mut(x, y) = display.pixel(&shown[index], x, y);
+                            }
+                        }
+                        frame_counts[index] += 1;
+                    }
+                    light_samples += frame_samples;
+                    frames += 1;
+                    for (screen_coords, splat) in integrator.take_splats() {
+                        film.add_splat(&screen_coords, &splat);
+                    }
+                    if progressive {
+                        passes += 1;
+                        let splat_scale = (WIDTH * HEIGHT) as f64 / light_samples as f64;
+                        for (index, shown) in shown.iter_mut().enumerate() {
+                            if film.has_samples(index) {
+                                *shown = film.pixel(index, splat_scale);
                             }
-                            *pixel = color::write_pixel(
-                                pixel_color,
-                                *pixel,
-                                SAMPLES_PER_PIXEL,
-                                frame_counts[(x + y * WIDTH) as usize],
-                                render_reset_flag.get()
-                            );
-                            frame_counts[(x + y * WIDTH) as usize] += 1;
                         }
-                        tex.update(&mut tex_context, &frame_buffer).unwrap();
-                        piston_window::image(&tex, c.transform, g);
-                        tex_context.encoder.flush(device);
-
-                        //* FPS Counter
-                        if !render_reset_flag.get() {
-                            let fps = fps_counter.tick();
-                            let fps = ((fps * 10.0).round() / 10.0).to_string();
-                            let transform = c.transform.trans(10.0, 30.0);
-                            piston_window::text::Text::new(32)
-                                .draw(&fps, &mut glyphs, &c.draw_state, transform, g)
-                                .unwrap();
-                            glyphs.factory.encoder.flush(device);
+                    }
+                    if full_frame {
+                        let denoised;
+                        let colors = if denoising {
+                            denoised = denoise::denoise(&shown, &guides, WIDTH, HEIGHT, options.threads);
+                            &denoised
+                        } else {
+                            &shown
+                        };
+                        frame_buffer = display.image(colors, WIDTH, HEIGHT);
+                    } else if progressive {
+                        for (x, y, pixel) in frame_buffer.enumerate_pixels_mut() {
+                            let index = (x + y * WIDTH) as usize;
+                            if film.has_samples(index) {
+                                *pixel = display.pixel(&shown[index], x, y);
+                            }
                         }
-                        
-                    });
-                }
+                    }
+                    tex.update(&mut tex_context, &frame_buffer).unwrap();
+                    piston_window::image(&tex, c.transform, g);
+                    tex_context.encoder.flush(device);
+
+                    //* FPS Counter
+                    if !render_reset_flag.get() {
+                        let fps = fps_counter.tick();
+                        let fps = format!(
+                            "{} {} {} {:+}EV{}",
+                            (fps * 10.0).round() / 10.0,
+                            integrator.name(),
+                            display.tone_mapper.name(),
+                            display.exposure,
+                            if denoising { " denoised" } else { "" },
+                        );
+                        let transform = c.transform.trans(10.0, 30.0);
+                        piston_window::text::Text::new(32)
+                            .draw(&fps, &mut glyphs, &c.draw_state, transform, g)
+                            .unwrap();
+                        glyphs.factory.encoder.flush(device);
+                    }
+                    
+                });
             }
             _ => (),
         }
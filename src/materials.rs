@@ -1,12 +1,67 @@
 
 use crate::ray;
+use crate::sampler::Sampler;
+use crate::spectrum;
+use crate::textures::{SolidColor, Texture};
 use crate::objects;
 use crate::util;
 use nalgebra_glm as glm;
 use std::sync::Arc;
 
-pub trait Material {
-    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> Option<(ray::Ray, glm::TVec3<f64>)>;
+pub trait Material: Send + Sync {
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)>;
+
+    // Pdf of scattering into `scattered`, for materials whose scatter direction is random
+    // enough to be combined with light sampling. Mirrors and glass leave this at zero.
+    fn scattering_pdf(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord, _scattered: &ray::Ray) -> f64 {
+        0.0
+    }
+
+    // The BSDF times the cosine towards `scattered`, for materials with a scattering pdf.
+    fn eval(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord, _scattered: &ray::Ray) -> glm::TVec3<f64> {
+        glm::vec3(0.0, 0.0, 0.0)
+    }
+
+    fn emitted(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        glm::vec3(0.0, 0.0, 0.0)
+    }
+
+    // The color of the surface, how much of the light hitting it it gives back at most, for
+    // auxiliary buffers and denoisers. Lights give back nothing.
+    fn albedo(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        glm::vec3(0.0, 0.0, 0.0)
+    }
+
+    // The normal the material shades `hit` with, which maps can bend away from the surface's.
+    fn shading_normal(&self, _r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        hit.normal
+    }
+
+    // Whether the material only reflects or refracts light, so it can focus it into caustics.
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    // The medium filling the object, for integrators that follow light inside it. Others only
+    // see the surface as `scatter` describes it.
+    fn medium(&self) -> Option<Medium> {
+        None
+    }
+}
+
+// A homogeneous medium behind a smooth boundary with index of refraction `ir`, scattering light
+// equally in all directions. `sigma_t` is the chance per unit length of light hitting a particle
+// in each channel, of which the fraction `albedo` is scattered and the rest absorbed.
+#[derive(Debug, Clone, Copy)]
+pub struct Medium {
+    pub sigma_t: glm::TVec3<f64>,
+    pub albedo: glm::TVec3<f64>,
+    pub ir: f64,
+}
+
+// Identifies a material by the allocation it lives in, shared by every object using it.
+pub fn id(material: &Arc<dyn Material>) -> usize {
+    Arc::as_ptr(material) as *const u8 as usize
 }
 
 pub struct Lambertian {
@@ -20,71 +75,577 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _r_in: &ray::Ray, hit: &objects::HitRecord) -> Option<(ray::Ray, glm::TVec3<f64>)> {
-        let mut scatter_direction: glm::TVec3<f64> = hit.normal + util::random_unit_vector();
+    fn scatter(&self, _r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        let mut scatter_direction: glm::TVec3<f64> = hit.normal + util::uniform_sphere(sampler.get_2d());
         if util::near_zero(&scatter_direction) {
             scatter_direction = hit.normal;
         }
         let scattered = ray::Ray::new(hit.p, scatter_direction);
         Some((scattered, self.albedo))
     }
+
+    fn scattering_pdf(&self, _r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> f64 {
+        let cosine = glm::dot(&hit.normal, &glm::normalize(&scattered.direction));
+        cosine.max(0.0) / std::f64::consts::PI
+    }
+
+    fn eval(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> glm::TVec3<f64> {
+        self.albedo * self.scattering_pdf(r_in, hit, scattered)
+    }
+
+    fn albedo(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.albedo
+    }
+}
+
+// Wavelengths in nanometres standing in for the red, green and blue channels when a thin film is
+// rendered in RGB.
+const CHANNEL_WAVELENGTHS: [f64; 3] = [630.0, 532.0, 465.0];
+
+// A transparent coating a few hundred nanometres thick, like a soap film or the anti-reflection
+// coating on a lens. Light reflected off its top and bottom interferes, so how much is reflected
+// depends on the wavelength and the angle, giving iridescent colors.
+#[derive(Debug, Clone, Copy)]
+pub struct ThinFilm {
+    // In nanometres.
+    pub thickness: f64,
+    pub ior: f64,
+}
+
+impl ThinFilm {
+    // Reflectance of the film between media with indices `n1`, the side light arrives from, and
+    // `n3`, using the Airy summation of the reflections inside it.
+    pub fn reflectance(&self, n1: f64, n3: f64, cos_theta: f64, wavelength: f64) -> f64 {
+        let n2 = self.ior;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let sin_film = n1 / n2 * sin_theta;
+        let sin_substrate = n1 / n3 * sin_theta;
+        if sin_film >= 1.0 || sin_substrate >= 1.0 {
+            return 1.0;
+        }
+        let cos_film = (1.0 - sin_film * sin_film).sqrt();
+        let cos_substrate = (1.0 - sin_substrate * sin_substrate).sqrt();
+        let (top_s, top_p) = fresnel_amplitudes(n1, n2, cos_theta, cos_film);
+        let (bottom_s, bottom_p) = fresnel_amplitudes(n2, n3, cos_film, cos_substrate);
+        let phase = self.phase(cos_film, wavelength);
+        (airy(top_s, bottom_s, phase) + airy(top_p, bottom_p, phase)) / 2.0
+    }
+
+    // Reflectance of the film on a metal reflecting `base` of the light arriving head on. The
+    // metal is taken to flip the phase of what it reflects, with Schlick's falloff towards
+    // grazing angles.
+    pub fn reflectance_on_metal(&self, base: f64, cos_theta: f64, wavelength: f64) -> f64 {
+        let n2 = self.ior;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let sin_film = sin_theta / n2;
+        let cos_film = (1.0 - sin_film * sin_film).sqrt();
+        let (top_s, top_p) = fresnel_amplitudes(1.0, n2, cos_theta, cos_film);
+        let metal = base + (1.0 - base) * (1.0 - cos_film).powi(5);
+        let bottom = -metal.clamp(0.0, 1.0).sqrt();
+        let phase = self.phase(cos_film, wavelength);
+        (airy(top_s, bottom, phase) + airy(top_p, bottom, phase)) / 2.0
+    }
+
+    fn phase(&self, cos_film: f64, wavelength: f64) -> f64 {
+        4.0 * std::f64::consts::PI * self.ior * self.thickness * cos_film / wavelength
+    }
+}
+
+// Amplitude reflection coefficients for s and p polarized light going from index `n1` to `n2`.
+fn fresnel_amplitudes(n1: f64, n2: f64, cos_i: f64, cos_t: f64) -> (f64, f64) {
+    let s = (n1 * cos_i - n2 * cos_t) / (n1 * cos_i + n2 * cos_t);
+    let p = (n2 * cos_i - n1 * cos_t) / (n2 * cos_i + n1 * cos_t);
+    (s, p)
+}
+
+fn airy(top: f64, bottom: f64, phase: f64) -> f64 {
+    let cross = 2.0 * top * bottom * phase.cos();
+    (top * top + bottom * bottom + cross) / (1.0 + top * top * bottom * bottom + cross)
+}
+
+// Evaluates `reflectance` at the wavelength a spectral path carries, or at one per channel.
+fn per_channel(wavelength: Option<f64>, reflectance: impl Fn(usize, f64) -> f64) -> glm::TVec3<f64> {
+    match wavelength {
+        Some(wavelength) => {
+            let value = reflectance(0, wavelength);
+            glm::vec3(value, value, value)
+        }
+        None => glm::vec3(
+            reflectance(0, CHANNEL_WAVELENGTHS[0]),
+            reflectance(1, CHANNEL_WAVELENGTHS[1]),
+            reflectance(2, CHANNEL_WAVELENGTHS[2]),
+        ),
+    }
 }
 
 pub struct Metal {
     pub albedo: glm::TVec3<f64>,
     pub fuzz: f64,
+    pub film: Option<ThinFilm>,
 }
 
 #[allow(dead_code)]
 impl Metal {
     pub fn new(albedo: glm::TVec3<f64>, fuzz: f64) -> Arc<Self> {
-        Arc::new(Metal { albedo, fuzz: if fuzz < 1.0 { fuzz } else { 1.0 } })
+        Arc::new(Metal { albedo, fuzz: if fuzz < 1.0 { fuzz } else { 1.0 }, film: None })
+    }
+
+    pub fn coated(albedo: glm::TVec3<f64>, fuzz: f64, film: ThinFilm) -> Arc<Self> {
+        Arc::new(Metal { albedo, fuzz: if fuzz < 1.0 { fuzz } else { 1.0 }, film: Some(film) })
     }
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> Option<(ray::Ray, glm::TVec3<f64>)> {
-        let reflected = util::reflect(&glm::normalize(&r_in.direction), &hit.normal);
-        let scattered = ray::Ray::new(hit.p, reflected + self.fuzz * util::random_point_in_sphere());
-        if glm::dot(&scattered.direction, &hit.normal) > 0.0 {
-            Some((scattered, self.albedo))
-        } else {
-            None
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        let unit_direction = glm::normalize(&r_in.direction);
+        let reflected = util::reflect(&unit_direction, &hit.normal);
+        let u = sampler.get_2d();
+        let scattered = ray::Ray::new(hit.p, reflected + self.fuzz * util::uniform_ball(glm::vec3(u.x, u.y, sampler.get_1d())));
+        if glm::dot(&scattered.direction, &hit.normal) <= 0.0 {
+            return None;
         }
+        let attenuation = match &self.film {
+            Some(film) => {
+                let cos_theta = (-unit_direction).dot(&hit.normal).clamp(0.0, 1.0);
+                // Spectral paths take the albedo as it is uplifted at their wavelength.
+                per_channel(r_in.wavelength, |channel, wavelength| {
+                    let base = match r_in.wavelength {
+                        Some(wavelength) => spectrum::uplift(&self.albedo, wavelength),
+                        None => self.albedo[channel],
+                    };
+                    film.reflectance_on_metal(base, cos_theta, wavelength)
+                })
+            }
+            None => self.albedo,
+        };
+        Some((scattered, attenuation))
+    }
+
+    fn albedo(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.albedo
     }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+// How the index of refraction of a dispersive medium varies with the wavelength, given in
+// micrometres to both formulas.
+#[derive(Debug, Clone, Copy)]
+pub enum Dispersion {
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
 }
 
+impl Dispersion {
+    pub fn ior(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+}
+
+// Borosilicate crown glass, the common optical glass.
+pub const BK7: Dispersion = Dispersion::Sellmeier {
+    b: [1.03961212, 0.231792344, 1.01046945],
+    c: [0.00600069867, 0.0200179144, 103.560653],
+};
+
+// Dense flint glass, which spreads colors much further than crown glass.
+pub const DENSE_FLINT: Dispersion = Dispersion::Cauchy { a: 1.7280, b: 0.01342 };
+
+pub const DIAMOND: Dispersion = Dispersion::Sellmeier {
+    b: [4.3356, 0.3306, 0.0],
+    c: [0.1060 * 0.1060, 0.1750 * 0.1750, 0.0],
+};
+
+// Wavelength of the sodium D line, where the index of refraction of glass is usually quoted and
+// which stands in for all wavelengths when rendering in RGB.
+const D_LINE: f64 = 589.3;
+
 pub struct Dielectric {
     pub ir: f64,
+    pub dispersion: Option<Dispersion>,
+    pub film: Option<ThinFilm>,
 }
 
 #[allow(dead_code)]
 impl Dielectric {
     pub fn new(ir: f64) -> Arc<Self> {
-        Arc::new(Dielectric { ir })
+        Arc::new(Dielectric { ir, dispersion: None, film: None })
+    }
+
+    pub fn dispersive(dispersion: Dispersion) -> Arc<Self> {
+        Arc::new(Dielectric { ir: dispersion.ior(D_LINE), dispersion: Some(dispersion), film: None })
+    }
+
+    pub fn coated(ir: f64, dispersion: Option<Dispersion>, film: ThinFilm) -> Arc<Self> {
+        let ir = dispersion.map_or(ir, |dispersion| dispersion.ior(D_LINE));
+        Arc::new(Dielectric { ir, dispersion, film: Some(film) })
+    }
+
+    fn ior(&self, wavelength: Option<f64>) -> f64 {
+        match (&self.dispersion, wavelength) {
+            (Some(dispersion), Some(wavelength)) => dispersion.ior(wavelength),
+            _ => self.ir,
+        }
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        let ir = self.ior(r_in.wavelength);
         let refraction_ratio = if hit.front_face {
-            1.0 / self.ir
+            1.0 / ir
         } else {
-            self.ir
+            ir
         };
         let unit_direction = glm::normalize(&r_in.direction);
         let cos_theta = (-unit_direction).dot(&hit.normal).min(1.0);
         let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let dir: glm::TVec3<f64>;
-        if cannot_refract || util::schlick(cos_theta, refraction_ratio) > rand::random() {
-            dir = util::reflect(&unit_direction, &hit.normal);
+        if cannot_refract {
+            let scattered = ray::Ray::new(hit.p, util::reflect(&unit_direction, &hit.normal));
+            return Some((scattered, glm::vec3(1.0, 1.0, 1.0)));
+        }
+        let reflectance = match &self.film {
+            Some(film) => {
+                let (outside, inside) = if hit.front_face { (1.0, ir) } else { (ir, 1.0) };
+                per_channel(r_in.wavelength, |_, wavelength| film.reflectance(outside, inside, cos_theta, wavelength))
+            }
+            None => {
+                let reflectance = util::schlick(cos_theta, refraction_ratio);
+                glm::vec3(reflectance, reflectance, reflectance)
+            }
+        };
+        // Reflect or refract by the average over the channels, weighting colored films to match.
+        let probability = (reflectance.x + reflectance.y + reflectance.z) / 3.0;
+        if probability > sampler.get_1d() {
+            let scattered = ray::Ray::new(hit.p, util::reflect(&unit_direction, &hit.normal));
+            Some((scattered, reflectance / probability))
         } else {
-            dir = util::refract(&unit_direction, &hit.normal, refraction_ratio);
+            let scattered = ray::Ray::new(hit.p, util::refract(&unit_direction, &hit.normal, refraction_ratio));
+            Some((scattered, (glm::vec3(1.0, 1.0, 1.0) - reflectance) / (1.0 - probability)))
         }
-        let scattered = ray::Ray::new(hit.p, dir);
+    }
 
-        Some((scattered, glm::vec3(1.0, 1.0, 1.0)))
+    // Clear glass lets all of the light through one way or the other.
+    fn albedo(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        glm::vec3(1.0, 1.0, 1.0)
     }
-}
\ No newline at end of file
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+// Translucent materials like skin, wax and marble, where light enters the surface and scatters
+// around inside before leaving it somewhere else. The mean free path is how far light travels
+// between scattering events, per channel.
+pub struct Subsurface {
+    pub albedo: glm::TVec3<f64>,
+    pub mean_free_path: glm::TVec3<f64>,
+    pub ir: f64,
+}
+
+impl Subsurface {
+    pub fn new(albedo: glm::TVec3<f64>, mean_free_path: glm::TVec3<f64>, ir: f64) -> Arc<Self> {
+        Arc::new(Subsurface { albedo, mean_free_path, ir })
+    }
+}
+
+// Without following light inside, the surface is diffuse with the color of the medium.
+impl Material for Subsurface {
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        Lambertian { albedo: self.albedo }.scatter(r_in, hit, sampler)
+    }
+
+    fn scattering_pdf(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> f64 {
+        Lambertian { albedo: self.albedo }.scattering_pdf(r_in, hit, scattered)
+    }
+
+    fn eval(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> glm::TVec3<f64> {
+        Lambertian { albedo: self.albedo }.eval(r_in, hit, scattered)
+    }
+
+    fn albedo(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.albedo
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        Some(Medium {
+            sigma_t: self.mean_free_path.map(|length| 1.0 / length),
+            albedo: self.albedo,
+            ir: self.ir,
+        })
+    }
+}
+
+pub struct DiffuseLight {
+    pub emit: glm::TVec3<f64>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: glm::TVec3<f64>) -> Arc<Self> {
+        Arc::new(DiffuseLight { emit })
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _r_in: &ray::Ray, _hit: &objects::HitRecord, _sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        None
+    }
+
+    fn emitted(&self, _r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        if hit.front_face {
+            self.emit
+        } else {
+            glm::vec3(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+// Blends two materials, with `weight` giving how much of `b` to take at each point. Scattering
+// picks one of them in proportion to its weight, while their BSDFs and pdfs are added up weighted
+// the same way, so light sampling sees the blend whichever was picked. A mirror or glass that gets
+// picked scatters as it does on its own, marking the ray as specular.
+pub struct Mix {
+    pub a: Arc<dyn Material>,
+    pub b: Arc<dyn Material>,
+    pub weight: Arc<dyn Texture>,
+}
+
+#[allow(dead_code)]
+impl Mix {
+    pub fn new(a: Arc<dyn Material>, b: Arc<dyn Material>, amount: f64) -> Arc<Self> {
+        Arc::new(Mix { a, b, weight: SolidColor::new(glm::vec3(amount, amount, amount)) })
+    }
+
+    pub fn textured(a: Arc<dyn Material>, b: Arc<dyn Material>, weight: Arc<dyn Texture>) -> Arc<Self> {
+        Arc::new(Mix { a, b, weight })
+    }
+
+    fn weight(&self, hit: &objects::HitRecord) -> f64 {
+        self.weight.value(hit.u, hit.v, &hit.p).mean().clamp(0.0, 1.0)
+    }
+}
+
+impl Material for Mix {
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        let chosen = if sampler.get_1d() < self.weight(hit) { &self.b } else { &self.a };
+        let (scattered, attenuation) = chosen.scatter(r_in, hit, sampler)?;
+        // A direction the picked material can't give a pdf for came from a mirror or glass, which
+        // is marked as such and left as it is. Otherwise it is weighted by the blend.
+        if chosen.scattering_pdf(r_in, hit, &scattered) <= 0.0 {
+            return Some((ray::Ray { specular: true, ..scattered }, attenuation));
+        }
+        let pdf = self.scattering_pdf(r_in, hit, &scattered);
+        let attenuation = self.eval(r_in, hit, &scattered) / pdf;
+        Some((scattered, attenuation))
+    }
+
+    fn scattering_pdf(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> f64 {
+        if scattered.specular {
+            return 0.0;
+        }
+        let weight = self.weight(hit);
+        (1.0 - weight) * self.a.scattering_pdf(r_in, hit, scattered) + weight * self.b.scattering_pdf(r_in, hit, scattered)
+    }
+
+    fn eval(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> glm::TVec3<f64> {
+        if scattered.specular {
+            return glm::vec3(0.0, 0.0, 0.0);
+        }
+        let weight = self.weight(hit);
+        (1.0 - weight) * self.a.eval(r_in, hit, scattered) + weight * self.b.eval(r_in, hit, scattered)
+    }
+
+    fn emitted(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        let weight = self.weight(hit);
+        (1.0 - weight) * self.a.emitted(r_in, hit) + weight * self.b.emitted(r_in, hit)
+    }
+
+    fn albedo(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        let weight = self.weight(hit);
+        (1.0 - weight) * self.a.albedo(r_in, hit) + weight * self.b.albedo(r_in, hit)
+    }
+
+    fn shading_normal(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        let weight = self.weight(hit);
+        let normal = (1.0 - weight) * self.a.shading_normal(r_in, hit) + weight * self.b.shading_normal(r_in, hit);
+        if normal.magnitude_squared() > 0.0 {
+            glm::normalize(&normal)
+        } else {
+            hit.normal
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        self.a.is_specular() || self.b.is_specular()
+    }
+}
+
+// A clear dielectric coat over any base material, like varnish or the lacquer on a car. Light is
+// reflected off the coat as often as Fresnel says, and otherwise passes through to the base, which
+// is all light sampling sees: the reflection is a mirror's, along a single direction.
+pub struct Coated {
+    pub base: Arc<dyn Material>,
+    pub ir: f64,
+}
+
+impl Coated {
+    pub fn new(base: Arc<dyn Material>, ir: f64) -> Arc<Self> {
+        Arc::new(Coated { base, ir })
+    }
+
+    fn fresnel(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> f64 {
+        let cos_theta = (-glm::normalize(&r_in.direction)).dot(&hit.normal).clamp(0.0, 1.0);
+        util::schlick(cos_theta, 1.0 / self.ir)
+    }
+
+    fn reflected(r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        util::reflect(&glm::normalize(&r_in.direction), &hit.normal)
+    }
+
+    // Whether `scattered` is the coat's reflection, which the base has no say in.
+    fn is_reflection(r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> bool {
+        (glm::normalize(&scattered.direction) - Self::reflected(r_in, hit)).magnitude_squared() < 1e-18
+    }
+}
+
+impl Material for Coated {
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        // Picking the coat as often as it reflects cancels the Fresnel factor out of either side.
+        if sampler.get_1d() < self.fresnel(r_in, hit) {
+            return Some((ray::Ray::new(hit.p, Self::reflected(r_in, hit)), glm::vec3(1.0, 1.0, 1.0)));
+        }
+        self.base.scatter(r_in, hit, sampler)
+    }
+
+    fn scattering_pdf(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> f64 {
+        if Self::is_reflection(r_in, hit, scattered) {
+            return 0.0;
+        }
+        (1.0 - self.fresnel(r_in, hit)) * self.base.scattering_pdf(r_in, hit, scattered)
+    }
+
+    fn eval(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> glm::TVec3<f64> {
+        if Self::is_reflection(r_in, hit, scattered) {
+            return glm::vec3(0.0, 0.0, 0.0);
+        }
+        (1.0 - self.fresnel(r_in, hit)) * self.base.eval(r_in, hit, scattered)
+    }
+
+    fn emitted(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.base.emitted(r_in, hit)
+    }
+
+    // The coat is clear, so the color is the base's.
+    fn albedo(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.base.albedo(r_in, hit)
+    }
+
+    fn shading_normal(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.base.shading_normal(r_in, hit)
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+// How a `Shaded` material bends the normal it shades with.
+#[derive(Clone)]
+pub enum ShadingMap {
+    // Normals in the tangent frame, stored as colors the way normal maps are: each channel
+    // mapped from [-1, 1] to [0, 1], with blue pointing away from the surface.
+    Normal(Arc<dyn Texture>),
+    // Heights above the surface, `strength` times the average of the texture's channels.
+    Bump { height: Arc<dyn Texture>, strength: f64 },
+}
+
+// Any material shaded with a normal perturbed by a normal or bump map, adding detail to a
+// surface without changing its shape.
+pub struct Shaded {
+    pub base: Arc<dyn Material>,
+    pub map: ShadingMap,
+}
+
+impl Shaded {
+    pub fn new(base: Arc<dyn Material>, map: ShadingMap) -> Arc<Self> {
+        Arc::new(Shaded { base, map })
+    }
+
+    fn shading_hit(&self, hit: &objects::HitRecord) -> objects::HitRecord {
+        let normal = match &self.map {
+            ShadingMap::Normal(texture) => {
+                let tangent = glm::normalize(&(hit.dpdu - hit.normal * glm::dot(&hit.dpdu, &hit.normal)));
+                let bitangent = glm::cross(&hit.normal, &tangent);
+                let local = texture.value(hit.u, hit.v, &hit.p) * 2.0 - glm::vec3(1.0, 1.0, 1.0);
+                tangent * local.x + bitangent * local.y + hit.normal * local.z
+            }
+            ShadingMap::Bump { height, strength } => {
+                // Finite differences of the height, moving a small step along each tangent.
+                let height_at = |u: f64, v: f64, p: &glm::TVec3<f64>| height.value(u, v, p).mean() * strength;
+                let du = 0.0005;
+                let dv = 0.0005;
+                let base = height_at(hit.u, hit.v, &hit.p);
+                let dhdu = (height_at(hit.u + du, hit.v, &(hit.p + hit.dpdu * du)) - base) / du;
+                let dhdv = (height_at(hit.u, hit.v + dv, &(hit.p + hit.dpdv * dv)) - base) / dv;
+                let dpdu = hit.dpdu + hit.normal * dhdu;
+                let dpdv = hit.dpdv + hit.normal * dhdv;
+                let normal = glm::cross(&dpdu, &dpdv);
+                // The cross product points whichever way the parametrization does.
+                if glm::dot(&normal, &hit.normal) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                }
+            }
+        };
+        if normal.magnitude_squared() == 0.0 || !normal.iter().all(|c| c.is_finite()) {
+            return hit.clone();
+        }
+        objects::HitRecord { normal: glm::normalize(&normal), ..hit.clone() }
+    }
+}
+
+impl Material for Shaded {
+    fn scatter(&self, r_in: &ray::Ray, hit: &objects::HitRecord, sampler: &mut dyn Sampler) -> Option<(ray::Ray, glm::TVec3<f64>)> {
+        self.base.scatter(r_in, &self.shading_hit(hit), sampler)
+    }
+
+    fn scattering_pdf(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> f64 {
+        self.base.scattering_pdf(r_in, &self.shading_hit(hit), scattered)
+    }
+
+    fn eval(&self, r_in: &ray::Ray, hit: &objects::HitRecord, scattered: &ray::Ray) -> glm::TVec3<f64> {
+        self.base.eval(r_in, &self.shading_hit(hit), scattered)
+    }
+
+    fn emitted(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.base.emitted(r_in, &self.shading_hit(hit))
+    }
+
+    fn albedo(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.base.albedo(r_in, &self.shading_hit(hit))
+    }
+
+    fn shading_normal(&self, r_in: &ray::Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        self.base.shading_normal(r_in, &self.shading_hit(hit))
+    }
+
+    fn is_specular(&self) -> bool {
+        self.base.is_specular()
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        self.base.medium()
+    }
+}
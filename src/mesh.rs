@@ -0,0 +1,344 @@
+use nalgebra_glm as glm;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::materials;
+use crate::objects::{self, Aabb, HitRecord, Hittable};
+use crate::ray;
+use crate::textures::Texture;
+use crate::util;
+
+// Rows and columns of quads spheres are made of when turned into meshes.
+const SPHERE_RINGS: usize = 48;
+const SPHERE_SEGMENTS: usize = 96;
+
+// Triangles a leaf of the hierarchy holds at most.
+const LEAF_SIZE: usize = 4;
+
+enum Node {
+    Interior { bounds: Aabb, children: [usize; 2] },
+    Leaf { bounds: Aabb, triangles: Range<usize> },
+}
+
+impl Node {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            Node::Interior { bounds, .. } | Node::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+// Triangles shaded smoothly with the normals at their corners, which are found by walking down a
+// hierarchy of boxes around them.
+pub struct Mesh {
+    positions: Vec<glm::TVec3<f64>>,
+    normals: Vec<glm::TVec3<f64>>,
+    uvs: Vec<glm::TVec2<f64>>,
+    triangles: Vec<[usize; 3]>,
+    // For every vertex the one standing for its point of the surface, where a texture seam splits
+    // a point into several vertices with their own texture coordinates.
+    welds: Vec<usize>,
+    material: Arc<dyn materials::Material>,
+    nodes: Vec<Node>,
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<glm::TVec3<f64>>,
+        normals: Vec<glm::TVec3<f64>>,
+        uvs: Vec<glm::TVec2<f64>>,
+        triangles: Vec<[usize; 3]>,
+        material: Arc<dyn materials::Material>,
+    ) -> Self {
+        let welds = (0..positions.len()).collect();
+        let mut mesh = Mesh { positions, normals, uvs, triangles, welds, material, nodes: Vec::new() };
+        mesh.build();
+        mesh
+    }
+
+    // A sphere of triangles, with the texture coordinates of `Sphere`, so maps line up the same
+    // way on both. The last column of vertices is the first again with u at 1 instead of 0, and
+    // each pole is one point however many vertices it has.
+    pub fn sphere(centre: glm::TVec3<f64>, radius: f64, material: Arc<dyn materials::Material>) -> Self {
+        let index = |ring: usize, segment: usize| ring * (SPHERE_SEGMENTS + 1) + segment;
+        let (mut positions, mut normals, mut uvs, mut welds) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for ring in 0..=SPHERE_RINGS {
+            for segment in 0..=SPHERE_SEGMENTS {
+                let (u, v) = (segment as f64 / SPHERE_SEGMENTS as f64, ring as f64 / SPHERE_RINGS as f64);
+                let weld = if ring == 0 || ring == SPHERE_RINGS {
+                    index(ring, 0)
+                } else {
+                    index(ring, segment % SPHERE_SEGMENTS)
+                };
+                // Welded vertices take the position of the first, so they match to the bit.
+                let normal = if weld < positions.len() {
+                    normals[weld]
+                } else {
+                    let (theta, phi) = (v * std::f64::consts::PI, u * 2.0 * std::f64::consts::PI);
+                    glm::vec3(-theta.sin() * phi.cos(), -theta.cos(), theta.sin() * phi.sin())
+                };
+                positions.push(centre + normal * radius);
+                normals.push(normal);
+                uvs.push(glm::vec2(u, v));
+                welds.push(weld);
+            }
+        }
+        let mut triangles = Vec::new();
+        for ring in 0..SPHERE_RINGS {
+            for segment in 0..SPHERE_SEGMENTS {
+                let (a, b) = (index(ring, segment), index(ring, segment + 1));
+                let (c, d) = (index(ring + 1, segment), index(ring + 1, segment + 1));
+                // The quads touching the poles have collapsed to triangles.
+                if ring != 0 {
+                    triangles.push([a, d, b]);
+                }
+                if ring != SPHERE_RINGS - 1 {
+                    triangles.push([a, c, d]);
+                }
+            }
+        }
+        let mut mesh = Mesh::new(positions, normals, uvs, triangles, material);
+        mesh.welds = welds;
+        mesh
+    }
+
+    // Moves every vertex along its normal by `scale` times the average of the texture's channels
+    // there, then smooths the normals over the displaced surface. Welded vertices move by their
+    // average height, so the mesh doesn't tear along texture seams.
+    pub fn displaced(mut self, height: &dyn Texture, scale: f64) -> Self {
+        let groups = self.welds.clone();
+        let mut heights = vec![(0.0, 0); self.positions.len()];
+        for (i, &group) in groups.iter().enumerate() {
+            heights[group].0 += height.value(self.uvs[i].x, self.uvs[i].y, &self.positions[i]).mean();
+            heights[group].1 += 1;
+        }
+        for (i, &group) in groups.iter().enumerate() {
+            let (sum, count) = heights[group];
+            self.positions[i] += self.normals[group] * (scale * sum / count as f64);
+        }
+
+        // Normals of the faces around each vertex, weighted by their areas.
+        let mut normals = vec![glm::vec3(0.0, 0.0, 0.0); self.positions.len()];
+        for &[a, b, c] in &self.triangles {
+            let face = glm::cross(&(self.positions[b] - self.positions[a]), &(self.positions[c] - self.positions[a]));
+            for vertex in [a, b, c] {
+                normals[groups[vertex]] += face;
+            }
+        }
+        for (i, &group) in groups.iter().enumerate() {
+            if normals[group].magnitude_squared() > 0.0 {
+                self.normals[i] = glm::normalize(&normals[group]);
+            }
+        }
+        self.build();
+        self
+    }
+
+    fn triangle_bounds(&self, triangle: &[usize; 3]) -> Aabb {
+        let [a, b, c] = triangle.map(|i| self.positions[i]);
+        Aabb { min: glm::min2(&glm::min2(&a, &b), &c), max: glm::max2(&glm::max2(&a, &b), &c) }
+    }
+
+    fn build(&mut self) {
+        self.nodes.clear();
+        if !self.triangles.is_empty() {
+            self.build_node(0..self.triangles.len());
+        }
+    }
+
+    // Splits the triangles at the median centroid along the axis where they are spread the most,
+    // returning the index of the node holding them.
+    fn build_node(&mut self, range: Range<usize>) -> usize {
+        let bounds = self.triangles[range.clone()]
+            .iter()
+            .map(|triangle| self.triangle_bounds(triangle))
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+        if range.len() <= LEAF_SIZE {
+            self.nodes.push(Node::Leaf { bounds, triangles: range });
+            return self.nodes.len() - 1;
+        }
+
+        let extent = bounds.max - bounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        let positions = &self.positions;
+        let centroid = |triangle: &[usize; 3]| triangle.iter().map(|&i| positions[i][axis]).sum::<f64>();
+        let middle = range.len() / 2;
+        self.triangles[range.clone()].select_nth_unstable_by(middle, |a, b| centroid(a).total_cmp(&centroid(b)));
+
+        let node = self.nodes.len();
+        self.nodes.push(Node::Leaf { bounds, triangles: 0..0 });
+        let left = self.build_node(range.start..range.start + middle);
+        let right = self.build_node(range.start + middle..range.end);
+        self.nodes[node] = Node::Interior { bounds, children: [left, right] };
+        node
+    }
+
+    // Where the ray crosses the triangle, as the distance along it and the weights of the second
+    // and third corners.
+    fn intersect(&self, r: &ray::Ray, triangle: &[usize; 3], t_min: f64, t_max: f64) -> Option<(f64, f64, f64)> {
+        let [p0, p1, p2] = triangle.map(|i| self.positions[i]);
+        let (edge1, edge2) = (p1 - p0, p2 - p0);
+        let h = glm::cross(&r.direction, &edge2);
+        let determinant = glm::dot(&edge1, &h);
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+        let s = r.origin - p0;
+        let b1 = glm::dot(&s, &h) / determinant;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = glm::cross(&s, &edge1);
+        let b2 = glm::dot(&r.direction, &q) / determinant;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = glm::dot(&edge2, &q) / determinant;
+        (t > t_min && t < t_max).then_some((t, b1, b2))
+    }
+
+    fn hit_record(&self, r: &ray::Ray, triangle: &[usize; 3], t: f64, b1: f64, b2: f64) -> HitRecord {
+        let [i0, i1, i2] = *triangle;
+        let b0 = 1.0 - b1 - b2;
+        let uv = self.uvs[i0] * b0 + self.uvs[i1] * b1 + self.uvs[i2] * b2;
+        let shading = self.normals[i0] * b0 + self.normals[i1] * b1 + self.normals[i2] * b2;
+        let (dp02, dp12) = (self.positions[i0] - self.positions[i2], self.positions[i1] - self.positions[i2]);
+        let mut geometric = glm::cross(&dp02, &dp12);
+        let outward_normal = if shading.magnitude_squared() > 0.0 {
+            glm::normalize(&shading)
+        } else {
+            glm::normalize(&geometric)
+        };
+        // Which side was hit is up to the triangle itself, the interpolated normal only shades.
+        if glm::dot(&geometric, &outward_normal) < 0.0 {
+            geometric = -geometric;
+        }
+        let front_face = glm::dot(&r.direction, &geometric) < 0.0;
+        let normal = if front_face { outward_normal } else { -outward_normal };
+
+        let (duv02, duv12) = (self.uvs[i0] - self.uvs[i2], self.uvs[i1] - self.uvs[i2]);
+        let determinant = duv02.x * duv12.y - duv02.y * duv12.x;
+        let (dpdu, dpdv) = if determinant.abs() < 1e-12 {
+            util::orthonormal_basis(&outward_normal)
+        } else {
+            ((dp02 * duv12.y - dp12 * duv02.y) / determinant, (dp12 * duv02.x - dp02 * duv12.x) / determinant)
+        };
+        HitRecord { t, p: r.at(t), normal, u: uv.x, v: uv.y, dpdu, dpdv, front_face, material: self.material.clone() }
+    }
+}
+
+impl Hittable for Mesh {
+    fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut closest: Option<(usize, f64, f64, f64)> = None;
+        let mut t_max = t_max;
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !node.bounds().hit(r, t_min, t_max) {
+                continue;
+            }
+            match node {
+                Node::Interior { children, .. } => stack.extend_from_slice(children),
+                Node::Leaf { triangles, .. } => {
+                    for index in triangles.clone() {
+                        if let Some((t, b1, b2)) = self.intersect(r, &self.triangles[index], t_min, t_max) {
+                            t_max = t;
+                            closest = Some((index, t, b1, b2));
+                        }
+                    }
+                }
+            }
+        }
+        closest.map(|(index, t, b1, b2)| self.hit_record(r, &self.triangles[index], t, b1, b2))
+    }
+
+    fn materials(&self) -> Vec<Arc<dyn materials::Material>> {
+        vec![self.material.clone()]
+    }
+
+    fn specular_bounds(&self) -> Option<objects::Aabb> {
+        if !self.material.is_specular() || self.nodes.is_empty() {
+            return None;
+        }
+        Some(*self.nodes[0].bounds())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::textures::SolidColor;
+
+    fn sphere() -> Mesh {
+        Mesh::sphere(glm::vec3(0.0, 0.0, 0.0), 1.0, materials::Lambertian::new(glm::vec3(0.5, 0.5, 0.5)))
+    }
+
+    #[test]
+    fn hits_sphere_from_outside() {
+        let hit = sphere().hit(&ray::Ray::new(glm::vec3(0.3, 0.2, -5.0), glm::vec3(0.0, 0.0, 1.0)), 0.001, f64::MAX).unwrap();
+        let expected = 5.0 - (1.0f64 - 0.3 * 0.3 - 0.2 * 0.2).sqrt();
+        assert!((hit.t - expected).abs() < 1e-3, "{} != {}", hit.t, expected);
+        assert!(hit.front_face);
+        assert!(glm::dot(&hit.normal, &glm::vec3(0.0, 0.0, -1.0)) > 0.9);
+    }
+
+    #[test]
+    fn hits_sphere_from_inside() {
+        let hit = sphere().hit(&ray::Ray::new(glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 0.0)), 0.001, f64::MAX).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-3);
+        assert!(!hit.front_face);
+        assert!(glm::dot(&hit.normal, &glm::vec3(-1.0, 0.0, 0.0)) > 0.99);
+    }
+
+    #[test]
+    fn displaces_along_normals_without_tearing() {
+        let mesh = sphere().displaced(SolidColor::new(glm::vec3(1.0, 1.0, 1.0)).as_ref(), 0.5);
+        for p in &mesh.positions {
+            assert!((p.magnitude() - 1.5).abs() < 1e-9);
+        }
+        // Straight through a pole, where the collapsed quads meet.
+        let hit = mesh.hit(&ray::Ray::new(glm::vec3(0.0, -5.0, 0.0), glm::vec3(0.0, 1.0, 0.0)), 0.001, f64::MAX).unwrap();
+        assert!((hit.t - 3.5).abs() < 1e-6);
+    }
+
+    // Heights rising from 0 to 1 around the sphere, so the two sides of the seam disagree the most.
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+            glm::vec3(u, u, u)
+        }
+    }
+
+    #[test]
+    fn keeps_seam_and_poles_closed_under_heights_varying_along_u() {
+        let mesh = sphere().displaced(&Ramp, 0.5);
+        let index = |ring: usize, segment: usize| ring * (SPHERE_SEGMENTS + 1) + segment;
+        for ring in 0..=SPHERE_RINGS {
+            assert_eq!(mesh.positions[index(ring, 0)], mesh.positions[index(ring, SPHERE_SEGMENTS)]);
+            assert_eq!(mesh.normals[index(ring, 0)], mesh.normals[index(ring, SPHERE_SEGMENTS)]);
+        }
+        for segment in 0..=SPHERE_SEGMENTS {
+            assert_eq!(mesh.positions[index(0, segment)], mesh.positions[index(0, 0)]);
+            assert_eq!(mesh.positions[index(SPHERE_RINGS, segment)], mesh.positions[index(SPHERE_RINGS, 0)]);
+        }
+        // Rays along the seam and through the poles all find the surface.
+        for y in [-0.9, -0.5, 0.0, 0.5, 0.9] {
+            assert!(mesh.hit(&ray::Ray::new(glm::vec3(-5.0, y, 0.0), glm::vec3(1.0, 0.0, 0.0)), 0.001, f64::MAX).is_some());
+        }
+        for direction in [1.0, -1.0] {
+            let origin = glm::vec3(0.0, -5.0 * direction, 0.0);
+            assert!(mesh.hit(&ray::Ray::new(origin, glm::vec3(0.0, direction, 0.0)), 0.001, f64::MAX).is_some());
+        }
+    }
+}
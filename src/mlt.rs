@@ -0,0 +1,276 @@
+use nalgebra_glm as glm;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f64::consts::PI;
+use std::sync::Mutex;
+
+use crate::color;
+use crate::camera::Camera;
+use crate::integrator::Integrator;
+use crate::path;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+
+#[derive(Default, Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    last_modified: u64,
+    backup_value: f64,
+    backup_modified: u64,
+}
+
+// The random numbers a path was traced with, as a point in primary sample space that mutations
+// move around. Values are only mutated once a path asks for them, catching up on all the small
+// steps they missed since they were last used.
+struct MltSampler {
+    samples: Vec<PrimarySample>,
+    // Where the values and mutations come from, so a chain started from the same seed takes the
+    // same steps.
+    rng: StdRng,
+    sigma: f64,
+    large_step_probability: f64,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize,
+}
+
+impl MltSampler {
+    fn new(sigma: f64, large_step_probability: f64, seed: u64) -> Self {
+        MltSampler {
+            samples: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup_value;
+                sample.last_modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> f64 {
+        let index = self.index;
+        self.index += 1;
+        if index >= self.samples.len() {
+            let last_large_step = self.last_large_step;
+            let rng = &mut self.rng;
+            self.samples.resize_with(index + 1, || PrimarySample {
+                value: rng.gen(),
+                last_modified: last_large_step,
+                ..Default::default()
+            });
+        }
+        let sample = &mut self.samples[index];
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modified = self.last_large_step;
+        }
+        sample.backup_value = sample.value;
+        sample.backup_modified = sample.last_modified;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // The sum of the small steps since the value was last used is a single wider one.
+            let small_steps = (self.iteration - sample.last_modified) as f64;
+            sample.value += normal_sample(&mut self.rng) * self.sigma * small_steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modified = self.iteration;
+        sample.value
+    }
+}
+
+// The path tracer draws its random numbers from the chain, one dimension of primary sample space
+// after another.
+impl Sampler for MltSampler {
+    fn start_pixel_sample(&mut self, _pixel: (u32, u32), _index: u64) {}
+
+    fn get_1d(&mut self) -> f64 {
+        self.next()
+    }
+
+    fn get_2d(&mut self) -> glm::TVec2<f64> {
+        glm::vec2(self.next(), self.next())
+    }
+}
+
+fn normal_sample(rng: &mut StdRng) -> f64 {
+    let u1 = 1.0 - rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+struct Chain {
+    sampler: MltSampler,
+    screen_coords: glm::TVec2<f64>,
+    radiance: glm::TVec3<f64>,
+    contribution: f64,
+}
+
+// Bootstrap samples taken every frame while the camera moves.
+const MOVING_BOOTSTRAP_SAMPLES: u32 = 1000;
+
+// Primary sample space Metropolis light transport: a Markov chain wanders through the random
+// numbers the path tracer consumes, visiting paths in proportion to how bright they are, so once
+// it finds a rare path that carries light it keeps exploring its neighbours. Every mutation
+// splats onto the image, which the bootstrap estimate of the image brightness normalizes.
+pub struct MltIntegrator {
+    path: path::PathIntegrator,
+    mutations: u32,
+    bootstrap_samples: u32,
+    large_step_probability: f64,
+    sigma: f64,
+    seed: u64,
+    // Average contribution of a path, the camera pose it was estimated from and whether it was
+    // estimated from all the bootstrap samples.
+    brightness: f64,
+    pose: Option<(glm::TVec3<f64>, glm::TVec3<f64>)>,
+    settled: bool,
+    chain: Mutex<Option<Chain>>,
+    splats: Mutex<Vec<(glm::TVec2<f64>, glm::TVec3<f64>)>>,
+}
+
+impl MltIntegrator {
+    pub fn new(
+        depths: path::PathDepths,
+        mutations: u32,
+        bootstrap_samples: u32,
+        large_step_probability: f64,
+        sigma: f64,
+        spectral: bool,
+        seed: u64,
+    ) -> Self {
+        MltIntegrator {
+            path: path::PathIntegrator { depths, spectral },
+            mutations,
+            bootstrap_samples,
+            large_step_probability,
+            sigma,
+            seed,
+            brightness: 0.0,
+            pose: None,
+            settled: false,
+            chain: Mutex::new(None),
+            splats: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Traces the path the sampler's current random numbers lead to, returning where it lands on
+    // the screen and the radiance it carries.
+    fn evaluate(&self, scene: &Scene, camera: &Camera, sampler: &mut MltSampler) -> (glm::TVec2<f64>, glm::TVec3<f64>) {
+        let screen_coords = sampler.get_2d();
+        let ray = camera.get_ray(screen_coords, sampler.get_2d());
+        let radiance = self.path.li(&ray, scene, camera, sampler);
+        if radiance.iter().any(|c| !c.is_finite()) {
+            return (screen_coords, glm::vec3(0.0, 0.0, 0.0));
+        }
+        (screen_coords, radiance)
+    }
+
+    // Estimates the image brightness from independent paths and starts the chain at one of them,
+    // picked in proportion to its contribution.
+    fn bootstrap(&self, scene: &Scene, camera: &Camera, samples: u32) -> (f64, Option<Chain>) {
+        let mut total = 0.0;
+        let mut chain: Option<Chain> = None;
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        for _ in 0..samples {
+            let mut sampler = MltSampler::new(self.sigma, self.large_step_probability, rng.gen());
+            let (screen_coords, radiance) = self.evaluate(scene, camera, &mut sampler);
+            let contribution = color::luminance(&radiance);
+            total += contribution;
+            if contribution > 0.0 && rng.gen::<f64>() < contribution / total {
+                chain = Some(Chain { sampler, screen_coords, radiance, contribution });
+            }
+        }
+        (total / samples as f64, chain)
+    }
+}
+
+impl Integrator for MltIntegrator {
+    fn name(&self) -> &'static str {
+        "mlt"
+    }
+
+    fn preprocess(&mut self, scene: &Scene, camera: &Camera, _pass: u64) {
+        let pose = (camera.position(), camera.forward());
+        // Bootstrapping takes long enough to stall the viewer, so while the camera moves the
+        // brightness is only roughly estimated, and properly once it comes to rest.
+        let samples = match self.pose {
+            None => self.bootstrap_samples,
+            Some(last) if last != pose => self.bootstrap_samples.min(MOVING_BOOTSTRAP_SAMPLES),
+            Some(_) if !self.settled => self.bootstrap_samples,
+            Some(_) => return,
+        };
+        let (brightness, chain) = self.bootstrap(scene, camera, samples);
+        self.brightness = brightness;
+        self.chain = Mutex::new(chain);
+        self.splats.lock().unwrap().clear();
+        self.pose = Some(pose);
+        self.settled = samples == self.bootstrap_samples;
+    }
+
+    // All the light arrives through splats, the pixel being sampled just decides how many
+    // mutations are made.
+    fn li(&self, _r: &Ray, scene: &Scene, camera: &Camera, _sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        let mut chain = self.chain.lock().unwrap();
+        let chain = match chain.as_mut() {
+            Some(chain) => chain,
+            None => return glm::vec3(0.0, 0.0, 0.0),
+        };
+        let scale = self.brightness / self.mutations as f64;
+        let mut splats = self.splats.lock().unwrap();
+        for _ in 0..self.mutations {
+            chain.sampler.start_iteration();
+            let (screen_coords, radiance) = self.evaluate(scene, camera, &mut chain.sampler);
+            let contribution = color::luminance(&radiance);
+            let accept = (contribution / chain.contribution).min(1.0);
+            // Both states splat in proportion to their chance of being the next one.
+            if accept > 0.0 {
+                splats.push((screen_coords, radiance * (accept * scale / contribution)));
+            }
+            splats.push((chain.screen_coords, chain.radiance * ((1.0 - accept) * scale / chain.contribution)));
+            if chain.sampler.rng.gen::<f64>() < accept {
+                chain.sampler.accept();
+                chain.screen_coords = screen_coords;
+                chain.radiance = radiance;
+                chain.contribution = contribution;
+            } else {
+                chain.sampler.reject();
+            }
+        }
+        glm::vec3(0.0, 0.0, 0.0)
+    }
+
+    fn take_splats(&self) -> Vec<(glm::TVec2<f64>, glm::TVec3<f64>)> {
+        std::mem::take(&mut *self.splats.lock().unwrap())
+    }
+
+    // There is only the one chain, and every call moves it on from where the last one left it.
+    fn is_sequential(&self) -> bool {
+        true
+    }
+}
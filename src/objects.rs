@@ -4,11 +4,16 @@ use crate::ray;
 use crate::materials;
 use std::sync::Arc;
 
-#[allow(dead_code)]
+#[allow(dead_code)] #[derive(Clone)]
 pub struct HitRecord {
     pub p: glm::TVec3<f64>,
     pub normal: glm::TVec3<f64>,
     pub t: f64,
+    pub u: f64,
+    pub v: f64,
+    // How the point moves with the texture coordinates, the tangent frame normal maps are in.
+    pub dpdu: glm::TVec3<f64>,
+    pub dpdv: glm::TVec3<f64>,
     pub material: Arc<dyn materials::Material>,
     pub front_face: bool
 }
@@ -23,8 +28,47 @@ pub fn set_face_normal(r: &ray::Ray, outward_normal: glm::TVec3<f64>) -> (glm::T
     (normal, front_face)
 }
 
-pub trait Hittable {
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: glm::TVec3<f64>,
+    pub max: glm::TVec3<f64>,
+}
+
+impl Aabb {
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+        }
+    }
+
+    // Whether the ray passes through the box between `t_min` and `t_max`.
+    pub fn hit(&self, r: &ray::Ray, mut t_min: f64, mut t_max: f64) -> bool {
+        for axis in 0..3 {
+            let inverse = 1.0 / r.direction[axis];
+            let mut t0 = (self.min[axis] - r.origin[axis]) * inverse;
+            let mut t1 = (self.max[axis] - r.origin[axis]) * inverse;
+            if inverse < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub trait Hittable: Send + Sync {
     fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+
+    // Materials of the object, in the order its parts were made.
+    fn materials(&self) -> Vec<Arc<dyn materials::Material>>;
+
+    // Bounds of the parts of the object made of mirrors or glass, which photons are aimed at.
+    fn specular_bounds(&self) -> Option<Aabb>;
 }
 
 #[derive(Default)]
@@ -36,19 +80,35 @@ impl HittableList {
     pub fn push(&mut self, hittable: impl Hittable + 'static) {
         self.objects.push(Box::new(hittable));
     }
-}
 
-impl Hittable for HittableList {
-    fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let mut hit_anything: Option<HitRecord> = None;
+    // Like `hit`, but also returning the index of the object hit, in the order they were pushed.
+    pub fn hit_object(&self, r: &ray::Ray, t_min: f64, t_max: f64) -> Option<(usize, HitRecord)> {
+        let mut hit_anything: Option<(usize, HitRecord)> = None;
         let mut closest_so_far: f64 = t_max;
 
-        for object in self.objects.iter() {
+        for (index, object) in self.objects.iter().enumerate() {
             if let Some(hit) = object.hit(r, t_min, closest_so_far) {
                 closest_so_far = hit.t;
-                hit_anything = Some(hit);
+                hit_anything = Some((index, hit));
             }
         }
         hit_anything
     }
+}
+
+impl Hittable for HittableList {
+    fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        self.hit_object(r, t_min, t_max).map(|(_, hit)| hit)
+    }
+
+    fn materials(&self) -> Vec<Arc<dyn materials::Material>> {
+        self.objects.iter().flat_map(|object| object.materials()).collect()
+    }
+
+    fn specular_bounds(&self) -> Option<Aabb> {
+        self.objects
+            .iter()
+            .filter_map(|object| object.specular_bounds())
+            .reduce(|a, b| a.union(&b))
+    }
 }
\ No newline at end of file
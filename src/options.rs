@@ -0,0 +1,353 @@
+use crate::aov;
+use crate::colorspace;
+use crate::filter;
+use crate::integrator;
+use crate::output;
+use crate::post;
+use crate::sampler;
+use crate::tonemap;
+use std::env;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub struct Options {
+    pub scene: String,
+    pub integrator: String,
+    pub sampler: String,
+    pub strata: u32,
+    // Reconstruction filter of the film, and its radius in pixels.
+    pub filter: String,
+    pub filter_radius: f64,
+    // Exposure in stops, and how the image is tone mapped for showing and saving.
+    pub exposure: f64,
+    pub tone_map: String,
+    pub dither: bool,
+    // Post effects applied after tone mapping, in order.
+    pub post: Vec<post::Effect>,
+    // Color spaces the scene's colors and environment maps are in, light is added up in, and
+    // saved images are written in.
+    pub input_space: String,
+    pub working_space: String,
+    pub output_space: String,
+    // Denoises the saved image, and the one in the window until N turns it off.
+    pub denoise: bool,
+    // Seeds the scene and every sample, so the same seed renders the same image.
+    pub seed: Option<u64>,
+    // Renders this many samples per pixel into `output` instead of opening a window.
+    pub output: Option<String>,
+    pub samples: u32,
+    pub threads: usize,
+    // Adaptive sampling, which takes more samples in noisy pixels until their error is below
+    // the target or the time budget, in seconds, runs out.
+    pub target_error: Option<f64>,
+    pub time_budget: Option<f64>,
+    pub max_samples: u32,
+    // Where O saves the image rendered progressively in the window.
+    pub snapshot: String,
+    // Where to save an image of how many samples each pixel took.
+    pub heatmap: Option<String>,
+    // Auxiliary buffers to save along with the `output`.
+    pub aovs: Vec<String>,
+    pub environment_map: Option<String>,
+    pub environment_rotation: f64,
+    pub environment_intensity: f64,
+    pub sky: bool,
+    pub sun_elevation: f64,
+    pub sun_azimuth: f64,
+    pub turbidity: f64,
+    pub ground_albedo: f64,
+    pub sky_intensity: f64,
+    pub light: Option<String>,
+    pub light_position: [f64; 3],
+    pub light_direction: [f64; 3],
+    pub light_intensity: f64,
+    pub light_profile: Option<String>,
+    pub spot_angle: f64,
+    pub spot_falloff: f64,
+    pub photons: usize,
+    pub photon_radius: f64,
+    pub mutations: u32,
+    pub bootstrap_samples: u32,
+    pub large_step_probability: f64,
+    pub mutation_sigma: f64,
+    pub spectral: bool,
+    pub glass: String,
+    pub film_thickness: Option<f64>,
+    pub film_ior: f64,
+    pub subsurface: Option<[f64; 3]>,
+    pub coat: Option<f64>,
+    pub checker_ground: bool,
+    pub normal_map: Option<String>,
+    pub bump_map: Option<String>,
+    pub bump_strength: f64,
+    pub cutout: Option<String>,
+    pub displacement: Option<String>,
+    pub displacement_scale: f64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            scene: "random".to_string(),
+            integrator: "path".to_string(),
+            sampler: "independent".to_string(),
+            strata: 4,
+            filter: "box".to_string(),
+            filter_radius: filter::default_radius("box"),
+            exposure: 0.0,
+            tone_map: "clamp".to_string(),
+            dither: true,
+            post: Vec::new(),
+            input_space: "srgb".to_string(),
+            working_space: "srgb".to_string(),
+            output_space: "srgb".to_string(),
+            denoise: false,
+            seed: None,
+            output: None,
+            samples: 64,
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            target_error: None,
+            time_budget: None,
+            max_samples: 1024,
+            snapshot: "snapshot.exr".to_string(),
+            heatmap: None,
+            aovs: Vec::new(),
+            environment_map: None,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            sky: false,
+            sun_elevation: 45.0,
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            ground_albedo: 0.3,
+            sky_intensity: 1.0,
+            light: None,
+            light_position: [0.0, 5.0, 0.0],
+            light_direction: [0.0, -1.0, 0.0],
+            light_intensity: 20.0,
+            light_profile: None,
+            spot_angle: 30.0,
+            spot_falloff: 20.0,
+            photons: 20000,
+            photon_radius: 0.1,
+            mutations: 1,
+            bootstrap_samples: 100000,
+            large_step_probability: 0.3,
+            mutation_sigma: 0.01,
+            spectral: false,
+            glass: "plain".to_string(),
+            film_thickness: None,
+            film_ior: 1.33,
+            subsurface: None,
+            coat: None,
+            checker_ground: false,
+            normal_map: None,
+            bump_map: None,
+            bump_strength: 0.01,
+            cutout: None,
+            displacement: None,
+            displacement_scale: 0.02,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = env::args().skip(1);
+        let mut filter_radius = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => options.scene = value(&arg, args.next())?,
+                "--integrator" => options.integrator = value(&arg, args.next())?,
+                "--sampler" => options.sampler = value(&arg, args.next())?,
+                "--strata" => options.strata = parse(&arg, args.next())?,
+                "--filter" => options.filter = value(&arg, args.next())?,
+                "--filter-radius" => filter_radius = Some(parse(&arg, args.next())?),
+                "--exposure" => options.exposure = parse(&arg, args.next())?,
+                "--tone-map" => options.tone_map = value(&arg, args.next())?,
+                "--no-dither" => options.dither = false,
+                "--post" => options.post = value(&arg, args.next())?.split(',').map(post::Effect::parse).collect::<Result<_, _>>()?,
+                "--input-space" => options.input_space = value(&arg, args.next())?,
+                "--working-space" => options.working_space = value(&arg, args.next())?,
+                "--output-space" => options.output_space = value(&arg, args.next())?,
+                "--denoise" => options.denoise = true,
+                "--seed" => options.seed = Some(parse(&arg, args.next())?),
+                "--output" => options.output = Some(value(&arg, args.next())?),
+                "--samples" => options.samples = parse(&arg, args.next())?,
+                "--threads" => options.threads = parse(&arg, args.next())?,
+                "--target-error" => options.target_error = Some(parse(&arg, args.next())?),
+                "--time-budget" => options.time_budget = Some(parse(&arg, args.next())?),
+                "--max-samples" => options.max_samples = parse(&arg, args.next())?,
+                "--snapshot" => options.snapshot = value(&arg, args.next())?,
+                "--heatmap" => options.heatmap = Some(value(&arg, args.next())?),
+                "--aovs" => options.aovs = value(&arg, args.next())?.split(',').map(|name| name.trim().to_string()).collect(),
+                "--env" => options.environment_map = Some(value(&arg, args.next())?),
+                "--env-rotation" => options.environment_rotation = parse(&arg, args.next())?,
+                "--env-intensity" => options.environment_intensity = parse(&arg, args.next())?,
+                "--sky" => options.sky = true,
+                "--sun-elevation" => options.sun_elevation = parse(&arg, args.next())?,
+                "--sun-azimuth" => options.sun_azimuth = parse(&arg, args.next())?,
+                "--turbidity" => options.turbidity = parse(&arg, args.next())?,
+                "--ground-albedo" => options.ground_albedo = parse(&arg, args.next())?,
+                "--sky-intensity" => options.sky_intensity = parse(&arg, args.next())?,
+                "--light" => options.light = Some(value(&arg, args.next())?),
+                "--light-position" => options.light_position = parse_vec3(&arg, args.next())?,
+                "--light-direction" => options.light_direction = parse_vec3(&arg, args.next())?,
+                "--light-intensity" => options.light_intensity = parse(&arg, args.next())?,
+                "--light-profile" => options.light_profile = Some(value(&arg, args.next())?),
+                "--spot-angle" => options.spot_angle = parse(&arg, args.next())?,
+                "--spot-falloff" => options.spot_falloff = parse(&arg, args.next())?,
+                "--photons" => options.photons = parse(&arg, args.next())?,
+                "--photon-radius" => options.photon_radius = parse(&arg, args.next())?,
+                "--mutations" => options.mutations = parse(&arg, args.next())?,
+                "--bootstrap" => options.bootstrap_samples = parse(&arg, args.next())?,
+                "--large-step" => options.large_step_probability = parse(&arg, args.next())?,
+                "--mutation-sigma" => options.mutation_sigma = parse(&arg, args.next())?,
+                "--spectral" => options.spectral = true,
+                "--glass" => options.glass = value(&arg, args.next())?,
+                "--film" => options.film_thickness = Some(parse(&arg, args.next())?),
+                "--film-ior" => options.film_ior = parse(&arg, args.next())?,
+                "--subsurface" => options.subsurface = Some(parse_vec3(&arg, args.next())?),
+                "--coat" => options.coat = Some(parse(&arg, args.next())?),
+                "--checker-ground" => options.checker_ground = true,
+                "--normal-map" => options.normal_map = Some(value(&arg, args.next())?),
+                "--bump-map" => options.bump_map = Some(value(&arg, args.next())?),
+                "--bump-strength" => options.bump_strength = parse(&arg, args.next())?,
+                "--cutout" => options.cutout = Some(value(&arg, args.next())?),
+                "--displacement" => options.displacement = Some(value(&arg, args.next())?),
+                "--displacement-scale" => options.displacement_scale = parse(&arg, args.next())?,
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        if options.sky && options.environment_map.is_some() {
+            return Err("--sky and --env can not be used together".to_string());
+        }
+        if !["random", "glowing"].contains(&options.scene.as_str()) {
+            return Err(format!("Unknown scene: {}", options.scene));
+        }
+        if let Some(light) = &options.light {
+            if light != "point" && light != "spot" {
+                return Err(format!("Unknown light: {}", light));
+            }
+        }
+        if options.light_profile.is_some() && options.light.is_none() {
+            options.light = Some("point".to_string());
+        }
+        if options.light_direction == [0.0; 3] {
+            return Err("--light-direction can not be zero".to_string());
+        }
+        if !integrator::NAMES.contains(&options.integrator.as_str()) {
+            return Err(format!("Unknown integrator: {}, expected one of {}", options.integrator, integrator::NAMES.join(", ")));
+        }
+        if options.spectral && ["bdpt", "photon"].contains(&options.integrator.as_str()) {
+            return Err(format!("--spectral can not be used with the {} integrator", options.integrator));
+        }
+        if !sampler::NAMES.contains(&options.sampler.as_str()) {
+            return Err(format!("Unknown sampler: {}, expected one of {}", options.sampler, sampler::NAMES.join(", ")));
+        }
+        if !filter::NAMES.contains(&options.filter.as_str()) {
+            return Err(format!("Unknown filter: {}, expected one of {}", options.filter, filter::NAMES.join(", ")));
+        }
+        if !tonemap::NAMES.contains(&options.tone_map.as_str()) {
+            return Err(format!("Unknown tone mapper: {}, expected one of {}", options.tone_map, tonemap::NAMES.join(", ")));
+        }
+        for space in [&options.input_space, &options.working_space, &options.output_space] {
+            if !colorspace::NAMES.contains(&space.as_str()) {
+                return Err(format!("Unknown color space: {}, expected one of {}", space, colorspace::NAMES.join(", ")));
+            }
+        }
+        if options.output_space == "acescg" && options.output.iter().chain([&options.snapshot]).any(|path| !output::is_linear(path)) {
+            return Err("ACEScg images can only be saved as .exr, .hdr or .pfm".to_string());
+        }
+        options.filter_radius = filter_radius.unwrap_or_else(|| filter::default_radius(&options.filter));
+        if options.filter_radius < 0.5 {
+            return Err(format!("Filter radius must be at least half a pixel, got {}", options.filter_radius));
+        }
+        if options.strata == 0 || options.strata > 256 {
+            return Err(format!("Strata must be between 1 and 256, got {}", options.strata));
+        }
+        if options.samples == 0 || options.threads == 0 {
+            return Err("--samples and --threads must be at least 1".to_string());
+        }
+        if options.output.is_none() && (options.target_error.is_some() || options.time_budget.is_some() || options.heatmap.is_some()) {
+            return Err("Adaptive sampling and heatmaps need an --output".to_string());
+        }
+        if options.aovs.iter().any(|name| name == "all") {
+            options.aovs = aov::NAMES.iter().map(|name| name.to_string()).collect();
+        }
+        if let Some(name) = options.aovs.iter().find(|name| !aov::NAMES.contains(&name.as_str())) {
+            return Err(format!("Unknown AOV: {}, expected all or some of {}", name, aov::NAMES.join(", ")));
+        }
+        if options.output.is_none() && !options.aovs.is_empty() {
+            return Err("AOVs need an --output".to_string());
+        }
+        if options.target_error.is_some_and(|error| error <= 0.0) || options.time_budget.is_some_and(|budget| budget <= 0.0) {
+            return Err("Target errors and time budgets must be positive".to_string());
+        }
+        if options.max_samples < options.samples {
+            return Err(format!("--max-samples must be at least --samples, got {}", options.max_samples));
+        }
+        if options.turbidity < 1.0 {
+            return Err(format!("Turbidity must be at least 1, got {}", options.turbidity));
+        }
+        if options.photons == 0 {
+            return Err("--photons must be at least 1".to_string());
+        }
+        if options.photon_radius <= 0.0 {
+            return Err(format!("Photon radius must be positive, got {}", options.photon_radius));
+        }
+        if !["plain", "bk7", "flint", "diamond"].contains(&options.glass.as_str()) {
+            return Err(format!("Unknown glass: {}", options.glass));
+        }
+        if options.film_thickness.is_some_and(|thickness| thickness <= 0.0) || options.film_ior < 1.0 {
+            return Err("Films need a positive thickness and an index of refraction of at least 1".to_string());
+        }
+        if options.subsurface.is_some_and(|mean_free_path| mean_free_path.iter().any(|&length| length <= 0.0)) {
+            return Err("Mean free paths must be positive".to_string());
+        }
+        if options.normal_map.is_some() && options.bump_map.is_some() {
+            return Err("--normal-map and --bump-map can not be used together".to_string());
+        }
+        if options.displacement.is_some() && options.cutout.is_some() {
+            return Err("--displacement and --cutout can not be used together".to_string());
+        }
+        if options.coat.is_some_and(|ir| ir < 1.0) {
+            return Err("Coats need an index of refraction of at least 1".to_string());
+        }
+        if options.mutations == 0 || options.bootstrap_samples == 0 {
+            return Err("--mutations and --bootstrap must be at least 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&options.large_step_probability) {
+            return Err(format!("Large step probability must be between 0 and 1, got {}", options.large_step_probability));
+        }
+        if options.mutation_sigma <= 0.0 {
+            return Err(format!("Mutation sigma must be positive, got {}", options.mutation_sigma));
+        }
+        Ok(options)
+    }
+}
+
+fn value(flag: &str, next: Option<String>) -> Result<String, String> {
+    next.ok_or_else(|| format!("Missing value for {}", flag))
+}
+
+fn parse<T: FromStr>(flag: &str, next: Option<String>) -> Result<T, String> {
+    let value = value(flag, next)?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+fn parse_vec3(flag: &str, next: Option<String>) -> Result<[f64; 3], String> {
+    let value = value(flag, next)?;
+    let parts: Vec<f64> = value
+        .split(',')
+        .map(|p| p.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))?;
+    match parts[..] {
+        [x, y, z] => Ok([x, y, z]),
+        _ => Err(format!("Expected three comma separated numbers for {}, got {}", flag, value)),
+    }
+}
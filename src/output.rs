@@ -0,0 +1,218 @@
+use nalgebra_glm as glm;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::colorspace::{self, ColorSpace};
+use crate::tonemap::Display;
+
+// A buffer of linear values to save, under a name and with one or three channels per pixel.
+#[derive(Clone)]
+pub struct Layer {
+    pub name: String,
+    pub channels: Vec<&'static str>,
+    // The channels of the first pixel, then of the next, row by row from the top.
+    pub values: Vec<f32>,
+}
+
+impl Layer {
+    pub fn rgb(name: &str, pixels: &[glm::TVec3<f64>]) -> Self {
+        Layer {
+            name: name.to_string(),
+            channels: vec!["R", "G", "B"],
+            values: pixels.iter().flat_map(|p| p.iter().map(|&c| c as f32)).collect(),
+        }
+    }
+
+    // Vectors, like normals and positions, as their coordinates.
+    pub fn xyz(name: &str, vectors: &[glm::TVec3<f64>]) -> Self {
+        Layer { channels: vec!["X", "Y", "Z"], ..Layer::rgb(name, vectors) }
+    }
+
+    pub fn gray(name: &str, values: &[f64]) -> Self {
+        Layer { name: name.to_string(), channels: vec!["Y"], values: values.iter().map(|&v| v as f32).collect() }
+    }
+
+    // Whether the layer holds light, which is shown like the image, rather than other data.
+    fn is_color(&self) -> bool {
+        self.channels == ["R", "G", "B"]
+    }
+
+    // The layer with its light converted from the working space to `space`, and other data as is.
+    fn in_space(&self, space: ColorSpace) -> Layer {
+        let working = colorspace::working();
+        if !self.is_color() || working == space {
+            return self.clone();
+        }
+        let conversion = working.conversion(space);
+        let values = rgb(self)
+            .flat_map(|[r, g, b]| {
+                let color = conversion * glm::vec3(r as f64, g as f64, b as f64);
+                [color.x as f32, color.y as f32, color.z as f32]
+            })
+            .collect();
+        Layer { values, ..self.clone() }
+    }
+
+    fn channel(&self, index: usize) -> impl Iterator<Item = f32> + '_ {
+        self.values.iter().skip(index).step_by(self.channels.len()).copied()
+    }
+}
+
+// Saves an image of `width` by `height` pixels in the format the extension of `path` asks for.
+// OpenEXR keeps all the layers in one file, the first as the main color channels and the rest as
+// channels prefixed with their name. Other formats save the first layer to `path` and each of the
+// others next to it, with the layer's name before the extension. Radiance HDR and PFM files keep
+// light linear in the display's color space, while other formats show it through the display
+// transform as 8 bit, and other data scaled from its smallest value to its largest.
+pub fn save(path: &str, width: u32, height: u32, layers: &[Layer], display: &Display) -> Result<(), String> {
+    if keeps_layers(path) {
+        let layers: Vec<Layer> = layers.iter().map(|layer| layer.in_space(display.space)).collect();
+        return save_exr(path, width, height, &layers, display.space).map_err(|e| format!("Could not save {}: {}", path, e));
+    }
+    for (index, layer) in layers.iter().enumerate() {
+        let path = if index == 0 { path.to_string() } else { layer_path(path, &layer.name) };
+        let result = match extension(&path).as_deref() {
+            Some("hdr") => save_hdr(&path, width, height, &layer.in_space(display.space)),
+            Some("pfm") => save_pfm(&path, width, height, &layer.in_space(display.space)),
+            _ if layer.is_color() => save_display(&path, width, height, layer, display),
+            _ => save_data(&path, width, height, layer),
+        };
+        result.map_err(|e| format!("Could not save {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Whether files saved to `path` keep light linear, as floats.
+pub fn is_linear(path: &str) -> bool {
+    matches!(extension(path).as_deref(), Some("exr" | "hdr" | "pfm"))
+}
+
+// Whether files saved to `path` keep every layer in themselves.
+pub fn keeps_layers(path: &str) -> bool {
+    extension(path).as_deref() == Some("exr")
+}
+
+fn extension(path: &str) -> Option<String> {
+    Path::new(path).extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase())
+}
+
+// Where a layer of an image saved to `path` goes when the format can't hold more than one.
+fn layer_path(path: &str, name: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let file = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.{}.{}", stem, name, extension),
+        None => format!("{}.{}", stem, name),
+    };
+    path.with_file_name(file).to_string_lossy().into_owned()
+}
+
+fn save_exr(path: &str, width: u32, height: u32, layers: &[Layer], space: ColorSpace) -> Result<(), String> {
+    use exr::prelude::*;
+    let mut channels = SmallVec::new();
+    for (index, layer) in layers.iter().enumerate() {
+        for (c, &channel) in layer.channels.iter().enumerate() {
+            let name = if index == 0 { channel.to_string() } else { format!("{}.{}", layer.name, channel) };
+            channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(layer.channel(c).collect())));
+        }
+    }
+    let layer = exr::image::Layer::new(
+        (width as usize, height as usize),
+        LayerAttributes::named(layers[0].name.as_str()),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    let mut image = Image::from_layer(layer);
+    // Readers assume the primaries of sRGB without this.
+    let [red, green, blue, white] = space.chromaticities().map(|xy| Vec2(xy.x as f32, xy.y as f32));
+    image.attributes.chromaticities = Some(exr::meta::attribute::Chromaticities { red, green, blue, white });
+    image.write().to_file(path).map_err(|e| e.to_string())
+}
+
+fn save_hdr(path: &str, width: u32, height: u32, layer: &Layer) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let pixels: Vec<image::Rgb<f32>> = rgb(layer).map(image::Rgb).collect();
+    image::codecs::hdr::HdrEncoder::new(BufWriter::new(file))
+        .encode(&pixels, width as usize, height as usize)
+        .map_err(|e| e.to_string())
+}
+
+// Portable float maps are a header and then the raw floats, with rows from the bottom up and a
+// negative scale marking them as little endian.
+fn save_pfm(path: &str, width: u32, height: u32, layer: &Layer) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+    let pixels: Vec<[f32; 3]> = rgb(layer).collect();
+    let write = |writer: &mut BufWriter<File>| -> std::io::Result<()> {
+        write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+        for row in pixels.chunks(width as usize).rev() {
+            for value in row.iter().flatten() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    };
+    write(&mut writer).map_err(|e| e.to_string())
+}
+
+fn save_display(path: &str, width: u32, height: u32, layer: &Layer, display: &Display) -> Result<(), String> {
+    let colors: Vec<glm::TVec3<f64>> = rgb(layer).map(|[r, g, b]| glm::vec3(r as f64, g as f64, b as f64)).collect();
+    image::DynamicImage::ImageRgba8(display.image(&colors, width, height)).to_rgb8().save(path).map_err(|e| e.to_string())
+}
+
+fn save_data(path: &str, width: u32, height: u32, layer: &Layer) -> Result<(), String> {
+    let finite = layer.values.iter().copied().filter(|v| v.is_finite());
+    let (min, max) = finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+    let range = (max - min).max(f32::EPSILON);
+    let mut image = image::RgbImage::new(width, height);
+    for (pixel, values) in image.pixels_mut().zip(rgb(layer)) {
+        *pixel = image::Rgb(values.map(|v| ((v - min) / range * 255.0).round().clamp(0.0, 255.0) as u8));
+    }
+    image.save(path).map_err(|e| e.to_string())
+}
+
+// The pixels of the layer as colors, with gray ones spread over all three channels.
+fn rgb(layer: &Layer) -> impl Iterator<Item = [f32; 3]> + '_ {
+    layer.values.chunks(layer.channels.len()).map(|p| match p {
+        [r, g, b] => [*r, *g, *b],
+        _ => [p[0], p[0], p[0]],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads back the header and the pixels of a PFM file, rows from the top like layers keep them.
+    fn load_pfm(path: &str) -> (String, Vec<[f32; 3]>) {
+        let bytes = std::fs::read(path).unwrap();
+        let header_end = bytes.iter().enumerate().filter(|(_, &b)| b == b'\n').nth(2).unwrap().0;
+        let (header, data) = bytes.split_at(header_end + 1);
+        let header = String::from_utf8(header.to_vec()).unwrap();
+        let width: usize = header.lines().nth(1).unwrap().split(' ').next().unwrap().parse().unwrap();
+        let floats: Vec<f32> = data.chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+        let pixels: Vec<[f32; 3]> = floats.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+        let rows: Vec<&[[f32; 3]]> = pixels.chunks(width).rev().collect();
+        (header, rows.concat())
+    }
+
+    #[test]
+    fn pfm_round_trips() {
+        let pixels = [
+            glm::vec3(0.0, 0.25, 0.5),
+            glm::vec3(1.0, 2.0, 3.0),
+            glm::vec3(-1.5, 1e-3, 1e6),
+            glm::vec3(0.125, 0.0, 42.0),
+        ];
+        let path = std::env::temp_dir().join(format!("raytrace-{}.pfm", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_pfm(path, 2, 2, &Layer::rgb("color", &pixels)).unwrap();
+        let (header, loaded) = load_pfm(path);
+        std::fs::remove_file(path).unwrap();
+        // A negative scale marks the floats as little endian.
+        assert_eq!(header, "PF\n2 2\n-1.0\n");
+        let expected: Vec<[f32; 3]> = pixels.iter().map(|p| [p.x as f32, p.y as f32, p.z as f32]).collect();
+        assert_eq!(loaded, expected);
+    }
+}
@@ -0,0 +1,288 @@
+use nalgebra_glm as glm;
+
+use crate::camera::Camera;
+use crate::integrator::{Integrator, Source};
+use crate::materials;
+use crate::materials::Material;
+use crate::objects::Hittable;
+use crate::objects;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum;
+use crate::util;
+
+// Limits on how many bounces of each kind a path may take. Past `roulette_depth` bounces, paths
+// carrying little light are terminated at random, with the survivors weighted up to compensate.
+#[derive(Debug, Clone, Copy)]
+pub struct PathDepths {
+    pub diffuse: u32,
+    pub specular: u32,
+    pub transmission: u32,
+    // Scattering events inside a medium.
+    pub volume: u32,
+    pub roulette_depth: u32,
+}
+
+// The diffuse bounce that produced a ray, used to weight whatever light the ray finds against
+// the light samples taken at that bounce.
+pub struct Bounce {
+    pub p: glm::TVec3<f64>,
+    pub normal: glm::TVec3<f64>,
+    pub pdf: f64,
+}
+
+// In spectral mode every path carries a single wavelength, which the colors it meets are
+// uplifted to, so glass can bend each wavelength differently.
+pub struct PathIntegrator {
+    pub depths: PathDepths,
+    pub spectral: bool,
+}
+
+impl Integrator for PathIntegrator {
+    fn name(&self) -> &'static str {
+        "path"
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, _camera: &Camera, sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        self.trace(r, scene, sampler, |_, _, _| {})
+    }
+
+    fn li_by_source(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        _camera: &Camera,
+        sampler: &mut dyn Sampler,
+        record: &mut dyn FnMut(Source, bool, &glm::TVec3<f64>),
+    ) -> glm::TVec3<f64> {
+        self.trace(r, scene, sampler, record)
+    }
+
+    fn splits_light(&self) -> bool {
+        true
+    }
+}
+
+impl PathIntegrator {
+    fn trace(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        mut record: impl FnMut(Source, bool, &glm::TVec3<f64>),
+    ) -> glm::TVec3<f64> {
+        let depths = &self.depths;
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+        let mut ray = Ray::new(r.origin, r.direction);
+        let wavelength = if self.spectral {
+            Some(spectrum::sample_wavelength(sampler.get_1d()).0)
+        } else {
+            None
+        };
+        ray.wavelength = wavelength;
+        let mut bounce: Option<Bounce> = None;
+        // The medium of the object the ray is travelling through.
+        let mut medium: Option<materials::Medium> = None;
+        let (mut diffuse, mut specular, mut transmission, mut volume) = (0, 0, 0, 0);
+        // Diffuse bounces and scattering off particles so far, which make light indirect past one.
+        let mut scatterings = 0;
+        let mut report = |source: Source, direct: bool, light: glm::TVec3<f64>| {
+            let light = match wavelength {
+                Some(wavelength) => spectrum::to_rgb(light.x, wavelength),
+                None => light,
+            };
+            record(source, direct, &light);
+        };
+
+        loop {
+            let hit = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(hit) => hit,
+                None => {
+                    let mut emitted = spectrum::at(&scene.background.color(&ray.direction), wavelength);
+                    if let Some(bounce) = &bounce {
+                        emitted *= util::power_heuristic(bounce.pdf, scene.background.pdf(&ray.direction));
+                    }
+                    color += throughput.component_mul(&emitted);
+                    report(Source::Background, scatterings <= 1, throughput.component_mul(&emitted));
+                    break;
+                }
+            };
+
+            if let Some(inside) = &medium {
+                if let Some(scattered) = sample_medium(inside, &ray, hit.t, &mut throughput, sampler) {
+                    volume += 1;
+                    scatterings += 1;
+                    if volume > depths.volume || !roulette(&mut throughput, diffuse + specular + transmission + volume, depths, sampler) {
+                        break;
+                    }
+                    bounce = None;
+                    ray = Ray { wavelength, ..scattered };
+                    continue;
+                }
+            }
+
+            let mut emitted = spectrum::at(&hit.material.emitted(&ray, &hit), wavelength);
+            if let Some(bounce) = &bounce {
+                if emitted != glm::vec3(0.0, 0.0, 0.0) {
+                    if let Some((light, pmf)) = scene.lights.pmf(&bounce.p, &bounce.normal, materials::id(&hit.material)) {
+                        let light_pdf = pmf * light.pdf(&bounce.p, &ray.direction);
+                        emitted *= util::power_heuristic(bounce.pdf, light_pdf);
+                    }
+                }
+            }
+            color += throughput.component_mul(&emitted);
+            if emitted != glm::vec3(0.0, 0.0, 0.0) {
+                let source = scene.lights.index_of(materials::id(&hit.material)).map_or(Source::Background, Source::Light);
+                report(source, scatterings <= 1, throughput.component_mul(&emitted));
+            }
+
+            // Objects filled with a medium are entered through their boundary instead of being
+            // scattered off.
+            let boundary = hit.material.medium();
+            let scatter = match &boundary {
+                Some(boundary) => materials::Dielectric { ir: boundary.ir, dispersion: None, film: None }.scatter(&ray, &hit, sampler),
+                None => hit.material.scatter(&ray, &hit, sampler),
+            };
+            let (scattered, attenuation) = match scatter {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let pdf = if boundary.is_some() { 0.0 } else { hit.material.scattering_pdf(&ray, &hit, &scattered) };
+            if pdf > 0.0 {
+                let background = sample_background(&ray, &hit, scene, sampler);
+                let light = sample_light(&ray, &hit, scene, sampler);
+                let direct = background + light.map_or(glm::vec3(0.0, 0.0, 0.0), |(_, radiance)| radiance);
+                color += throughput.component_mul(&direct);
+                report(Source::Background, scatterings == 0, throughput.component_mul(&background));
+                if let Some((index, radiance)) = light {
+                    report(Source::Light(index), scatterings == 0, throughput.component_mul(&radiance));
+                }
+                diffuse += 1;
+                scatterings += 1;
+                if diffuse > depths.diffuse {
+                    break;
+                }
+                bounce = Some(Bounce { p: hit.p, normal: hit.normal, pdf });
+            } else if let Some(boundary) = boundary {
+                // Crossing or reflecting off the boundary of a medium is part of the walk through it.
+                volume += 1;
+                if volume > depths.volume {
+                    break;
+                }
+                if glm::dot(&scattered.direction, &hit.normal) < 0.0 {
+                    medium = hit.front_face.then(|| materials::Medium {
+                        sigma_t: spectrum::at(&boundary.sigma_t, wavelength),
+                        albedo: spectrum::at(&boundary.albedo, wavelength),
+                        ..boundary
+                    });
+                }
+                bounce = None;
+            } else {
+                // The normal faces the incoming ray, so scattering below it means passing through.
+                if glm::dot(&scattered.direction, &hit.normal) < 0.0 {
+                    transmission += 1;
+                    if transmission > depths.transmission {
+                        break;
+                    }
+                } else {
+                    specular += 1;
+                    if specular > depths.specular {
+                        break;
+                    }
+                }
+                bounce = None;
+            }
+
+            throughput = throughput.component_mul(&spectrum::at(&attenuation, wavelength));
+            if !roulette(&mut throughput, diffuse + specular + transmission + volume, depths, sampler) {
+                break;
+            }
+            ray = Ray { wavelength, ..scattered };
+        }
+        match wavelength {
+            Some(wavelength) => spectrum::to_rgb(color.x, wavelength),
+            None => color,
+        }
+    }
+}
+
+// Whether a path that took `bounces` bounces survives Russian roulette, weighting it up if so.
+fn roulette(throughput: &mut glm::TVec3<f64>, bounces: u32, depths: &PathDepths, sampler: &mut dyn Sampler) -> bool {
+    if bounces <= depths.roulette_depth {
+        return true;
+    }
+    let survival = throughput.max().min(0.95);
+    if survival <= 0.0 || sampler.get_1d() > survival {
+        return false;
+    }
+    *throughput /= survival;
+    true
+}
+
+// Follows `r` through `medium` towards the surface it hits at `t_hit`, returning the ray
+// scattered off a particle if it hits one first. A channel picks the distance, weighted against
+// the chance of the others picking it, and `throughput` takes the transmittance on the way.
+pub fn sample_medium(
+    medium: &materials::Medium,
+    r: &Ray,
+    t_hit: f64,
+    throughput: &mut glm::TVec3<f64>,
+    sampler: &mut dyn Sampler,
+) -> Option<Ray> {
+    let length = r.direction.magnitude();
+    let u = sampler.get_2d();
+    let channel = ((u.x * 3.0) as usize).min(2);
+    let distance = -(1.0 - u.y).ln() / medium.sigma_t[channel];
+    let surface = t_hit * length;
+    let transmittance = medium.sigma_t.map(|sigma_t| (-sigma_t * distance.min(surface)).exp());
+    if distance < surface {
+        let density = medium.sigma_t.component_mul(&transmittance);
+        let pdf = (density.x + density.y + density.z) / 3.0;
+        *throughput = throughput.component_mul(&density.component_mul(&medium.albedo)) / pdf;
+        let direction = util::uniform_sphere(sampler.get_2d());
+        Some(Ray::new(r.at(distance / length), direction))
+    } else {
+        let pdf = (transmittance.x + transmittance.y + transmittance.z) / 3.0;
+        *throughput = throughput.component_mul(&transmittance) / pdf;
+        None
+    }
+}
+
+pub fn sample_background(r: &Ray, hit: &objects::HitRecord, scene: &Scene, sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+    let u = sampler.get_2d();
+    if let Some((direction, radiance, light_pdf)) = scene.background.sample(u) {
+        let shadow_ray = Ray::new(hit.p, direction);
+        let scattering_pdf = hit.material.scattering_pdf(r, hit, &shadow_ray);
+        if scattering_pdf > 0.0 && scene.world.hit(&shadow_ray, 0.001, f64::MAX).is_none() {
+            let weight = util::power_heuristic(light_pdf, scattering_pdf);
+            let f = spectrum::at(&hit.material.eval(r, hit, &shadow_ray), r.wavelength);
+            return f.component_mul(&spectrum::at(&radiance, r.wavelength)) * weight / light_pdf;
+        }
+    }
+    glm::vec3(0.0,0.0,0.0)
+}
+
+// Light from one of the scene's lights, picked by how much it is likely to contribute, along with
+// the index of the light. Nothing is returned where there are no lights to pick.
+pub fn sample_light(r: &Ray, hit: &objects::HitRecord, scene: &Scene, sampler: &mut dyn Sampler) -> Option<(usize, glm::TVec3<f64>)> {
+    let (index, pmf) = scene.lights.sample(&hit.p, &hit.normal, sampler.get_1d())?;
+    let light = &scene.lights.lights()[index];
+    let u = sampler.get_2d();
+    if let Some(sample) = light.sample(&hit.p, u) {
+        let shadow_ray = Ray::new(hit.p, sample.direction);
+        let scattering_pdf = hit.material.scattering_pdf(r, hit, &shadow_ray);
+        if scattering_pdf > 0.0 && sample.pdf > 0.0 && scene.world.hit(&shadow_ray, 0.001, sample.distance - 0.001).is_none() {
+            let light_pdf = pmf * sample.pdf;
+            let weight = if light.is_delta() {
+                1.0
+            } else {
+                util::power_heuristic(light_pdf, scattering_pdf)
+            };
+            let f = spectrum::at(&hit.material.eval(r, hit, &shadow_ray), r.wavelength);
+            return Some((index, f.component_mul(&spectrum::at(&sample.radiance, r.wavelength)) * weight / light_pdf));
+        }
+    }
+    Some((index, glm::vec3(0.0,0.0,0.0)))
+}
@@ -0,0 +1,274 @@
+use nalgebra_glm as glm;
+use std::f64::consts::PI;
+
+use crate::camera::Camera;
+use crate::integrator::Integrator;
+use crate::materials;
+use crate::objects;
+use crate::objects::Hittable;
+use crate::path;
+use crate::photon_map::{Photon, PhotonMap};
+use crate::ray::Ray;
+use crate::sampler::{self, Sampler};
+use crate::scene::Scene;
+use crate::util;
+
+// How quickly the gather radius shrinks between passes, trading noise for bias.
+const ALPHA: f64 = 2.0 / 3.0;
+
+// Path tracing with caustics taken from a photon map. Each pass traces a new set of photons
+// through mirrors and glass and stores them where they land on a diffuse surface; light reaching
+// a diffuse surface that way is then estimated from the photons around it instead of the rare
+// paths that find it. The gather radius shrinks with every pass accumulated into the same image,
+// making this progressive photon mapping.
+pub struct PhotonIntegrator {
+    depths: path::PathDepths,
+    photons: usize,
+    initial_radius: f64,
+    radius: f64,
+    map: PhotonMap,
+    background_photons: bool,
+    seed: u64,
+}
+
+impl PhotonIntegrator {
+    pub fn new(depths: path::PathDepths, photons: usize, radius: f64, seed: u64) -> Self {
+        PhotonIntegrator {
+            depths,
+            photons,
+            initial_radius: radius,
+            radius,
+            map: PhotonMap::new(Vec::new()),
+            background_photons: false,
+            seed,
+        }
+    }
+
+    fn trace_photons(&mut self, scene: &Scene, pass: u64) -> Vec<Photon> {
+        let mut photons = Vec::new();
+        let has_lights = !scene.lights.lights().is_empty();
+        let target = scene.world.specular_bounds();
+        self.background_photons = target.is_some() && scene.background.sample_photon(glm::vec2(0.5, 0.5)).is_some();
+        if !has_lights && !self.background_photons {
+            return photons;
+        }
+        // Half the photons come from the background when both it and the lights can emit them.
+        let light_probability = if !has_lights {
+            0.0
+        } else if self.background_photons {
+            0.5
+        } else {
+            1.0
+        };
+        let mut sampler = sampler::Independent::new(self.seed);
+        for i in 0..self.photons {
+            // Photons are numbered on from the previous passes, so every pass traces new ones.
+            sampler.start_pixel_sample((0, 0), pass * self.photons as u64 + i as u64);
+            let emitted = if sampler.get_1d() < light_probability {
+                emit_from_light(scene, &mut sampler).map(|(ray, power)| (ray, power / light_probability))
+            } else {
+                emit_from_background(scene, target.as_ref().unwrap(), &mut sampler)
+                    .map(|(ray, power)| (ray, power / (1.0 - light_probability)))
+            };
+            if let Some((ray, power)) = emitted {
+                self.trace_photon(scene, ray, power / self.photons as f64, &mut photons, &mut sampler);
+            }
+        }
+        photons
+    }
+
+    // Follows a photon through mirrors and glass, keeping it if it reaches a diffuse surface.
+    fn trace_photon(
+        &self,
+        scene: &Scene,
+        ray: Ray,
+        power: glm::TVec3<f64>,
+        photons: &mut Vec<Photon>,
+        sampler: &mut dyn Sampler,
+    ) {
+        let mut ray = ray;
+        let mut power = power;
+        let (mut specular, mut transmission) = (0, 0);
+        while let Some(hit) = scene.world.hit(&ray, 0.001, f64::MAX) {
+            let (scattered, attenuation) = match hit.material.scatter(&ray, &hit, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            if hit.material.scattering_pdf(&ray, &hit, &scattered) > 0.0 {
+                if specular + transmission > 0 {
+                    photons.push(Photon { position: hit.p, direction: glm::normalize(&ray.direction), power });
+                }
+                break;
+            }
+            if glm::dot(&scattered.direction, &hit.normal) < 0.0 {
+                transmission += 1;
+                if transmission > self.depths.transmission {
+                    break;
+                }
+            } else {
+                specular += 1;
+                if specular > self.depths.specular {
+                    break;
+                }
+            }
+            power = power.component_mul(&attenuation);
+            if specular + transmission > self.depths.roulette_depth {
+                let survival = attenuation.max().min(0.95);
+                if survival <= 0.0 || sampler.get_1d() > survival {
+                    break;
+                }
+                power /= survival;
+            }
+            ray = scattered;
+        }
+    }
+
+    // Caustic light leaving `hit` back along `r`, from the photons within the gather radius.
+    fn caustics(&self, r: &Ray, hit: &objects::HitRecord) -> glm::TVec3<f64> {
+        let mut sum = glm::vec3(0.0, 0.0, 0.0);
+        self.map.for_each_within(&hit.p, self.radius, &mut |photon| {
+            let towards_light = -photon.direction;
+            let cosine = glm::dot(&towards_light, &hit.normal);
+            if cosine > 0.0 {
+                let f = hit.material.eval(r, hit, &Ray::new(hit.p, towards_light)) / cosine;
+                sum += f.component_mul(&photon.power);
+            }
+        });
+        sum / (PI * self.radius * self.radius)
+    }
+}
+
+impl Integrator for PhotonIntegrator {
+    fn name(&self) -> &'static str {
+        "photon"
+    }
+
+    fn preprocess(&mut self, scene: &Scene, _camera: &Camera, pass: u64) {
+        let mut radius_squared = self.initial_radius * self.initial_radius;
+        for i in 1..=pass {
+            radius_squared *= (i as f64 - 1.0 + ALPHA) / i as f64;
+        }
+        self.radius = radius_squared.sqrt();
+        let photons = self.trace_photons(scene, pass);
+        self.map = PhotonMap::new(photons);
+    }
+
+    fn li(&self, r: &Ray, scene: &Scene, _camera: &Camera, sampler: &mut dyn Sampler) -> glm::TVec3<f64> {
+        let depths = &self.depths;
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+        let mut ray = Ray::new(r.origin, r.direction);
+        let mut bounce: Option<path::Bounce> = None;
+        // Whether the ray left a mirror or glass on its way from a diffuse surface, so any light
+        // it finds that the photons also carry is already part of the caustics there.
+        let mut caustic = false;
+        let (mut diffuse, mut specular, mut transmission) = (0, 0, 0);
+
+        loop {
+            let hit = match scene.world.hit(&ray, 0.001, f64::MAX) {
+                Some(hit) => hit,
+                None => {
+                    let mut emitted = scene.background.color(&ray.direction);
+                    if let Some(bounce) = &bounce {
+                        emitted *= util::power_heuristic(bounce.pdf, scene.background.pdf(&ray.direction));
+                    } else if caustic && self.background_photons && scene.background.photon_pdf(&ray.direction) > 0.0 {
+                        emitted = glm::vec3(0.0, 0.0, 0.0);
+                    }
+                    color += throughput.component_mul(&emitted);
+                    break;
+                }
+            };
+
+            let mut emitted = hit.material.emitted(&ray, &hit);
+            if emitted != glm::vec3(0.0, 0.0, 0.0) {
+                let id = materials::id(&hit.material);
+                if let Some(bounce) = &bounce {
+                    if let Some((light, pmf)) = scene.lights.pmf(&bounce.p, &bounce.normal, id) {
+                        let light_pdf = pmf * light.pdf(&bounce.p, &ray.direction);
+                        emitted *= util::power_heuristic(bounce.pdf, light_pdf);
+                    }
+                } else if caustic && scene.lights.index_of(id).is_some() {
+                    emitted = glm::vec3(0.0, 0.0, 0.0);
+                }
+            }
+            color += throughput.component_mul(&emitted);
+
+            let (scattered, attenuation) = match hit.material.scatter(&ray, &hit, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let pdf = hit.material.scattering_pdf(&ray, &hit, &scattered);
+            if pdf > 0.0 {
+                let background = path::sample_background(&ray, &hit, scene, sampler);
+                let direct = background + path::sample_light(&ray, &hit, scene, sampler).map_or(glm::vec3(0.0, 0.0, 0.0), |(_, light)| light);
+                color += throughput.component_mul(&(direct + self.caustics(&ray, &hit)));
+                diffuse += 1;
+                if diffuse > depths.diffuse {
+                    break;
+                }
+                bounce = Some(path::Bounce { p: hit.p, normal: hit.normal, pdf });
+                caustic = false;
+            } else {
+                if glm::dot(&scattered.direction, &hit.normal) < 0.0 {
+                    transmission += 1;
+                    if transmission > depths.transmission {
+                        break;
+                    }
+                } else {
+                    specular += 1;
+                    if specular > depths.specular {
+                        break;
+                    }
+                }
+                bounce = None;
+                caustic = diffuse > 0;
+            }
+
+            throughput = throughput.component_mul(&attenuation);
+            if diffuse + specular + transmission > depths.roulette_depth {
+                let survival = throughput.max().min(0.95);
+                if survival <= 0.0 || sampler.get_1d() > survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+            ray = scattered;
+        }
+        color
+    }
+}
+
+fn emit_from_light(scene: &Scene, sampler: &mut dyn Sampler) -> Option<(Ray, glm::TVec3<f64>)> {
+    let (index, pmf) = scene.lights.sample_by_power(sampler.get_1d())?;
+    let emission = scene.lights.lights()[index].sample_le(sampler.get_2d(), sampler.get_2d())?;
+    if pmf <= 0.0 || emission.pdf_position <= 0.0 || emission.pdf_direction <= 0.0 {
+        return None;
+    }
+    let cosine = if emission.normal == glm::vec3(0.0, 0.0, 0.0) {
+        1.0
+    } else {
+        glm::dot(&emission.normal, &glm::normalize(&emission.ray.direction)).abs()
+    };
+    let power = emission.radiance * cosine / (pmf * emission.pdf_position * emission.pdf_direction);
+    Some((emission.ray, power))
+}
+
+// Light from the background arrives from infinitely far away, so photons start on a disk
+// facing the sampled direction and covering the bounds of the specular objects.
+fn emit_from_background(scene: &Scene, target: &objects::Aabb, sampler: &mut dyn Sampler) -> Option<(Ray, glm::TVec3<f64>)> {
+    let (direction, radiance, pdf) = scene.background.sample_photon(sampler.get_2d())?;
+    if pdf <= 0.0 {
+        return None;
+    }
+    let direction = glm::normalize(&direction);
+    let centre = (target.min + target.max) * 0.5;
+    let radius = (target.max - target.min).magnitude() * 0.5;
+    let (a, b) = util::orthonormal_basis(&direction);
+    let disk = util::unit_disk(sampler.get_2d()) * radius;
+    let origin = centre + direction * radius + a * disk.x + b * disk.y;
+    // Whatever lies beyond the disk keeps the light from reaching it.
+    if scene.world.hit(&Ray::new(origin, direction), 0.001, f64::MAX).is_some() {
+        return None;
+    }
+    Some((Ray::new(origin, -direction), radiance * PI * radius * radius / pdf))
+}
@@ -0,0 +1,73 @@
+use nalgebra_glm as glm;
+
+// Light carried to a surface by a path traced from a light.
+#[derive(Clone, Copy)]
+pub struct Photon {
+    pub position: glm::TVec3<f64>,
+    // Direction the photon was travelling in when it arrived.
+    pub direction: glm::TVec3<f64>,
+    pub power: glm::TVec3<f64>,
+}
+
+// Photons in a balanced kd-tree stored in place: every range of the vector is split at its middle
+// photon, along the axis recorded for it, with the photons before it on the lower side.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>) -> Self {
+        let mut photons = photons;
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    // Calls `f` with every photon within `radius` of `p`.
+    pub fn for_each_within(&self, p: &glm::TVec3<f64>, radius: f64, f: &mut impl FnMut(&Photon)) {
+        self.search(0, self.photons.len(), p, radius * radius, f);
+    }
+
+    fn search(&self, start: usize, end: usize, p: &glm::TVec3<f64>, radius_squared: f64, f: &mut impl FnMut(&Photon)) {
+        if start >= end {
+            return;
+        }
+        let middle = start + (end - start) / 2;
+        let photon = &self.photons[middle];
+        if glm::distance2(p, &photon.position) <= radius_squared {
+            f(photon);
+        }
+        let axis = self.axes[middle];
+        let offset = p[axis] - photon.position[axis];
+        let (near, far) = if offset < 0.0 {
+            ((start, middle), (middle + 1, end))
+        } else {
+            ((middle + 1, end), (start, middle))
+        };
+        self.search(near.0, near.1, p, radius_squared, f);
+        if offset * offset <= radius_squared {
+            self.search(far.0, far.1, p, radius_squared, f);
+        }
+    }
+}
+
+// Splits along the axis the photons are most spread out on.
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.is_empty() {
+        return;
+    }
+    let (mut min, mut max) = (photons[0].position, photons[0].position);
+    for photon in photons.iter() {
+        min = glm::min2(&min, &photon.position);
+        max = glm::max2(&max, &photon.position);
+    }
+    let axis = (max - min).imax();
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+    axes[middle] = axis;
+    let (lower, upper) = photons.split_at_mut(middle);
+    let (lower_axes, upper_axes) = axes.split_at_mut(middle);
+    build(lower, lower_axes);
+    build(&mut upper[1..], &mut upper_axes[1..]);
+}
@@ -0,0 +1,169 @@
+use nalgebra_glm as glm;
+
+use crate::colorspace::ColorSpace;
+use crate::sampler;
+use crate::spectrum;
+
+pub const NAMES: [&str; 6] = ["bloom", "vignette", "distortion", "aberration", "white-balance", "grain"];
+
+// Effects applied to the tone mapped image, in the order they are given, like a camera and its
+// lens would.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    // Light brighter than white spilling over its surroundings, by how much of it does.
+    Bloom(f64),
+    // Darkening towards the corners, by how much darker they get.
+    Vignette(f64),
+    // Barrel distortion, or pincushion when negative, by how much more the centre is magnified
+    // than the corners, which stay where they are.
+    Distortion(f64),
+    // Red and blue pulled apart towards the corners, by how many pixels they are apart there.
+    Aberration(f64),
+    // Balances light of a black body at this many kelvin to come out white.
+    WhiteBalance(f64),
+    // Noise like the grain of film, strongest in the midtones, by how strong it is.
+    Grain(f64),
+}
+
+// Reference white of sRGB, D65, as a black body.
+const WHITE_TEMPERATURE: f64 = 6500.0;
+
+// How far bloom spreads, relative to the height of the image.
+const BLOOM_RADIUS: f64 = 0.02;
+
+impl Effect {
+    // An effect given as its name, or its name and amount as `name=amount`.
+    pub fn parse(effect: &str) -> Result<Self, String> {
+        let (name, amount) = match effect.split_once('=') {
+            Some((name, amount)) => {
+                let amount = amount.trim().parse().map_err(|_| format!("Invalid amount for {}: {}", name, amount))?;
+                (name.trim(), Some(amount))
+            }
+            None => (effect.trim(), None),
+        };
+        let effect = match name {
+            "bloom" => Effect::Bloom(amount.unwrap_or(0.3)),
+            "vignette" => Effect::Vignette(amount.unwrap_or(0.3)),
+            "distortion" => Effect::Distortion(amount.unwrap_or(0.1)),
+            "aberration" => Effect::Aberration(amount.unwrap_or(2.0)),
+            "white-balance" => Effect::WhiteBalance(amount.unwrap_or(5000.0)),
+            "grain" => Effect::Grain(amount.unwrap_or(0.05)),
+            _ => return Err(format!("Unknown effect: {}, expected one of {}", name, NAMES.join(", "))),
+        };
+        if let Effect::WhiteBalance(kelvin) = effect {
+            if kelvin < 1000.0 {
+                return Err(format!("White balance must be at least 1000 K, got {}", kelvin));
+            }
+        }
+        Ok(effect)
+    }
+
+    // Applies the effect to the tone mapped linear `image` in `space`, with the light it was tone
+    // mapped from, exposure included, in `hdr`.
+    pub fn apply(&self, image: &mut Vec<glm::TVec3<f64>>, hdr: &[glm::TVec3<f64>], width: u32, height: u32, space: ColorSpace) {
+        let (w, h) = (width as usize, height as usize);
+        match *self {
+            Effect::Bloom(amount) => {
+                let mut glow: Vec<glm::TVec3<f64>> = hdr.iter().map(|c| c.map(|c| (c - 1.0).max(0.0))).collect();
+                // Three box blurs come close to a Gaussian.
+                let radius = (BLOOM_RADIUS * height as f64).round().max(1.0) as usize;
+                for _ in 0..3 {
+                    box_blur(&mut glow, w, h, radius);
+                }
+                for (color, glow) in image.iter_mut().zip(&glow) {
+                    *color += glow * amount;
+                }
+            }
+            Effect::Vignette(amount) => {
+                for (index, color) in image.iter_mut().enumerate() {
+                    let offset = offset(index % w, index / w, w, h);
+                    *color *= (1.0 - amount * offset.magnitude_squared()).max(0.0);
+                }
+            }
+            Effect::Distortion(k) => {
+                *image = remap(image, w, h, |r2| glm::vec3(1.0, 1.0, 1.0) * (1.0 + k * r2) / (1.0 + k));
+            }
+            Effect::Aberration(pixels) => {
+                let shift = pixels / (0.5 * ((w * w + h * h) as f64).sqrt());
+                *image = remap(image, w, h, |_| glm::vec3(1.0 + shift, 1.0, 1.0 - shift));
+            }
+            Effect::WhiteBalance(kelvin) => {
+                let white = |kelvin: f64| ColorSpace::LinearSrgb.convert(&spectrum::blackbody(kelvin), space);
+                let gains = white(WHITE_TEMPERATURE).component_div(&white(kelvin));
+                let gains = gains / space.luminance(&gains);
+                for color in image.iter_mut() {
+                    *color = color.component_mul(&gains);
+                }
+            }
+            Effect::Grain(amount) => {
+                for (index, color) in image.iter_mut().enumerate() {
+                    // Keyed apart from the dither, which hashes the channels 0 to 2.
+                    let bits = sampler::hash(&[(index % w) as u64, (index / w) as u64, 3]);
+                    let noise = (bits >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+                    let luminance = space.luminance(color).clamp(0.0, 1.0);
+                    *color += glm::vec3(1.0, 1.0, 1.0) * (noise * amount * 4.0 * luminance * (1.0 - luminance));
+                }
+            }
+        }
+    }
+}
+
+// Where the centre of pixel (x, y) is from the centre of the image, with the corners one away.
+fn offset(x: usize, y: usize, width: usize, height: usize) -> glm::TVec2<f64> {
+    let half_diagonal = 0.5 * ((width * width + height * height) as f64).sqrt();
+    glm::vec2(x as f64 + 0.5 - 0.5 * width as f64, y as f64 + 0.5 - 0.5 * height as f64) / half_diagonal
+}
+
+// Resamples each channel of `image` from further out from the centre by the factor `scale` gives
+// for it at the squared distance from the centre of the pixel.
+fn remap(image: &[glm::TVec3<f64>], width: usize, height: usize, scale: impl Fn(f64) -> glm::TVec3<f64>) -> Vec<glm::TVec3<f64>> {
+    let centre = glm::vec2(0.5 * width as f64, 0.5 * height as f64);
+    (0..width * height)
+        .map(|index| {
+            let (x, y) = (index % width, index / width);
+            let scales = scale(offset(x, y, width, height).magnitude_squared());
+            let position = glm::vec2(x as f64 + 0.5, y as f64 + 0.5) - centre;
+            let channel = |c: usize| bilinear(image, width, height, centre + position * scales[c])[c];
+            glm::vec3(channel(0), channel(1), channel(2))
+        })
+        .collect()
+}
+
+// The image at `position`, in pixels, interpolated between the four nearest pixel centres and
+// clamped to the edge.
+fn bilinear(image: &[glm::TVec3<f64>], width: usize, height: usize, position: glm::TVec2<f64>) -> glm::TVec3<f64> {
+    let x = (position.x - 0.5).clamp(0.0, (width - 1) as f64);
+    let y = (position.y - 0.5).clamp(0.0, (height - 1) as f64);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (tx, ty) = (x - x0 as f64, y - y0 as f64);
+    let row = |y: usize| image[x0 + y * width] * (1.0 - tx) + image[x1 + y * width] * tx;
+    row(y0) * (1.0 - ty) + row(y1) * ty
+}
+
+// Blurs `image` with a box `radius` pixels either way, first along rows and then along columns,
+// with the edge pixels repeated beyond the edge.
+fn box_blur(image: &mut [glm::TVec3<f64>], width: usize, height: usize, radius: usize) {
+    let blur = |line: &[glm::TVec3<f64>]| -> Vec<glm::TVec3<f64>> {
+        let n = line.len() as i64;
+        let at = |i: i64| line[i.clamp(0, n - 1) as usize];
+        let r = radius as i64;
+        let mut sum = (-r..=r).fold(glm::vec3(0.0, 0.0, 0.0), |sum, i| sum + at(i));
+        let mut blurred = Vec::with_capacity(line.len());
+        for i in 0..n {
+            blurred.push(sum / (2 * r + 1) as f64);
+            sum += at(i + r + 1) - at(i - r);
+        }
+        blurred
+    };
+    for row in image.chunks_mut(width) {
+        let blurred = blur(row);
+        row.copy_from_slice(&blurred);
+    }
+    for x in 0..width {
+        let column: Vec<glm::TVec3<f64>> = (0..height).map(|y| image[x + y * width]).collect();
+        for (y, value) in blur(&column).into_iter().enumerate() {
+            image[x + y * width] = value;
+        }
+    }
+}
@@ -1,38 +1,25 @@
 use nalgebra_glm as glm;
 
-use crate::objects::Hittable;
-use crate::objects;
-
 pub struct Ray {
     pub origin: glm::TVec3<f64>,
-    pub direction: glm::TVec3<f64>
+    pub direction: glm::TVec3<f64>,
+    // Wavelength in nanometres of a path carrying a single one, in spectral rendering.
+    pub wavelength: Option<f64>,
+    // Whether a material scattered the ray off a mirror or through glass, where no other direction
+    // would have done, even if the material has a pdf for the direction otherwise.
+    pub specular: bool,
 }
 
 impl Ray {
     pub fn new(origin: glm::TVec3<f64>, direction: glm::TVec3<f64>) -> Ray {
         Ray {
             origin,
-            direction
+            direction,
+            wavelength: None,
+            specular: false,
         }
     }
     pub fn at(&self, t: f64) -> glm::TVec3<f64> {
         self.origin + self.direction * t
     }
 }
-
-
-
-pub fn ray_color(r: &Ray, world: &objects::HittableList, depth: u8) -> glm::TVec3<f64> {
-    if depth <= 0 {
-        return glm::vec3(0.0,0.0,0.0);
-    }
-    if let Some(hit) = world.hit(r, 0.001, std::f64::MAX) {
-        if let Some((scattered, attenuation)) = hit.material.scatter(&r, &hit) {
-            return attenuation.zip_map(&ray_color(&scattered, &world, depth - 1), |x, y| x * y);
-        }
-        return glm::vec3(0.0,0.0,0.0);
-    }
-    let unit_direction = glm::normalize(&r.direction);
-    let t = 0.5*(unit_direction.y + 1.0);
-    (1.0-t) * glm::vec3(1.0, 1.0, 1.0) + t * glm::vec3(0.5, 0.7, 1.0)
-}
\ No newline at end of file
@@ -0,0 +1,294 @@
+use nalgebra_glm as glm;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::aov::{AovRow, Aovs};
+use crate::camera::Camera;
+use crate::denoise::Guides;
+use crate::color;
+use crate::film::Film;
+use crate::filter;
+use crate::integrator::Integrator;
+use crate::output::Layer;
+use crate::sampler::{self, Sampler};
+use crate::scene::Scene;
+
+// What an offline render is configured with.
+pub struct Settings<'a> {
+    pub sampler: &'a str,
+    pub strata: u32,
+    pub seed: u64,
+    pub samples: u32,
+    pub threads: usize,
+    pub width: u32,
+    pub height: u32,
+    pub filter: &'a str,
+    pub filter_radius: f64,
+    // Takes `samples` in every pixel and then more in the noisy ones, instead of stopping there.
+    pub adaptive: Option<Adaptive>,
+    // Names of the auxiliary buffers to render along with the image.
+    pub aovs: &'a [String],
+    // Whether to keep the buffers the denoiser is guided by.
+    pub guides: bool,
+}
+
+// When adaptive sampling stops sampling a pixel.
+#[derive(Debug, Clone, Copy)]
+pub struct Adaptive {
+    // Standard error of the pixel's mean brightness, relative to the brightness.
+    pub target_error: f64,
+    pub max_samples: u32,
+    // Time after which the render is saved however noisy it still is.
+    pub time_budget: Option<Duration>,
+}
+
+// Pixels darker than this count as this bright when estimating their relative error, or black
+// pixels with the odd bright sample would never be done.
+const DARK_LUMINANCE: f64 = 0.05;
+
+// A finished render, how many samples each of its pixels took, the estimated variance of each
+// pixel's brightness, which is infinite where one sample was all there was to go by, and the
+// auxiliary buffers asked for, along with those the denoiser is guided by if it is to be.
+pub struct Render {
+    pub image: Vec<glm::TVec3<f64>>,
+    pub samples: Vec<u32>,
+    pub variance: Vec<f64>,
+    pub aovs: Vec<Layer>,
+    pub guides: Option<Guides>,
+}
+
+// How many samples were taken in a pixel so far, with the sums their variance is estimated from.
+#[derive(Debug, Default, Clone, Copy)]
+struct PixelStats {
+    luminance_sum: f64,
+    luminance_squares: f64,
+    samples: u32,
+}
+
+impl PixelStats {
+    fn add(&mut self, color: &glm::TVec3<f64>) {
+        let luminance = color::luminance(color);
+        self.luminance_sum += luminance;
+        self.luminance_squares += luminance * luminance;
+        self.samples += 1;
+    }
+
+    // Estimated variance of the mean brightness of the samples.
+    fn variance(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.samples as f64;
+        let mean = self.luminance_sum / n;
+        ((self.luminance_squares - mean * self.luminance_sum) / (n - 1.0)).max(0.0) / n
+    }
+
+    // Estimated standard error of the mean brightness, relative to the brightness.
+    fn error(&self) -> f64 {
+        let mean = self.luminance_sum / self.samples.max(1) as f64;
+        self.variance().sqrt() / mean.max(DARK_LUMINANCE)
+    }
+}
+
+// Traces sample `index` of `pixel`, at a point within the pixel picked by the sampler, returning
+// where on the film it landed and what it saw. What it saw is also added to `aovs`, the
+// auxiliary buffers of the pixel's row, if there are any.
+#[allow(clippy::too_many_arguments)]
+pub fn sample_pixel(
+    scene: &Scene,
+    integrator: &dyn Integrator,
+    camera: &Camera,
+    sampler: &mut dyn Sampler,
+    pixel: (u32, u32),
+    index: u64,
+    size: (u32, u32),
+    aovs: Option<&mut AovRow>,
+) -> (glm::TVec2<f64>, glm::TVec3<f64>) {
+    let (x, y) = pixel;
+    let (width, height) = size;
+    sampler.start_pixel_sample(pixel, index);
+    let position = glm::vec2(x as f64, y as f64) + sampler.get_2d();
+    let screen_coords = glm::vec2(position.x / width as f64, 1. - position.y / height as f64);
+    let ray = camera.get_ray(screen_coords, sampler.get_2d());
+    let aovs = match aovs {
+        Some(aovs) => aovs,
+        None => return (position, integrator.li(&ray, scene, camera, sampler)),
+    };
+    aovs.add_hit(x as usize, &ray, scene.world.hit_object(&ray, 0.001, f64::MAX), index);
+    let color = if aovs.splits_light() {
+        integrator.li_by_source(&ray, scene, camera, sampler, &mut |source, direct, light| aovs.add_light(x as usize, source, direct, light))
+    } else {
+        integrator.li(&ray, scene, camera, sampler)
+    };
+    (position, color)
+}
+
+// Renders the image onto a film in passes, handing out rows to the threads as they finish the
+// last. The first pass takes `samples` in every pixel. With adaptive sampling, each further pass
+// doubles the samples of the pixels whose error is still above the target, until none are left or
+// the time runs out. Every sample draws its random numbers from its own stream, which only depends
+// on the seed, so without a time budget the image comes out the same on any number of threads.
+// Integrators that can only be run in order get a single thread.
+pub fn render(scene: &Scene, camera: &Camera, integrator: &mut dyn Integrator, settings: &Settings) -> Render {
+    let (width, height) = (settings.width, settings.height);
+    let deadline = settings.adaptive.and_then(|adaptive| adaptive.time_budget).map(|budget| Instant::now() + budget);
+    // Integrators are prepared once for the whole render, so the photon mapper traces one photon
+    // map and gathers it at `--photon-radius` throughout, rather than shrinking the radius as the
+    // viewer does.
+    integrator.preprocess(scene, camera, 0);
+    let integrator: &dyn Integrator = integrator;
+    let threads = if integrator.is_sequential() { 1 } else { settings.threads };
+    let pixels = Mutex::new(vec![PixelStats::default(); (width * height) as usize]);
+    let film = Mutex::new(Film::new(width, height, filter::by_name(settings.filter, settings.filter_radius).unwrap()));
+    let aovs = Mutex::new(Aovs::new(settings.aovs, width, height, scene.lights.lights().len(), integrator.splits_light(), settings.guides));
+    let has_aovs = !aovs.lock().unwrap().is_empty();
+    // Samples to take in each pixel this pass.
+    let mut batch = vec![settings.samples; (width * height) as usize];
+    // The first pass is always finished, so every pixel has some samples.
+    let mut first_pass = true;
+    loop {
+        let next_row = AtomicU32::new(0);
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut sampler = sampler::by_name(settings.sampler, settings.strata, settings.seed).unwrap();
+                    loop {
+                        let y = next_row.fetch_add(1, Ordering::Relaxed);
+                        let out_of_time = !first_pass && deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                        if y >= height || out_of_time {
+                            break;
+                        }
+                        let start = (y * width) as usize;
+                        let mut row = pixels.lock().unwrap()[start..start + width as usize].to_vec();
+                        let mut aov_row = has_aovs.then(|| aovs.lock().unwrap().row(y));
+                        let mut samples = Vec::new();
+                        for (x, stats) in row.iter_mut().enumerate() {
+                            let first = stats.samples as u64;
+                            for i in first..first + batch[start + x] as u64 {
+                                let pixel = (x as u32, y);
+                                let size = (width, height);
+                                let sample = sample_pixel(scene, integrator, camera, sampler.as_mut(), pixel, i, size, aov_row.as_mut());
+                                stats.add(&sample.1);
+                                samples.push(sample);
+                            }
+                        }
+                        pixels.lock().unwrap()[start..start + width as usize].copy_from_slice(&row);
+                        if let Some(aov_row) = &aov_row {
+                            aovs.lock().unwrap().set_row(y, aov_row);
+                        }
+                        let mut film = film.lock().unwrap();
+                        for (position, color) in &samples {
+                            film.add_sample(position, color);
+                        }
+                        for (screen_coords, splat) in integrator.take_splats() {
+                            film.add_splat(&screen_coords, &splat);
+                        }
+                    }
+                });
+            }
+        });
+        let adaptive = match settings.adaptive {
+            Some(adaptive) if deadline.is_none_or(|deadline| Instant::now() < deadline) => adaptive,
+            _ => break,
+        };
+        let pixels = pixels.lock().unwrap();
+        for (samples, stats) in batch.iter_mut().zip(pixels.iter()) {
+            *samples = if stats.error() > adaptive.target_error {
+                stats.samples.min(adaptive.max_samples - stats.samples)
+            } else {
+                0
+            };
+        }
+        if batch.iter().all(|&samples| samples == 0) {
+            break;
+        }
+        first_pass = false;
+    }
+    let pixels = pixels.into_inner().unwrap();
+    let film = film.into_inner().unwrap();
+    // Splats are gathered over every sample of the image, and spread over all its pixels.
+    let total_samples: u64 = pixels.iter().map(|stats| stats.samples as u64).sum();
+    let splat_scale = (width * height) as f64 / total_samples.max(1) as f64;
+    let image = (0..pixels.len()).map(|index| film.pixel(index, splat_scale)).collect();
+    let aovs = aovs.into_inner().unwrap();
+    Render {
+        image,
+        samples: pixels.iter().map(|stats| stats.samples).collect(),
+        variance: pixels.iter().map(|stats| stats.variance()).collect(),
+        guides: settings.guides.then(|| aovs.guides()),
+        aovs: aovs.into_layers(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background;
+    use crate::colorspace::ColorSpace;
+    use crate::integrator;
+    use crate::light_bvh::LightBvh;
+    use crate::lights::{Light, SphereLight};
+    use crate::materials;
+    use crate::objects::HittableList;
+    use crate::path::PathDepths;
+    use crate::sphere::Sphere;
+    use std::sync::Arc;
+
+    fn scene() -> Scene {
+        let mut world = HittableList::default();
+        world.push(Sphere::new(glm::vec3(0.0, -1000.0, 0.0), 1000.0, materials::Lambertian::new(glm::vec3(0.5, 0.5, 0.5))));
+        world.push(Sphere::new(glm::vec3(0.0, 1.0, 0.0), 1.0, materials::Dielectric::new(1.5)));
+        world.push(Sphere::new(glm::vec3(2.0, 1.0, 0.0), 1.0, materials::Metal::new(glm::vec3(0.7, 0.6, 0.5), 0.1)));
+        let light = SphereLight::new(glm::vec3(-2.0, 3.0, 1.0), 0.5, glm::vec3(4.0, 4.0, 4.0));
+        world.push(light.sphere());
+        let lights: Vec<Arc<dyn Light>> = vec![Arc::new(light)];
+        Scene {
+            world,
+            lights: LightBvh::new(lights),
+            background: Box::new(background::Gradient::new(ColorSpace::LinearSrgb)),
+        }
+    }
+
+    fn render_with(integrator: &str, threads: usize) -> Vec<glm::TVec3<f64>> {
+        let settings = integrator::Settings {
+            depths: PathDepths { diffuse: 4, specular: 4, transmission: 4, volume: 4, roulette_depth: 2 },
+            photons: 1000,
+            photon_radius: 0.1,
+            mutations: 1,
+            bootstrap_samples: 1000,
+            large_step_probability: 0.3,
+            mutation_sigma: 0.01,
+            spectral: false,
+            seed: 7,
+        };
+        let mut integrator = integrator::by_name(integrator, &settings).unwrap();
+        let settings = Settings {
+            sampler: "sobol",
+            strata: 4,
+            seed: 7,
+            samples: 2,
+            threads,
+            width: 16,
+            height: 16,
+            // Wide enough that samples land on the rows other threads render.
+            filter: "mitchell",
+            filter_radius: 2.0,
+            adaptive: None,
+            aovs: &[],
+            guides: false,
+        };
+        let camera = Camera::new(glm::vec3(6.0, 2.0, 6.0), glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 1.0, 0.0), 40.0, 1.0, 0.0, 8.0);
+        render(&scene(), &camera, integrator.as_mut(), &settings).image
+    }
+
+    #[test]
+    fn same_image_on_any_number_of_threads() {
+        for integrator in ["path", "bdpt"] {
+            let image = render_with(integrator, 1);
+            assert!(image.iter().any(|color| color.max() > 0.0));
+            assert_eq!(image, render_with(integrator, 4), "{} differs", integrator);
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use nalgebra_glm as glm;
+use std::sync::OnceLock;
+
+// Where the random numbers that paths are traced with come from. Every sample of a pixel asks
+// for its numbers in the same order, one dimension after another, so samplers can spread the
+// samples of a pixel evenly over each dimension instead of leaving them to chance.
+pub trait Sampler {
+    // Starts the `index`th sample of `pixel`, going back to the first dimension.
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u64);
+
+    fn get_1d(&mut self) -> f64;
+
+    fn get_2d(&mut self) -> glm::TVec2<f64>;
+}
+
+pub const NAMES: [&str; 5] = ["independent", "stratified", "halton", "sobol", "bluenoise"];
+
+// `strata` is how many strata the stratified sampler splits each side of the unit square into.
+// Samplers made with the same `seed` give the same numbers for the same sample of a pixel, in
+// whatever order the samples are taken.
+pub fn by_name(name: &str, strata: u32, seed: u64) -> Option<Box<dyn Sampler>> {
+    let position = Position { seed, ..Default::default() };
+    let sampler: Box<dyn Sampler> = match name {
+        "independent" => Box::new(Independent { position }),
+        "stratified" => Box::new(Stratified { strata, position }),
+        "halton" => Box::new(Halton { position }),
+        "sobol" => Box::new(Sobol { position }),
+        "bluenoise" => Box::new(BlueNoise { position }),
+        _ => return None,
+    };
+    Some(sampler)
+}
+
+// Uniform random numbers with nothing tying the samples together.
+pub struct Independent {
+    position: Position,
+}
+
+impl Independent {
+    pub fn new(seed: u64) -> Self {
+        Independent { position: Position { seed, ..Default::default() } }
+    }
+}
+
+impl Sampler for Independent {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u64) {
+        self.position.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.position.random()
+    }
+
+    fn get_2d(&mut self) -> glm::TVec2<f64> {
+        glm::vec2(self.position.random(), self.position.random())
+    }
+}
+
+// The sample being taken and the next dimension it asks for, which the samplers below hash to
+// scramble their points differently for every pixel and dimension.
+#[derive(Default)]
+struct Position {
+    seed: u64,
+    pixel: (u32, u32),
+    index: u64,
+    dimension: u64,
+    // Random numbers drawn so far by this sample, for the ones that aren't tied to a dimension.
+    draws: u64,
+}
+
+impl Position {
+    fn start(&mut self, pixel: (u32, u32), index: u64) {
+        *self = Position { seed: self.seed, pixel, index, dimension: 0, draws: 0 };
+    }
+
+    // A random number of its own for every sample and draw, so it only depends on the seed and
+    // which sample of which pixel is being taken.
+    fn random(&mut self) -> f64 {
+        self.draws += 1;
+        let bits = hash(&[self.seed, self.pixel.0 as u64, self.pixel.1 as u64, self.index, self.draws]);
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uses up the next `count` dimensions, returning the first.
+    fn next_dimension(&mut self, count: u64) -> u64 {
+        self.dimension += count;
+        self.dimension - count
+    }
+
+    // Hash of the pixel and the next `count` dimensions.
+    fn next_seed(&mut self, count: u64) -> u64 {
+        let dimension = self.next_dimension(count);
+        hash(&[self.seed, self.pixel.0 as u64, self.pixel.1 as u64, dimension])
+    }
+}
+
+// Jittered samples in strata of a grid `strata` wide, each dimension visiting its strata in a
+// different random order. As the renderer doesn't know how many samples it will take, every run
+// of `strata * strata` samples covers all the strata before starting over in a new order.
+pub struct Stratified {
+    strata: u32,
+    position: Position,
+}
+
+impl Stratified {
+    // Stratum of the current sample, out of `count`.
+    fn stratum(&self, count: u32, seed: u64) -> u32 {
+        let round = self.position.index / count as u64;
+        let slot = (self.position.index % count as u64) as u32;
+        permutation_element(slot, count, hash(&[seed, round]) as u32)
+    }
+}
+
+impl Sampler for Stratified {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u64) {
+        self.position.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let count = self.strata * self.strata;
+        let seed = self.position.next_seed(1);
+        (self.stratum(count, seed) as f64 + self.position.random()) / count as f64
+    }
+
+    fn get_2d(&mut self) -> glm::TVec2<f64> {
+        let seed = self.position.next_seed(2);
+        let stratum = self.stratum(self.strata * self.strata, seed);
+        let (x, y) = (stratum % self.strata, stratum / self.strata);
+        glm::vec2(x as f64 + self.position.random(), y as f64 + self.position.random()) / self.strata as f64
+    }
+}
+
+// The Halton sequence, with a prime base per dimension. The digits are Owen scrambled with a
+// different hash for every pixel, so neighbouring pixels don't share the same points.
+pub struct Halton {
+    position: Position,
+}
+
+// Dimensions past this many are filled in with independent random numbers. Reusing a base would
+// tie the dimensions sharing it together, however they were scrambled.
+const HALTON_DIMENSIONS: usize = 1000;
+
+fn primes() -> &'static [u64] {
+    static PRIMES: OnceLock<Vec<u64>> = OnceLock::new();
+    PRIMES.get_or_init(|| {
+        let mut primes: Vec<u64> = Vec::with_capacity(HALTON_DIMENSIONS);
+        let mut candidate = 2;
+        while primes.len() < HALTON_DIMENSIONS {
+            if primes.iter().take_while(|&&p| p * p <= candidate).all(|&p| candidate % p != 0) {
+                primes.push(candidate);
+            }
+            candidate += 1;
+        }
+        primes
+    })
+}
+
+impl Sampler for Halton {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u64) {
+        self.position.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let base = match primes().get(self.position.dimension as usize) {
+            Some(&base) => base,
+            None => return self.position.random(),
+        };
+        let seed = self.position.next_seed(1);
+        owen_scrambled_radical_inverse(base, self.position.index, seed)
+    }
+
+    fn get_2d(&mut self) -> glm::TVec2<f64> {
+        glm::vec2(self.get_1d(), self.get_1d())
+    }
+}
+
+// Pairs of dimensions from the first two dimensions of the Sobol sequence, which are well spread
+// over the unit square at any power of two samples. Each pair shuffles the order of the samples
+// and Owen scrambles the points with its own hash, after Burley, "Practical Hash-based Owen
+// Scrambling", so the pairs aren't correlated with each other.
+pub struct Sobol {
+    position: Position,
+}
+
+impl Sampler for Sobol {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u64) {
+        self.position.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let seed = self.position.next_seed(1);
+        sobol_1d(self.position.index, seed)
+    }
+
+    fn get_2d(&mut self) -> glm::TVec2<f64> {
+        let seed = self.position.next_seed(2);
+        sobol_2d(self.position.index, seed)
+    }
+}
+
+fn sobol_1d(index: u64, seed: u64) -> f64 {
+    let index = nested_uniform_scramble(index as u32, seed as u32);
+    to_unit(nested_uniform_scramble(index.reverse_bits(), (seed >> 32) as u32))
+}
+
+fn sobol_2d(index: u64, seed: u64) -> glm::TVec2<f64> {
+    let index = nested_uniform_scramble(index as u32, seed as u32);
+    let x = nested_uniform_scramble(index.reverse_bits(), hash(&[seed, 0]) as u32);
+    let y = nested_uniform_scramble(sobol_second_dimension(index), hash(&[seed, 1]) as u32);
+    glm::vec2(to_unit(x), to_unit(y))
+}
+
+// Every pixel takes the same scrambled Sobol points, shifted around the unit square by the value
+// of a blue noise mask at the pixel, after Heitz and Belcour, "Distributing Monte Carlo Errors as
+// a Blue Noise in Screen Space". Neighbouring pixels are shifted by very different amounts, so
+// the error of any one sample is spread over the image as blue noise, which looks finer than
+// white noise and is easier to filter out.
+pub struct BlueNoise {
+    position: Position,
+}
+
+impl BlueNoise {
+    // The mask at the current pixel, moved by a different amount for every dimension.
+    fn offset(&self, seed: u64) -> f64 {
+        let (x, y) = self.position.pixel;
+        let x = (x as usize + (seed % MASK_SIZE as u64) as usize) % MASK_SIZE;
+        let y = (y as usize + ((seed >> 32) % MASK_SIZE as u64) as usize) % MASK_SIZE;
+        blue_noise_mask()[y * MASK_SIZE + x]
+    }
+}
+
+impl Sampler for BlueNoise {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u64) {
+        self.position.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let seed = hash(&[self.position.seed, self.position.next_dimension(1)]);
+        (sobol_1d(self.position.index, seed) + self.offset(seed)).fract()
+    }
+
+    fn get_2d(&mut self) -> glm::TVec2<f64> {
+        let seed = hash(&[self.position.seed, self.position.next_dimension(2)]);
+        let point = sobol_2d(self.position.index, seed);
+        let x = point.x + self.offset(hash(&[seed, 0]));
+        let y = point.y + self.offset(hash(&[seed, 1]));
+        glm::vec2(x.fract(), y.fract())
+    }
+}
+
+const MASK_SIZE: usize = 64;
+
+// A tiling blue noise mask: every value in [0, 1) appears once, and pixels with similar values
+// lie far apart. Made with Ulichney's void and cluster method on first use.
+fn blue_noise_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(void_and_cluster)
+}
+
+fn void_and_cluster() -> Vec<f64> {
+    const SIGMA: f64 = 1.5;
+    let n = MASK_SIZE * MASK_SIZE;
+    // How much a point contributes to the energy of another, by their offset on the torus.
+    let kernel: Vec<f64> = (0..n)
+        .map(|i| {
+            let (x, y) = (i % MASK_SIZE, i / MASK_SIZE);
+            let dx = x.min(MASK_SIZE - x) as f64;
+            let dy = y.min(MASK_SIZE - y) as f64;
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    let toggle = |pattern: &mut [bool], energy: &mut [f64], i: usize| {
+        pattern[i] = !pattern[i];
+        let sign = if pattern[i] { 1.0 } else { -1.0 };
+        let (x, y) = (i % MASK_SIZE, i / MASK_SIZE);
+        for (j, e) in energy.iter_mut().enumerate() {
+            let dx = (j % MASK_SIZE + MASK_SIZE - x) % MASK_SIZE;
+            let dy = (j / MASK_SIZE + MASK_SIZE - y) % MASK_SIZE;
+            *e += sign * kernel[dy * MASK_SIZE + dx];
+        }
+    };
+    // The tightest cluster is the point with the most energy, the largest void the empty one
+    // with the least.
+    let tightest_cluster = |pattern: &[bool], energy: &[f64]| {
+        (0..n).filter(|&i| pattern[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f64]| {
+        (0..n).filter(|&i| !pattern[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // Scatter a tenth of the points at random and even them out, moving the point in the tightest
+    // cluster to the largest void until it's already in it.
+    let mut pattern = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let initial = n / 10;
+    let mut placed = 0;
+    let mut counter = 0;
+    while placed < initial {
+        let i = (hash(&[counter]) % n as u64) as usize;
+        counter += 1;
+        if !pattern[i] {
+            toggle(&mut pattern, &mut energy, i);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // The initial points are ranked by taking them away from the tightest clusters, and the rest
+    // by filling in the largest voids.
+    let mut rank = vec![0; n];
+    let (mut removing, mut removing_energy) = (pattern.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let cluster = tightest_cluster(&removing, &removing_energy);
+        toggle(&mut removing, &mut removing_energy, cluster);
+        rank[cluster] = r;
+    }
+    for r in initial..n {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        rank[void] = r;
+    }
+    rank.into_iter().map(|r| (r as f64 + 0.5) / n as f64).collect()
+}
+
+fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, &v| mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15)))
+}
+
+// Element `i` of a random permutation of `0..count` picked by `seed`, from Kensler, "Correlated
+// Multi-Jittered Sampling".
+fn permutation_element(mut i: u32, count: u32, seed: u32) -> u32 {
+    let mut w = count - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < count {
+            break;
+        }
+    }
+    i.wrapping_add(seed) % count
+}
+
+// The digits of `index` in `base` mirrored around the point, each permuted by a hash of the
+// digits before it, which is Owen scrambling.
+fn owen_scrambled_radical_inverse(base: u64, mut index: u64, seed: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut value = 0.0;
+    // The digits so far, only for hashing, so it's fine for them to overflow.
+    let mut prefix: u64 = 0;
+    let mut inverse_base_m = 1.0;
+    // Keep going past the last digit of `index`, as its zeros get scrambled too.
+    while 1.0 - (base - 1) as f64 * inverse_base_m < 1.0 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit_hash = mix_bits(seed ^ prefix) as u32;
+        let digit = permutation_element(digit as u32, base as u32, digit_hash) as u64;
+        prefix = prefix.wrapping_mul(base).wrapping_add(digit);
+        inverse_base_m *= inverse_base;
+        value += digit as f64 * inverse_base_m;
+        index = next;
+    }
+    value.min(1.0 - f64::EPSILON / 2.0)
+}
+
+// Second dimension of the Sobol sequence, with its bits in the order of a binary fraction.
+fn sobol_second_dimension(index: u32) -> u32 {
+    let mut direction = 1u32 << 31;
+    let mut result = 0;
+    for bit in 0..32 {
+        if (index >> bit) & 1 == 1 {
+            result ^= direction;
+        }
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+// Owen scrambles the binary fraction `x`, or shuffles sample indices when used on those.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn to_unit(x: u32) -> f64 {
+    x as f64 / (1u64 << 32) as f64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_every_number_in_the_unit_interval() {
+        for name in NAMES.iter() {
+            let mut sampler = by_name(name, 4, 7).unwrap();
+            for index in 0..64 {
+                sampler.start_pixel_sample((index as u32 % 7, 3), index);
+                for _ in 0..20 {
+                    let x = sampler.get_1d();
+                    let p = sampler.get_2d();
+                    for value in [x, p.x, p.y].iter() {
+                        assert!((0.0..1.0).contains(value), "{} gave {}", name, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn covers_every_stratum_of_the_square() {
+        const STRATA: u32 = 4;
+        for name in ["stratified", "sobol"].iter() {
+            let mut sampler = by_name(name, STRATA, 7).unwrap();
+            for dimension in 0..5 {
+                let mut covered = vec![false; (STRATA * STRATA) as usize];
+                for index in 0..(STRATA * STRATA) as u64 {
+                    sampler.start_pixel_sample((5, 9), index);
+                    for _ in 0..dimension {
+                        sampler.get_2d();
+                    }
+                    let p = sampler.get_2d() * STRATA as f64;
+                    covered[(p.y as u32 * STRATA + p.x as u32) as usize] = true;
+                }
+                assert!(covered.iter().all(|&c| c), "{} missed a stratum in dimension {}", name, dimension);
+            }
+        }
+    }
+
+    #[test]
+    fn gives_the_same_numbers_for_the_same_seed_in_any_order() {
+        for name in NAMES.iter() {
+            let take = |sampler: &mut Box<dyn Sampler>, index: u64| {
+                sampler.start_pixel_sample((12, 34), index);
+                (sampler.get_1d(), sampler.get_2d(), sampler.get_1d())
+            };
+            let mut forwards = by_name(name, 4, 7).unwrap();
+            let mut backwards = by_name(name, 4, 7).unwrap();
+            let first: Vec<_> = (0..16).map(|index| take(&mut forwards, index)).collect();
+            let mut second: Vec<_> = (0..16).rev().map(|index| take(&mut backwards, index)).collect();
+            second.reverse();
+            assert_eq!(first, second, "{}", name);
+            let mut reseeded = by_name(name, 4, 8).unwrap();
+            assert_ne!(first, (0..16).map(|index| take(&mut reseeded, index)).collect::<Vec<_>>(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use crate::background::Background;
+use crate::light_bvh::LightBvh;
+use crate::objects;
+
+pub struct Scene {
+    pub world: objects::HittableList,
+    pub lights: LightBvh,
+    pub background: Box<dyn Background>,
+}
@@ -0,0 +1,178 @@
+use crate::background::Background;
+use crate::colorspace;
+use crate::util;
+use nalgebra_glm as glm;
+use std::f64::consts::PI;
+
+// Angular radius of the sun as seen from the earth.
+const SUN_ANGULAR_RADIUS: f64 = 0.00465;
+// Irradiance of the sun at the top of the atmosphere, in the same units as the scaled sky.
+const SUN_IRRADIANCE: f64 = 4.0;
+// Preetham's model works in kcd/m², this brings a clear noon sky to roughly the brightness
+// of the old gradient.
+const SKY_SCALE: f64 = 1.0 / 25.0;
+
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight" (1999).
+pub struct Sky {
+    sun_direction: glm::TVec3<f64>,
+    sun_radiance: glm::TVec3<f64>,
+    cos_sun_radius: f64,
+    intensity: f64,
+    zenith: glm::TVec3<f64>,
+    perez_y: [f64; 5],
+    perez_x_chroma: [f64; 5],
+    perez_y_chroma: [f64; 5],
+    ground_radiance: glm::TVec3<f64>,
+}
+
+impl Sky {
+    pub fn new(sun_elevation: f64, sun_azimuth: f64, turbidity: f64, ground_albedo: f64, intensity: f64) -> Self {
+        let sun_direction = glm::vec3(
+            sun_elevation.cos() * sun_azimuth.cos(),
+            sun_elevation.sin(),
+            sun_elevation.cos() * sun_azimuth.sin(),
+        );
+        let theta_s = (PI / 2.0 - sun_elevation).clamp(0.0, PI / 2.0);
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_x = chromaticity(t, theta_s, [
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity(t, theta_s, [
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut sky = Sky {
+            sun_direction,
+            sun_radiance: glm::vec3(0.0, 0.0, 0.0),
+            cos_sun_radius: SUN_ANGULAR_RADIUS.cos(),
+            intensity,
+            zenith: glm::vec3(zenith_luminance.max(0.0), zenith_x, zenith_y),
+            perez_y: [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            perez_x_chroma: [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            perez_y_chroma: [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+            ground_radiance: glm::vec3(0.0, 0.0, 0.0),
+        };
+        let solid_angle = 2.0 * PI * (1.0 - sky.cos_sun_radius);
+        sky.sun_radiance = colorspace::from_srgb(&sun_transmittance(theta_s, t)) * SUN_IRRADIANCE * intensity / solid_angle;
+        if sun_elevation < 0.0 {
+            sky.sun_radiance = glm::vec3(0.0, 0.0, 0.0);
+        }
+        sky.ground_radiance = sky.ground_irradiance() * ground_albedo / PI;
+        sky
+    }
+
+    fn sky_radiance(&self, direction: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        let cos_theta = direction.y.max(0.01);
+        let cos_gamma = glm::dot(direction, &self.sun_direction).clamp(-1.0, 1.0);
+        let theta_s = self.sun_direction.y.clamp(0.0, 1.0).acos();
+        let relative = |coefficients: &[f64; 5], zenith: f64| {
+            zenith * perez(coefficients, cos_theta, cos_gamma) / perez(coefficients, 1.0, theta_s.cos())
+        };
+        let luminance = relative(&self.perez_y, self.zenith.x);
+        let x = relative(&self.perez_x_chroma, self.zenith.y);
+        let y = relative(&self.perez_y_chroma, self.zenith.z);
+        colorspace::from_srgb(&xyy_to_rgb(x, y, luminance)) * SKY_SCALE * self.intensity
+    }
+
+    fn ground_irradiance(&self) -> glm::TVec3<f64> {
+        const STEPS: usize = 16;
+        let mut irradiance = glm::vec3(0.0, 0.0, 0.0);
+        for i in 0..STEPS {
+            for j in 0..STEPS * 2 {
+                let cos_theta = ((i as f64 + 0.5) / STEPS as f64).sqrt();
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let phi = 2.0 * PI * (j as f64 + 0.5) / (STEPS * 2) as f64;
+                let direction = glm::vec3(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+                irradiance += self.sky_radiance(&direction);
+            }
+        }
+        irradiance *= PI / (STEPS * STEPS * 2) as f64;
+        let solid_angle = 2.0 * PI * (1.0 - self.cos_sun_radius);
+        irradiance + self.sun_radiance * solid_angle * self.sun_direction.y.max(0.0)
+    }
+}
+
+impl Background for Sky {
+    fn color(&self, direction: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        let d = glm::normalize(direction);
+        if d.y < 0.0 {
+            return self.ground_radiance;
+        }
+        let mut radiance = self.sky_radiance(&d);
+        if glm::dot(&d, &self.sun_direction) >= self.cos_sun_radius {
+            radiance += self.sun_radiance;
+        }
+        radiance
+    }
+
+    fn sample(&self, u: glm::TVec2<f64>) -> Option<(glm::TVec3<f64>, glm::TVec3<f64>, f64)> {
+        if self.sun_direction.y < 0.0 {
+            return None;
+        }
+        let direction = util::sample_cone(&self.sun_direction, self.cos_sun_radius, u);
+        Some((direction, self.color(&direction), self.pdf(&direction)))
+    }
+
+    fn pdf(&self, direction: &glm::TVec3<f64>) -> f64 {
+        if self.sun_direction.y < 0.0 {
+            return 0.0;
+        }
+        if glm::dot(&glm::normalize(direction), &self.sun_direction) >= self.cos_sun_radius {
+            1.0 / (2.0 * PI * (1.0 - self.cos_sun_radius))
+        } else {
+            0.0
+        }
+    }
+}
+
+fn perez(coefficients: &[f64; 5], cos_theta: f64, cos_gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    let gamma = cos_gamma.acos();
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+fn chromaticity(turbidity: f64, theta_s: f64, m: [[f64; 4]; 3]) -> f64 {
+    let t = [turbidity * turbidity, turbidity, 1.0];
+    let theta = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+    let mut result = 0.0;
+    for (i, row) in m.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            result += t[i] * value * theta[j];
+        }
+    }
+    result
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> glm::TVec3<f64> {
+    if y <= 0.0 {
+        return glm::vec3(0.0, 0.0, 0.0);
+    }
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+    let rgb = glm::vec3(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    );
+    rgb.map(|c| c.max(0.0))
+}
+
+// Rayleigh and aerosol extinction along the path of the sunlight, evaluated at a representative
+// wavelength (in micrometers) for each channel.
+fn sun_transmittance(theta_s: f64, turbidity: f64) -> glm::TVec3<f64> {
+    let theta_degrees = theta_s.to_degrees();
+    let relative_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_degrees).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    glm::vec3(0.68, 0.55, 0.44).map(|lambda: f64| {
+        let rayleigh = (-0.008735 * lambda.powf(-4.08) * relative_mass).exp();
+        let aerosol = (-beta * lambda.powf(-1.3) * relative_mass).exp();
+        rayleigh * aerosol
+    })
+}
@@ -0,0 +1,138 @@
+use nalgebra_glm as glm;
+use std::sync::OnceLock;
+
+use crate::colorspace::{self, ColorSpace};
+
+// Spectra of the RGB primaries and their mixtures from Smits, "An RGB-to-Spectrum Conversion for
+// Reflectances", in ten bins evenly spread over 380 to 720 nm.
+const SMITS_START: f64 = 380.0;
+const SMITS_BIN_WIDTH: f64 = 34.0;
+const WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Range of wavelengths sampled, in nanometres.
+const MIN_WAVELENGTH: f64 = 360.0;
+const MAX_WAVELENGTH: f64 = 830.0;
+
+// Picks a wavelength with a density roughly following the eye's sensitivity, from PBRT v3.
+pub fn sample_wavelength(u: f64) -> (f64, f64) {
+    let wavelength = 538.0 - 138.888889 * (0.85691062 - 1.82750197 * u).atanh();
+    (wavelength, wavelength_pdf(wavelength))
+}
+
+fn wavelength_pdf(wavelength: f64) -> f64 {
+    if !(MIN_WAVELENGTH..=MAX_WAVELENGTH).contains(&wavelength) {
+        return 0.0;
+    }
+    0.0039398042 / (0.0072 * (wavelength - 538.0)).cosh().powi(2)
+}
+
+// Value at `wavelength` of a smooth spectrum with the given RGB color in the working space.
+pub fn uplift(rgb: &glm::TVec3<f64>, wavelength: f64) -> f64 {
+    // Smits' spectra are fitted to the sRGB primaries.
+    let rgb = colorspace::to_srgb(rgb);
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let at = |spectrum: &[f64; 10]| smits(spectrum, wavelength);
+    if r <= g && r <= b {
+        if g <= b {
+            r * at(&WHITE) + (g - r) * at(&CYAN) + (b - g) * at(&BLUE)
+        } else {
+            r * at(&WHITE) + (b - r) * at(&CYAN) + (g - b) * at(&GREEN)
+        }
+    } else if g <= r && g <= b {
+        if r <= b {
+            g * at(&WHITE) + (r - g) * at(&MAGENTA) + (b - r) * at(&BLUE)
+        } else {
+            g * at(&WHITE) + (b - g) * at(&MAGENTA) + (r - b) * at(&RED)
+        }
+    } else if r <= g {
+        b * at(&WHITE) + (r - b) * at(&YELLOW) + (g - r) * at(&GREEN)
+    } else {
+        b * at(&WHITE) + (g - b) * at(&YELLOW) + (r - g) * at(&RED)
+    }
+}
+
+// Interpolates between the bin centres, holding the end bins beyond them.
+fn smits(spectrum: &[f64; 10], wavelength: f64) -> f64 {
+    let x = ((wavelength - SMITS_START) / SMITS_BIN_WIDTH - 0.5).clamp(0.0, 9.0);
+    let i = (x as usize).min(8);
+    let t = x - i as f64;
+    spectrum[i] * (1.0 - t) + spectrum[i + 1] * t
+}
+
+// Uplifts `rgb` at `wavelength`, for RGB quantities met by a path carrying a single wavelength.
+// Without a wavelength colors stay as they are.
+pub fn at(rgb: &glm::TVec3<f64>, wavelength: Option<f64>) -> glm::TVec3<f64> {
+    match wavelength {
+        Some(wavelength) => {
+            let value = uplift(rgb, wavelength);
+            glm::vec3(value, value, value)
+        }
+        None => *rgb,
+    }
+}
+
+// Contribution, in the working space, of `value` found at a wavelength sampled by `sample_wavelength`,
+// balanced so that a constant spectrum of one comes out white.
+pub fn to_rgb(value: f64, wavelength: f64) -> glm::TVec3<f64> {
+    let pdf = wavelength_pdf(wavelength);
+    if pdf == 0.0 {
+        return glm::vec3(0.0, 0.0, 0.0);
+    }
+    colorspace::from_srgb(&(xyz_to_rgb(&xyz(wavelength)).component_div(white()) * value / pdf))
+}
+
+static WHITE_RGB: OnceLock<glm::TVec3<f64>> = OnceLock::new();
+
+// Sum of the colors of every wavelength, which a flat spectrum has to come out as white.
+fn white() -> &'static glm::TVec3<f64> {
+    WHITE_RGB.get_or_init(|| integrate(|_| 1.0))
+}
+
+// Linear sRGB color of a spectrum, unbalanced, summed over the visible wavelengths.
+fn integrate(spectrum: impl Fn(f64) -> f64) -> glm::TVec3<f64> {
+    let mut sum = glm::vec3(0.0, 0.0, 0.0);
+    let mut wavelength = MIN_WAVELENGTH;
+    while wavelength <= MAX_WAVELENGTH {
+        sum += xyz_to_rgb(&xyz(wavelength)) * spectrum(wavelength);
+        wavelength += 1.0;
+    }
+    sum
+}
+
+// Linear sRGB color of the light a black body glows with at `kelvin`, by Planck's law, balanced
+// like `to_rgb` and scaled to a luminance of one, whatever the working space.
+pub fn blackbody(kelvin: f64) -> glm::TVec3<f64> {
+    // Second radiation constant, in nanometre kelvin.
+    const C2: f64 = 1.4387769e7;
+    let color = integrate(|wavelength| 1.0 / (wavelength.powi(5) * ((C2 / (wavelength * kelvin)).exp() - 1.0)));
+    let color = color.component_div(white());
+    color / ColorSpace::LinearSrgb.luminance(&color)
+}
+
+// CIE 1931 color matching functions, as fitted by Wyman, Sloan and Shirley, "Simple Analytic
+// Approximations to the CIE XYZ Color Matching Functions".
+fn xyz(wavelength: f64) -> glm::TVec3<f64> {
+    let g = |mu: f64, sigma_below: f64, sigma_above: f64| {
+        let t = (wavelength - mu) / if wavelength < mu { sigma_below } else { sigma_above };
+        (-0.5 * t * t).exp()
+    };
+    glm::vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+fn xyz_to_rgb(xyz: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+    glm::vec3(
+        3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+        0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+    )
+}
@@ -3,29 +3,66 @@ use nalgebra_glm as glm;
 use crate::ray;
 use crate::objects;
 use crate::materials;
+use crate::textures::Texture;
+use crate::util;
 use std::sync::Arc;
 
 use objects::Hittable;
 
 use self::objects::HitRecord;
 
-pub struct Sphere<M: materials::Material> {
+pub struct Sphere {
     pub centre: glm::TVec3<f64>,
     pub radius: f64,
-    pub material: Arc<M>
+    pub material: Arc<dyn materials::Material>,
+    // Cut holes out of the sphere where this is below one, for things like leaves and fences.
+    pub opacity: Option<Arc<dyn Texture>>,
 }
 
-impl<M: materials::Material> Sphere<M> {
-    pub fn new(centre: glm::TVec3<f64>, radius: f64, material: Arc<M>) -> Self {
+impl Sphere {
+    pub fn new(centre: glm::TVec3<f64>, radius: f64, material: Arc<dyn materials::Material>) -> Self {
         Sphere {
             centre,
             radius,
             material,
+            opacity: None,
+        }
+    }
+
+    pub fn cutout(centre: glm::TVec3<f64>, radius: f64, material: Arc<dyn materials::Material>, opacity: Arc<dyn Texture>) -> Self {
+        Sphere {
+            centre,
+            radius,
+            material,
+            opacity: Some(opacity),
         }
     }
 }
 
-impl<M: 'static +  materials::Material> Hittable for Sphere<M> {
+impl Sphere {
+    fn hit_record(&self, r: &ray::Ray, t: f64) -> HitRecord {
+        let p = r.at(t);
+        let outward_normal = (p - self.centre) / self.radius;
+        let (u, v) = sphere_uv(&outward_normal);
+        let (dpdu, dpdv) = sphere_tangents(&(p - self.centre), u, v);
+        let (normal, front_face) = objects::set_face_normal(r, outward_normal);
+        HitRecord { t, p, normal, u, v, dpdu, dpdv, front_face, material: self.material.clone() }
+    }
+
+    // Whether the ray passes through a hole at `hit`. Partly opaque points let through that
+    // fraction of rays, picked by hashing the ray so the choice doesn't use up random numbers.
+    fn cut_out(&self, r: &ray::Ray, hit: &HitRecord) -> bool {
+        match &self.opacity {
+            Some(opacity) => {
+                let alpha = opacity.value(hit.u, hit.v, &hit.p).mean();
+                alpha <= 0.0 || (alpha < 1.0 && util::hashed_f64(hit.p.iter().chain(r.direction.iter())) >= alpha)
+            }
+            None => false,
+        }
+    }
+}
+
+impl Hittable for Sphere {
     fn hit(&self, r: &ray::Ray, t_min: f64, t_max: f64) -> Option<objects::HitRecord> {
         let oc = r.origin - self.centre;
         let a = glm::dot(&r.direction, &r.direction);
@@ -36,20 +73,49 @@ impl<M: 'static +  materials::Material> Hittable for Sphere<M> {
             let sqrt_discriminant = discriminant.sqrt();
             let t = (-b - sqrt_discriminant) / a;
             if t < t_max && t > t_min {
-                let p = r.at(t);
-                let normal = (p - self.centre) / self.radius;
-                let (normal, front_face) = objects::set_face_normal(r, normal);
-                return Some(HitRecord { t, p, normal, front_face, material: self.material.clone() })
+                let hit = self.hit_record(r, t);
+                if !self.cut_out(r, &hit) {
+                    return Some(hit)
+                }
             }
             let t = (-b + sqrt_discriminant) / a;
             if t < t_max && t > t_min {
-                let p = r.at(t);
-                let normal = (p - self.centre) / self.radius;
-                let (normal, front_face) = objects::set_face_normal(r, normal);
-                return Some(HitRecord { t, p, normal, front_face, material: self.material.clone() })
+                let hit = self.hit_record(r, t);
+                if !self.cut_out(r, &hit) {
+                    return Some(hit)
+                }
             }
         }
         None
     }
+
+    fn materials(&self) -> Vec<Arc<dyn materials::Material>> {
+        vec![self.material.clone()]
+    }
+
+    fn specular_bounds(&self) -> Option<objects::Aabb> {
+        if !self.material.is_specular() {
+            return None;
+        }
+        let extent = glm::vec3(1.0, 1.0, 1.0) * self.radius.abs();
+        Some(objects::Aabb { min: self.centre - extent, max: self.centre + extent })
+    }
 }
 
+
+fn sphere_uv(p: &glm::TVec3<f64>) -> (f64, f64) {
+    let theta = (-p.y).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z).atan2(p.x) + std::f64::consts::PI;
+    (phi / (2.0 * std::f64::consts::PI), theta / std::f64::consts::PI)
+}
+
+// Derivatives of the point `local` from the centre with respect to the `sphere_uv` coordinates.
+fn sphere_tangents(local: &glm::TVec3<f64>, u: f64, v: f64) -> (glm::TVec3<f64>, glm::TVec3<f64>) {
+    let (theta, phi) = (v * std::f64::consts::PI, 2.0 * std::f64::consts::PI * u);
+    let radius = local.magnitude();
+    let dpdu = 2.0 * std::f64::consts::PI * glm::vec3(local.z, 0.0, -local.x);
+    let dpdv = std::f64::consts::PI
+        * radius
+        * glm::vec3(-theta.cos() * phi.cos(), theta.sin(), theta.cos() * phi.sin());
+    (dpdu, dpdv)
+}
@@ -0,0 +1,111 @@
+use image::GenericImageView;
+use nalgebra_glm as glm;
+use std::sync::Arc;
+
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, p: &glm::TVec3<f64>) -> glm::TVec3<f64>;
+}
+
+pub struct SolidColor(pub glm::TVec3<f64>);
+
+impl SolidColor {
+    pub fn new(color: glm::TVec3<f64>) -> Arc<Self> {
+        Arc::new(SolidColor(color))
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _p: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        self.0
+    }
+}
+
+// Alternates between two textures in a 3D checkerboard with cells `1 / scale` wide.
+pub struct Checker {
+    pub odd: Arc<dyn Texture>,
+    pub even: Arc<dyn Texture>,
+    pub scale: f64,
+}
+
+impl Checker {
+    pub fn new(odd: Arc<dyn Texture>, even: Arc<dyn Texture>, scale: f64) -> Arc<Self> {
+        Arc::new(Checker { odd, even, scale })
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, u: f64, v: f64, p: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        let cell = (self.scale * p).map(|x| x.floor() as i64);
+        if (cell.x + cell.y + cell.z).rem_euclid(2) == 1 {
+            self.odd.value(u, v, p)
+        } else {
+            self.even.value(u, v, p)
+        }
+    }
+}
+
+// An image wrapped over the texture coordinates, with `v` running up from the bottom row. Pixels
+// are read as they're stored, without undoing any gamma, as normal and bump maps are linear.
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<glm::TVec3<f64>>,
+}
+
+impl Image {
+    pub fn load(path: &str) -> Result<Arc<Self>, String> {
+        let image = open(path)?.to_rgb8();
+        let pixels = image
+            .pixels()
+            .map(|p| glm::vec3(p[0] as f64, p[1] as f64, p[2] as f64) / 255.0)
+            .collect();
+        Ok(Arc::new(Image { width: image.width() as usize, height: image.height() as usize, pixels }))
+    }
+
+    // Loads the alpha channel of an image as a grey texture, or its brightness if it has none.
+    pub fn load_opacity(path: &str) -> Result<Arc<Self>, String> {
+        let image = open(path)?;
+        let has_alpha = image.color().has_alpha();
+        let image = image.to_rgba8();
+        let pixels = image
+            .pixels()
+            .map(|p| {
+                let alpha = if has_alpha {
+                    p[3] as f64
+                } else {
+                    (p[0] as f64 + p[1] as f64 + p[2] as f64) / 3.0
+                };
+                glm::vec3(alpha, alpha, alpha) / 255.0
+            })
+            .collect();
+        Ok(Arc::new(Image { width: image.width() as usize, height: image.height() as usize, pixels }))
+    }
+
+    fn texel(&self, x: i64, y: i64) -> glm::TVec3<f64> {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Texture for Image {
+    // Interpolates bilinearly, so bump maps have slopes between texel centres.
+    fn value(&self, u: f64, v: f64, _p: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+        let x = u * self.width as f64 - 0.5;
+        let y = (1.0 - v) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0) * (1.0 - tx) + self.texel(x0 + 1, y0) * tx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - tx) + self.texel(x0 + 1, y0 + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+fn open(path: &str) -> Result<image::DynamicImage, String> {
+    let image = image::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    if image.width() == 0 || image.height() == 0 {
+        return Err(format!("Texture {} is empty", path));
+    }
+    Ok(image)
+}
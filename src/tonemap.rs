@@ -0,0 +1,185 @@
+use nalgebra_glm as glm;
+
+use crate::colorspace::{self, ColorSpace};
+use crate::post;
+use crate::sampler;
+
+// Ways of squeezing the unbounded light the renderer works with into what a screen can show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapper {
+    // Cuts off everything brighter than white.
+    Clamp,
+    Reinhard,
+    Aces,
+    Agx,
+    Uncharted2,
+}
+
+pub const NAMES: [&str; 5] = ["clamp", "reinhard", "aces", "agx", "uncharted2"];
+
+const TONE_MAPPERS: [ToneMapper; 5] = [
+    ToneMapper::Clamp,
+    ToneMapper::Reinhard,
+    ToneMapper::Aces,
+    ToneMapper::Agx,
+    ToneMapper::Uncharted2,
+];
+
+impl ToneMapper {
+    pub fn by_name(name: &str) -> Option<Self> {
+        NAMES.iter().position(|&n| n == name).map(|index| TONE_MAPPERS[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        NAMES[self.index()]
+    }
+
+    pub fn next(&self) -> Self {
+        TONE_MAPPERS[(self.index() + 1) % TONE_MAPPERS.len()]
+    }
+
+    fn index(&self) -> usize {
+        TONE_MAPPERS.iter().position(|mapper| mapper == self).unwrap()
+    }
+
+    // Maps linear `color` in `space` to linear values between black and white.
+    pub fn map(&self, color: &glm::TVec3<f64>, space: ColorSpace) -> glm::TVec3<f64> {
+        let color = color.map(|c| c.max(0.0));
+        let mapped = match self {
+            ToneMapper::Clamp => color,
+            ToneMapper::Reinhard => reinhard(&color, space),
+            ToneMapper::Aces => aces(&color),
+            ToneMapper::Agx => agx(&color),
+            ToneMapper::Uncharted2 => uncharted2(&color),
+        };
+        mapped.map(|c| c.clamp(0.0, 1.0))
+    }
+}
+
+// Reinhard et al., "Photographic Tone Reproduction for Digital Images", on the luminance so that
+// bright colors keep their hue.
+fn reinhard(color: &glm::TVec3<f64>, space: ColorSpace) -> glm::TVec3<f64> {
+    let luminance = space.luminance(color);
+    if luminance <= 0.0 {
+        return *color;
+    }
+    color * (1.0 / (1.0 + luminance))
+}
+
+// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms.
+fn aces(color: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+    let input = glm::mat3(
+        0.59719, 0.35458, 0.04823,
+        0.07600, 0.90834, 0.01566,
+        0.02840, 0.13383, 0.83777,
+    );
+    let output = glm::mat3(
+        1.60475, -0.53108, -0.07367,
+        -0.10208, 1.10813, -0.00605,
+        -0.00327, -0.07276, 1.07602,
+    );
+    let v = input * color;
+    let fitted = v.map(|v| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.4329510) + 0.238081));
+    output * fitted
+}
+
+// Exposure range the AgX curve covers, in stops around middle grey.
+const AGX_MIN_EV: f64 = -12.47393;
+const AGX_MAX_EV: f64 = 4.026069;
+
+// A small version of Troy Sobotka's AgX, after Benjamin Wrensch's: colors are pulled in towards
+// grey so that bright saturated ones fade to white instead of skewing in hue, squeezed through
+// a sigmoid over a logarithmic exposure range, and pushed back out.
+fn agx(color: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+    let inset = glm::mat3(
+        0.842479062253094, 0.0784335999999992, 0.0792237451477643,
+        0.0423282422610123, 0.878468636469772, 0.0791661274605434,
+        0.0423756549057051, 0.0784336, 0.879142973793104,
+    );
+    let outset = glm::mat3(
+        1.19687900512017, -0.0980208811401368, -0.0990297440797205,
+        -0.0528968517574562, 1.15190312990417, -0.0989611768448433,
+        -0.0529716355144438, -0.0980434501171241, 1.15107367264116,
+    );
+    let encoded = (inset * color).map(|c| {
+        let x = (c.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV) - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+        let (x2, x4) = (x * x, x * x * x * x);
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+    // The curve comes out gamma encoded for a 2.2 display.
+    (outset * encoded).map(|c| c.max(0.0).powf(2.2))
+}
+
+// John Hable's filmic curve from Uncharted 2, with his exposure bias and white point.
+fn uncharted2(color: &glm::TVec3<f64>) -> glm::TVec3<f64> {
+    const WHITE: f64 = 11.2;
+    let curve = |x: f64| {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+    };
+    color.map(|c| curve(2.0 * c) / curve(WHITE))
+}
+
+pub fn srgb_encode(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn srgb_decode(encoded: f64) -> f64 {
+    if encoded <= 0.04045 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// How the linear colors the renderer works with become the 8 bit pixels that are shown and saved:
+// converted to the display's color space, scaled by the exposure, in stops, tone mapped, put
+// through the post effects and encoded.
+// Dithering adds a little noise before rounding, fixed for each pixel, which breaks up the bands
+// smooth gradients would otherwise show.
+#[derive(Debug, Clone)]
+pub struct Display {
+    pub exposure: f64,
+    pub tone_mapper: ToneMapper,
+    pub dither: bool,
+    pub effects: Vec<post::Effect>,
+    pub space: ColorSpace,
+}
+
+impl Display {
+    // A single pixel, which skips the effects as they need the whole image.
+    pub fn pixel(&self, color: &glm::TVec3<f64>, x: u32, y: u32) -> image::Rgba<u8> {
+        let color = colorspace::from_working(color, self.space);
+        self.encode(&self.tone_mapper.map(&(color * self.exposure.exp2()), self.space), x, y)
+    }
+
+    pub fn image(&self, colors: &[glm::TVec3<f64>], width: u32, height: u32) -> image::RgbaImage {
+        let hdr: Vec<glm::TVec3<f64>> = colors
+            .iter()
+            .map(|color| colorspace::from_working(color, self.space) * self.exposure.exp2())
+            .collect();
+        let mut mapped: Vec<glm::TVec3<f64>> = hdr.iter().map(|color| self.tone_mapper.map(color, self.space)).collect();
+        for effect in &self.effects {
+            effect.apply(&mut mapped, &hdr, width, height, self.space);
+        }
+        image::RgbaImage::from_fn(width, height, |x, y| self.encode(&mapped[(x + y * width) as usize], x, y))
+    }
+
+    fn encode(&self, mapped: &glm::TVec3<f64>, x: u32, y: u32) -> image::Rgba<u8> {
+        let channel = |c: usize| {
+            let mut value = self.space.encode(mapped[c].clamp(0.0, 1.0)) * 255.0;
+            if self.dither {
+                // Triangular noise a step wide either way, the difference of two uniform numbers.
+                let bits = sampler::hash(&[x as u64, y as u64, c as u64]);
+                let (u1, u2) = ((bits >> 32) as f64, (bits & 0xffffffff) as f64);
+                value += (u1 - u2) / (1u64 << 32) as f64;
+            }
+            (value + 0.5).floor().clamp(0.0, 255.0) as u8
+        };
+        image::Rgba([channel(0), channel(1), channel(2), 255])
+    }
+}
@@ -1,25 +1,19 @@
 
 use nalgebra_glm as glm;
-use rand::Rng;
-use rand::distributions::Uniform;
-
-pub fn random_point_in_sphere() -> glm::TVec3<f64> {
-    let mut rng = rand::thread_rng();
-    let distribution = Uniform::from(-1.0..=1.0);
-    loop {
-        let x = rng.sample(&distribution);
-        let y = rng.sample(&distribution);
-        let z = rng.sample(&distribution);
-        let vector = glm::vec3(x, y, z);
-        if glm::dot(&vector, &vector) <= 1.0 {
-            return vector;
-        }
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// A number in [0, 1) that looks random but is fixed by `values`, for choices that have to come
+// out the same whenever they're made about the same thing.
+pub fn hashed_f64<'a>(values: impl IntoIterator<Item = &'a f64>) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    for value in values {
+        value.to_bits().hash(&mut hasher);
     }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
 }
 
-pub fn random_unit_vector() -> glm::TVec3<f64> {
-    glm::normalize(&random_point_in_sphere())
-}
 
 
 pub fn near_zero(vector: &glm::TVec3<f64>) -> bool {
@@ -32,7 +26,7 @@ pub fn reflect(vector: &glm::TVec3<f64>, normal: &glm::TVec3<f64>) -> glm::TVec3
 }
 
 pub fn refract(vector: &glm::TVec3<f64>, normal: &glm::TVec3<f64>, etai_over_etat: f64) -> glm::TVec3<f64> {
-    let cos_theta = glm::dot(&-vector, &normal).min(1.0);
+    let cos_theta = glm::dot(&-vector, normal).min(1.0);
     let r_out_perp = etai_over_etat * (vector + cos_theta*normal);
     let r_out_parallel = -(1.0 - r_out_perp.magnitude_squared()).abs().sqrt() * normal;
     r_out_perp + r_out_parallel
@@ -49,15 +43,52 @@ pub fn degrees_to_radians(deg: f64) -> f64 {
     deg * PI / 180.0
 }
 
-pub fn random_point_in_unit_disk() -> glm::TVec3<f64> {
-    let mut rng = rand::thread_rng();
-    let distribution = Uniform::from(-1.0..=1.0);
-    loop {
-        let x = rng.sample(&distribution);
-        let y = rng.sample(&distribution);
-        let vector = glm::vec3(x, y, 0.0);
-        if glm::dot(&vector, &vector) <= 1.0 {
-            return vector;
-        }
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b == 0.0 {
+        return 0.0;
     }
+    a / (a + b)
+}
+
+pub fn orthonormal_basis(w: &glm::TVec3<f64>) -> (glm::TVec3<f64>, glm::TVec3<f64>) {
+    let a = if w.x.abs() > 0.9 { glm::vec3(0.0, 1.0, 0.0) } else { glm::vec3(1.0, 0.0, 0.0) };
+    let v = glm::normalize(&glm::cross(w, &a));
+    let u = glm::cross(w, &v);
+    (u, v)
+}
+
+pub fn sample_cone(axis: &glm::TVec3<f64>, cos_theta_max: f64, u: glm::TVec2<f64>) -> glm::TVec3<f64> {
+    let cos_theta = 1.0 - u.x * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.y;
+    let (a, b) = orthonormal_basis(axis);
+    a * (phi.cos() * sin_theta) + b * (phi.sin() * sin_theta) + axis * cos_theta
+}
+
+pub fn uniform_sphere(u: glm::TVec2<f64>) -> glm::TVec3<f64> {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.y;
+    glm::vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Uniform point inside the unit ball.
+pub fn uniform_ball(u: glm::TVec3<f64>) -> glm::TVec3<f64> {
+    uniform_sphere(u.xy()) * u.z.cbrt()
+}
+
+pub fn cosine_hemisphere(normal: &glm::TVec3<f64>, u: glm::TVec2<f64>) -> glm::TVec3<f64> {
+    let r = u.x.sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.y;
+    let z = (1.0 - u.x).max(0.0).sqrt();
+    let (a, b) = orthonormal_basis(normal);
+    a * (r * phi.cos()) + b * (r * phi.sin()) + normal * z
+}
+
+pub fn unit_disk(u: glm::TVec2<f64>) -> glm::TVec2<f64> {
+    let r = u.x.sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u.y;
+    glm::vec2(r * phi.cos(), r * phi.sin())
 }